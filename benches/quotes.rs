@@ -0,0 +1,86 @@
+//! Parsing and selection benchmarks over a synthetic archive.
+//!
+//! Run with `cargo bench --no-default-features`; criterion writes reports to `target/criterion`.
+
+#[macro_use]
+extern crate criterion;
+extern crate arrayong;
+extern crate ordermap;
+extern crate serde_json;
+
+use arrayong::{ parse_quotes, select_quote, select_random, Quote, QuoteRng, QuoteYear };
+use arrayong::search::{ search_quote, FuzzyDistance, DEFAULT_SEARCH_ORDER };
+use criterion::Criterion;
+use ordermap::OrderMap;
+use serde_json::Value as Json;
+
+const YEARS: usize = 20;
+const QUOTES_PER_MONTH: usize = 50;
+const WORDS: [&str; 12] = ["array", "philosophy", "revolution", "quote", "bot", "server", "rust", "discord",
+    "month", "year", "random", "search"];
+
+// About `YEARS * 12 * QUOTES_PER_MONTH` quotes in the nested layout, with a little variety in the text.
+fn corpus() -> Json {
+    let mut years = serde_json::Map::new();
+    for year in 0..YEARS {
+        let mut months = serde_json::Map::new();
+        for month in 0..12 {
+            let quotes = (0..QUOTES_PER_MONTH)
+                .map(|index| {
+                    let seed = year * 7919 + month * 104_729 + index;
+                    let text = (0..8)
+                        .map(|word| WORDS[(seed + word * 31) % WORDS.len()])
+                        .collect::<Vec<&str>>()
+                        .join(" ");
+                    Json::from(format!("{} #{}", text, seed))
+                })
+                .collect();
+            months.insert(format!("{:02}", month + 1), Json::Array(quotes));
+        }
+        years.insert((2000 + year).to_string(), Json::Object(months));
+    }
+    Json::Object(years)
+}
+
+fn flatten(quotes: &OrderMap<String, QuoteYear>) -> Vec<&Quote> {
+    quotes.values()
+        .flat_map(|year| year.months.values())
+        .flat_map(|month| month.quotes.iter())
+        .collect()
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let json = corpus();
+    c.bench_function("parse_quotes", move |b| b.iter_with_setup(|| json.clone(), |json| parse_quotes(json).unwrap()));
+}
+
+fn bench_flatten(c: &mut Criterion) {
+    let quotes = parse_quotes(corpus()).unwrap().quotes;
+    c.bench_function("flatten", move |b| b.iter(|| flatten(&quotes).len()));
+}
+
+fn bench_select(c: &mut Criterion) {
+    let quotes = parse_quotes(corpus()).unwrap().quotes;
+    c.bench_function("select_quote (random)", move |b| {
+        let mut rng = QuoteRng::new(Option::Some(1));
+        b.iter(|| select_quote(&quotes, Option::None, FuzzyDistance::Scaled, &mut rng).map(|quote| quote.index))
+    });
+    let quotes = parse_quotes(corpus()).unwrap().quotes;
+    c.bench_function("select_random (prebuilt)", move |b| {
+        let flat = flatten(&quotes);
+        let mut rng = QuoteRng::new(Option::Some(1));
+        b.iter(|| select_random(&flat, &mut rng, &flat[..1]).map(|quote| quote.index))
+    });
+}
+
+fn bench_search(c: &mut Criterion) {
+    let quotes = parse_quotes(corpus()).unwrap().quotes;
+    c.bench_function("search_quote (layered)", move |b| {
+        let mut rng = QuoteRng::new(Option::Some(1));
+        b.iter(|| search_quote(&quotes, "philosofy revolushun", &DEFAULT_SEARCH_ORDER, FuzzyDistance::Scaled,
+                               &mut rng).map(|(quote, _, _)| quote.index))
+    });
+}
+
+criterion_group!(benches, bench_parse, bench_flatten, bench_select, bench_search);
+criterion_main!(benches);
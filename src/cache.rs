@@ -0,0 +1,1107 @@
+use ordermap::OrderMap;
+use quote::{ parse_quotes, parse_quotes_array, parse_quotes_nested, parse_quotes_ndjson, dedup_quotes, ParsedQuotes,
+             Quote, QuoteMonth, QuoteYear, Source, SourceFormat };
+use rand::{ self, Rng };
+use reqwest;
+use search::tokenize;
+use serde_json::{ self, Value as Json };
+use std::cmp::Ordering;
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::error::Error;
+use std::fmt;
+use std::fs::{ self, File };
+use std::io::{ self, BufRead, BufReader, Read };
+use std::sync::Arc;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+const STOPWORDS: [&str; 52] = ["a", "about", "all", "an", "and", "are", "as", "at", "be", "but", "by",
+    "do", "for", "from", "had", "has", "have", "he", "her", "him", "his", "i", "if", "in", "is", "it",
+    "its", "me", "my", "no", "not", "of", "on", "or", "our", "she", "so", "that", "the", "them",
+    "they", "this", "to", "was", "we", "what", "with", "you", "your", "s", "t", "just"];
+const LATENCY_WINDOW: usize = 100;
+// How long, in milliseconds, a failed fetch waits before the next try instead of the whole delay. Repeated
+// failures are left to the circuit breaker to space out.
+const FAILED_RETRY_DELAY: u64 = 1000 * 5;
+/// Consecutive failed fetches before `CircuitBreaker` opens, unless configured otherwise.
+pub const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+/// How long, in milliseconds, an open `CircuitBreaker` skips fetches before trying again.
+pub const DEFAULT_BREAKER_COOLDOWN: u64 = 1000 * 60 * 5;
+
+/// Returned when no quotes have been fetched yet.
+#[derive(Debug)]
+pub struct CacheError;
+
+impl Error for CacheError {
+    fn description(&self) -> &str {
+        "Cache miss!"
+    }
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cache miss!")
+    }
+}
+
+/// A source couldn't be fetched or parsed.
+#[derive(Debug)]
+pub struct CacheRetrievalError(pub String);
+
+impl Error for CacheRetrievalError {
+    fn description(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CacheRetrievalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cache retrieval failed: {}", &self.0)
+    }
+}
+
+impl From<reqwest::Error> for CacheRetrievalError {
+    fn from(err: reqwest::Error) -> Self {
+        CacheRetrievalError(err.description().to_string())
+    }
+}
+
+impl From<io::Error> for CacheRetrievalError {
+    fn from(err: io::Error) -> Self {
+        CacheRetrievalError(err.description().to_string())
+    }
+}
+
+impl From<serde_json::Error> for CacheRetrievalError {
+    fn from(err: serde_json::Error) -> Self {
+        CacheRetrievalError(err.description().to_string())
+    }
+}
+
+
+/// The built-in English stopwords, used unless a stopwords file replaces them.
+pub fn default_stopwords() -> HashSet<String> {
+    STOPWORDS.iter().map(|word| word.to_string()).collect()
+}
+
+/// Word frequencies across the whole archive, leaving out stopwords.
+pub struct Vocabulary {
+    frequencies: HashMap<String, usize>,
+}
+
+impl Vocabulary {
+    /// Counts every token in the archive that isn't in `stopwords`.
+    pub fn build(quotes: &OrderMap<String, QuoteYear>, stopwords: &HashSet<String>) -> Vocabulary {
+        let mut frequencies: HashMap<String, usize> = HashMap::new();
+        for (_, year) in quotes {
+            for (_, month) in &year.months {
+                for quote in &month.quotes {
+                    for token in tokenize(&quote.text).into_iter().filter(|token| !stopwords.contains(token)) {
+                        *frequencies.entry(token).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+        Vocabulary { frequencies }
+    }
+    /// The `count` most frequent words.
+    pub fn top(&self, count: usize) -> Vec<(&str, usize)> {
+        let mut words: Vec<(&str, usize)> = self.frequencies.iter()
+            .map(|(word, &frequency)| (word.as_str(), frequency))
+            .collect();
+        words.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        words.truncate(count);
+        words
+    }
+    /// Up to `count` quotes sharing the most significant words with `target`, best first, excluding `target`.
+    ///
+    /// Each shared word scores the inverse of its archive frequency, so rare words outweigh common ones.
+    pub fn similar<'a>(&self, quotes: &'a OrderMap<String, QuoteYear>, target: &Quote, count: usize)
+        -> Vec<(&'a Quote, f64)> {
+        // Stopwords never made it into the frequencies, so anything missing from them is one.
+        let terms: HashSet<String> = tokenize(&target.text).into_iter()
+            .filter(|term| self.frequencies.contains_key(term))
+            .collect();
+        let mut scored: Vec<(&Quote, f64)> = quotes.values()
+            .flat_map(|year| year.months.values())
+            .flat_map(|month| month.quotes.iter())
+            .filter(|quote| (&quote.year, &quote.month, quote.index) != (&target.year, &target.month, target.index))
+            .filter_map(|quote| {
+                let shared: HashSet<String> = tokenize(&quote.text).into_iter()
+                    .filter(|term| terms.contains(term))
+                    .collect();
+                let score: f64 = shared.iter()
+                    .map(|term| 1.0 / self.frequencies[term] as f64)
+                    .sum();
+                if score > 0.0 { Option::Some((quote, score)) } else { Option::None }
+            })
+            .collect();
+        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        scored.truncate(count);
+        scored
+    }
+}
+
+/// Which quotes carry each tag, by year, month and quote position in the archive.
+pub struct TagIndex {
+    positions: HashMap<String, Vec<(usize, usize, usize)>>,
+}
+
+impl TagIndex {
+    pub fn new() -> TagIndex {
+        TagIndex { positions: HashMap::new() }
+    }
+    /// Indexes every tagged quote.
+    pub fn build(quotes: &OrderMap<String, QuoteYear>) -> TagIndex {
+        let mut positions: HashMap<String, Vec<(usize, usize, usize)>> = HashMap::new();
+        for (year_position, (_, year)) in quotes.iter().enumerate() {
+            for (month_position, (_, month)) in year.months.iter().enumerate() {
+                for (quote_position, quote) in month.quotes.iter().enumerate() {
+                    for tag in &quote.tags {
+                        positions.entry(tag.clone()).or_insert_with(Vec::new)
+                            .push((year_position, month_position, quote_position));
+                    }
+                }
+            }
+        }
+        TagIndex { positions }
+    }
+    /// The quotes tagged `tag`, which must be lowercase; `quotes` must be what the index was built from.
+    pub fn quotes<'a>(&self, quotes: &'a OrderMap<String, QuoteYear>, tag: &str) -> Vec<&'a Quote> {
+        self.positions.get(tag).map_or_else(Vec::new, |positions| positions.iter()
+            .filter_map(|&(year, month, quote)| quotes.get_index(year)
+                .and_then(|(_, year)| year.months.get_index(month))
+                .and_then(|(_, month)| month.quotes.get(quote)))
+            .collect())
+    }
+    /// Every tag with how many quotes carry it, most common first.
+    pub fn counts(&self) -> Vec<(&str, usize)> {
+        let mut counts: Vec<(&str, usize)> = self.positions.iter()
+            .map(|(tag, positions)| (tag.as_str(), positions.len()))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        counts
+    }
+}
+
+/// Every quote's year, month and quote position in the archive, in archive order, so a uniform pick doesn't have
+/// to walk (or copy) the nested map, along with where each id sits in that order.
+pub struct QuoteIndex {
+    positions: Vec<(usize, usize, usize)>,
+    ids: HashMap<String, usize>,
+}
+
+impl QuoteIndex {
+    pub fn new() -> QuoteIndex {
+        QuoteIndex { positions: Vec::new(), ids: HashMap::new() }
+    }
+    pub fn build(quotes: &OrderMap<String, QuoteYear>) -> QuoteIndex {
+        let mut positions = Vec::new();
+        let mut ids = HashMap::new();
+        for (year_position, (_, year)) in quotes.iter().enumerate() {
+            for (month_position, (_, month)) in year.months.iter().enumerate() {
+                for (quote_position, quote) in month.quotes.iter().enumerate() {
+                    ids.insert(quote.id(), positions.len());
+                    positions.push((year_position, month_position, quote_position));
+                }
+            }
+        }
+        QuoteIndex { positions, ids }
+    }
+    /// Where the quote with `id` sits in archive order, without walking the archive.
+    pub fn position(&self, id: &str) -> Option<usize> {
+        self.ids.get(id).cloned()
+    }
+    /// The quote at `position` in archive order; `quotes` must be what the index was built from.
+    pub fn get<'a>(&self, quotes: &'a OrderMap<String, QuoteYear>, position: usize) -> Option<&'a Quote> {
+        let (year, month, quote) = *self.positions.get(position)?;
+        quotes.get_index(year)
+            .and_then(|(_, year)| year.months.get_index(month))
+            .and_then(|(_, month)| month.quotes.get(quote))
+    }
+    /// A uniformly random quote, or `None` if there are none; `quotes` must be what the index was built from. Like
+    /// `select_random`, a single quote is returned without touching `rng`.
+    pub fn pick<'a, R: Rng>(&self, quotes: &'a OrderMap<String, QuoteYear>, rng: &mut R) -> Option<&'a Quote> {
+        match self.positions.len() {
+            0 => Option::None,
+            1 => self.get(quotes, 0),
+            len => self.get(quotes, rng.gen_range(0, len)),
+        }
+    }
+    /// Like `pick`, but never a quote at one of the `avoid` positions while there's anything else to pick. Once
+    /// every quote is avoided, repeats are allowed again.
+    pub fn pick_avoiding<'a, R: Rng>(&self, quotes: &'a OrderMap<String, QuoteYear>, rng: &mut R, avoid: &[usize])
+        -> Option<&'a Quote> {
+        let mut avoid: Vec<usize> = avoid.iter().cloned().filter(|&position| position < self.positions.len()).collect();
+        avoid.sort();
+        avoid.dedup();
+        let fresh = self.positions.len() - avoid.len();
+        if fresh == 0 {
+            return self.pick(quotes, rng);
+        }
+        // Pick among the fresh quotes, then step over each avoided position at or before the pick.
+        let mut position = if fresh == 1 { 0 } else { rng.gen_range(0, fresh) };
+        for &avoided in &avoid {
+            if avoided > position {
+                break;
+            }
+            position += 1;
+        }
+        self.get(quotes, position)
+    }
+}
+
+/// The most recent latency samples, for p50/max reporting.
+pub struct LatencyWindow {
+    samples: VecDeque<u64>,
+}
+
+impl LatencyWindow {
+    pub fn new() -> LatencyWindow {
+        LatencyWindow { samples: VecDeque::with_capacity(LATENCY_WINDOW) }
+    }
+    pub fn record(&mut self, millis: u64) {
+        if self.samples.len() >= LATENCY_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(millis);
+    }
+    pub fn summary(&self) -> String {
+        if self.samples.is_empty() {
+            return "no samples yet".to_string();
+        }
+        let mut sorted: Vec<u64> = self.samples.iter().cloned().collect();
+        sorted.sort();
+        format!("p50 {}ms, max {}ms over {} samples",
+                sorted[sorted.len() / 2], sorted[sorted.len() - 1], sorted.len())
+    }
+}
+
+/// Where a `CircuitBreaker` stands.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BreakerState {
+    /// Fetches go ahead as usual.
+    Closed,
+    /// Fetches are skipped for the remaining duration.
+    Open(Duration),
+    /// The cool-down is over and a single trial fetch is running.
+    Trial,
+}
+
+/// Stops fetching from a failing source for a while instead of retrying, and logging another failure, on every
+/// expiry. Once the cool-down passes one trial fetch is let through: success closes the circuit, failure opens it
+/// for another cool-down.
+pub struct CircuitBreaker {
+    /// Consecutive failures that open the circuit; 0 never opens it.
+    pub threshold: u32,
+    pub cooldown: Duration,
+    failures: u32,
+    opened_at: Option<Instant>,
+    trial: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker { threshold, cooldown, failures: 0, opened_at: Option::None, trial: false }
+    }
+    pub fn state(&self) -> BreakerState {
+        match self.opened_at {
+            Option::None => BreakerState::Closed,
+            Option::Some(_) if self.trial => BreakerState::Trial,
+            Option::Some(opened_at) => BreakerState::Open(self.cooldown.checked_sub(opened_at.elapsed())
+                .unwrap_or_else(|| Duration::from_secs(0))),
+        }
+    }
+    /// Failures in a row since the last successful fetch.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+    /// Whether a fetch may start now. Past the cool-down this lets exactly one trial through.
+    pub fn permits(&mut self) -> bool {
+        match self.opened_at {
+            Option::None => true,
+            Option::Some(opened_at) if self.trial || opened_at.elapsed() < self.cooldown => false,
+            Option::Some(_) => {
+                log_event!(Info, "circuit_trial", "Circuit cool-down over; trying the source once",
+                           failures = self.failures);
+                self.trial = true;
+                true
+            },
+        }
+    }
+    pub fn record_success(&mut self) {
+        if self.opened_at.is_some() {
+            log_event!(Info, "circuit_closed", format!("Source recovered after {} failures; circuit closed",
+                                                       self.failures), failures = self.failures);
+        }
+        self.failures = 0;
+        self.opened_at = Option::None;
+        self.trial = false;
+    }
+    pub fn record_failure(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+        if self.trial {
+            self.trial = false;
+            self.opened_at = Option::Some(Instant::now());
+            log_event!(Warn, "circuit_reopened", format!("Trial fetch failed; skipping fetches for another {}ms",
+                                                         as_millis(self.cooldown)),
+                       failures = self.failures, cooldown_ms = as_millis(self.cooldown));
+        } else if self.opened_at.is_none() && self.threshold > 0 && self.failures >= self.threshold {
+            self.opened_at = Option::Some(Instant::now());
+            log_event!(Warn, "circuit_open", format!("{} fetches failed in a row; skipping fetches for {}ms and \
+                                                      serving the cached quotes", self.failures,
+                                                     as_millis(self.cooldown)),
+                       failures = self.failures, cooldown_ms = as_millis(self.cooldown));
+        }
+    }
+}
+
+/// Where `QuoteCache` reads the time from when deciding whether a refresh is due and how old its quotes are.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock, which every cache uses unless told otherwise.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Quotes fetched from the configured sources, refreshed lazily once the delay has passed.
+pub struct QuoteCache {
+    last_request_time: SystemTime,
+    pub cache: Option<OrderMap<String, QuoteYear>>,
+    pub cache_size: usize,
+    /// Malformed entries left out of the last successful refresh.
+    pub skipped: usize,
+    pub vocabulary: Vocabulary,
+    /// Words left out of the vocabulary; changing this takes effect at the next refresh.
+    pub stopwords: HashSet<String>,
+    pub tags: TagIndex,
+    /// Every quote in archive order, built at refresh time for uniform picks.
+    pub index: QuoteIndex,
+    pub refreshes: LatencyWindow,
+    client: reqwest::Client,
+    pub sources: Vec<Source>,
+    pub request: SourceRequest,
+    pub format: SourceFormat,
+    pub dedup: bool,
+    pub delay: Duration,
+    pub jitter: u64,
+    next_delay: Duration,
+    refreshed_at: Option<SystemTime>,
+    files_modified: Option<SystemTime>,
+    /// Why the most recent fetch failed, or `None` if it succeeded (or none has run yet).
+    pub last_error: Option<String>,
+    /// Leaves refreshing to a background worker driving `begin_refresh`, so `get_quotes` never fetches.
+    pub background: bool,
+    /// Most refreshes that may be claimed and not yet finished at once; further due refreshes are skipped and
+    /// the current quotes keep being served until one lands.
+    pub max_in_flight: usize,
+    in_flight: usize,
+    pub breaker: CircuitBreaker,
+    /// `SystemClock` unless replaced, e.g. by a test clock that steps past the delay without waiting.
+    pub clock: Arc<Clock>,
+    /// Bumped by every successful refresh, so anything derived from `cache` can tell when it's out of date.
+    pub generation: u64,
+}
+
+/// A fetch claimed by `QuoteCache::begin_refresh`, holding everything it needs to run without the cache.
+pub struct Refresh {
+    client: reqwest::Client,
+    sources: Vec<Source>,
+    request: SourceRequest,
+    format: SourceFormat,
+    dedup: bool,
+    stopwords: HashSet<String>,
+}
+
+/// Freshly fetched quotes with their indexes already built, ready to replace a cache's contents in one go.
+pub struct Snapshot {
+    pub quotes: OrderMap<String, QuoteYear>,
+    pub quote_count: usize,
+    pub skipped: usize,
+    pub vocabulary: Vocabulary,
+    pub tags: TagIndex,
+    pub index: QuoteIndex,
+    pub fetch_ms: u64,
+    pub index_ms: u64,
+}
+
+impl Refresh {
+    /// Fetches every source and builds the vocabulary, tag and quote indexes over the result.
+    pub fn run(self) -> Result<Snapshot, CacheRetrievalError> {
+        let started = Instant::now();
+        let parsed = fetch_quotes(&self.client, &self.sources, &self.request, self.format, self.dedup)?;
+        let fetched = Instant::now();
+        let vocabulary = Vocabulary::build(&parsed.quotes, &self.stopwords);
+        let tags = TagIndex::build(&parsed.quotes);
+        let index = QuoteIndex::build(&parsed.quotes);
+        Result::Ok(Snapshot {
+            quotes: parsed.quotes,
+            quote_count: parsed.quote_count,
+            skipped: parsed.skipped,
+            vocabulary,
+            tags,
+            index,
+            fetch_ms: as_millis(fetched - started),
+            index_ms: as_millis(fetched.elapsed()),
+        })
+    }
+}
+
+/// Whole milliseconds in a duration.
+pub fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos() / 1_000_000)
+}
+
+fn jittered(delay: Duration, jitter: u64) -> Duration {
+    if jitter == 0 {
+        return delay;
+    }
+    let millis = as_millis(delay);
+    let spread = millis * jitter / 100;
+    Duration::from_millis(millis - spread + rand::thread_rng().gen_range(0, spread * 2 + 1))
+}
+
+/// A client with gzip decoding enabled, suitable for `fetch_quotes`.
+pub fn http_client() -> Result<reqwest::Client, CacheRetrievalError> {
+    Result::Ok(reqwest::Client::builder().gzip(true).build()?)
+}
+
+/// How each source is requested, and where the quotes sit in the response.
+#[derive(Clone, PartialEq)]
+pub struct SourceRequest {
+    pub method: reqwest::Method,
+    pub body: Option<String>,
+    pub content_type: Option<String>,
+    /// Extra headers sent with every request; values are never logged.
+    pub headers: Vec<(String, String)>,
+    /// Dot-separated path to the quotes object, e.g. `data.quotes`; only used for nested sources.
+    pub path: Option<String>,
+}
+
+impl Default for SourceRequest {
+    fn default() -> SourceRequest {
+        SourceRequest {
+            method: reqwest::Method::Get,
+            body: Option::None,
+            content_type: Option::None,
+            headers: Vec::new(),
+            path: Option::None,
+        }
+    }
+}
+
+impl fmt::Debug for SourceRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SourceRequest")
+            .field("method", &self.method)
+            .field("content_type", &self.content_type)
+            .field("headers", &self.headers.iter().map(|&(ref name, _)| name.as_str()).collect::<Vec<&str>>())
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// Checks that `name` is a valid header token and `value` holds no control characters.
+pub fn valid_header(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+        && value.chars().all(|c| c == '\t' || !c.is_control())
+}
+
+fn open_source(client: &reqwest::Client, request_url: reqwest::Url, request: &SourceRequest)
+    -> Result<BufReader<Box<Read>>, CacheRetrievalError> {
+    if request_url.scheme() == "file" {
+        let path = request_url.to_file_path()
+            .map_err(|_| CacheRetrievalError(format!("`{}` isn't a local path", request_url)))?;
+        let file = File::open(&path).map_err(|err| CacheRetrievalError(format!("{}: {}", path.display(), err)))?;
+        let mut reader = BufReader::new(Box::new(file) as Box<Read>);
+        skip_bom(&mut reader)?;
+        return Result::Ok(reader);
+    }
+    let mut builder = client.request(request.method.clone(), request_url);
+    let mut headers = reqwest::header::Headers::new();
+    if let Option::Some(ref content_type) = request.content_type {
+        headers.set_raw("Content-Type", content_type.clone());
+    }
+    for &(ref name, ref value) in &request.headers {
+        headers.set_raw(name.clone(), value.clone());
+    }
+    builder.headers(headers);
+    if let Option::Some(ref body) = request.body {
+        builder.body(body.clone());
+    }
+    let response = builder.send()?;
+    if let Option::Some(charset) = declared_charset(&response) {
+        if !charset.eq_ignore_ascii_case("utf-8") && !charset.eq_ignore_ascii_case("utf8")
+            && !charset.eq_ignore_ascii_case("us-ascii") {
+            return Result::Err(CacheRetrievalError(
+                format!("Source declares charset `{}`; only UTF-8 responses are supported", charset)));
+        }
+    }
+    let mut reader = BufReader::new(Box::new(response) as Box<Read>);
+    skip_bom(&mut reader)?;
+    Result::Ok(reader)
+}
+
+// The `charset` parameter of the response's Content-Type, if it has one.
+fn declared_charset(response: &reqwest::Response) -> Option<String> {
+    let content_type = response.headers().get_raw("Content-Type")
+        .and_then(|raw| raw.one())
+        .map(|value| String::from_utf8_lossy(value).into_owned())?;
+    content_type.split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next().map(str::trim), parts.next()) {
+                (Option::Some(name), Option::Some(value)) if name.eq_ignore_ascii_case("charset") =>
+                    Option::Some(value.trim().trim_matches('"').to_string()),
+                _ => Option::None,
+            }
+        })
+        .next()
+}
+
+// Drops a leading UTF-8 byte order mark, which serde_json would otherwise reject as an unexpected
+// character. A UTF-16 mark means the body isn't UTF-8 at all, so that's an error.
+fn skip_bom<R: BufRead>(reader: &mut R) -> Result<(), CacheRetrievalError> {
+    let skip = {
+        let start = reader.fill_buf()?;
+        if start.starts_with(&[0xEF, 0xBB, 0xBF]) {
+            3
+        } else if start.starts_with(&[0xFF, 0xFE]) || start.starts_with(&[0xFE, 0xFF]) {
+            return Result::Err(CacheRetrievalError(
+                "Source response is UTF-16 (it starts with a UTF-16 byte order mark); only UTF-8 is supported"
+                    .to_string()));
+        } else {
+            0
+        }
+    };
+    reader.consume(skip);
+    Result::Ok(())
+}
+
+fn perform_request(client: &reqwest::Client, request_url: reqwest::Url, request: &SourceRequest)
+    -> Result<Json, CacheRetrievalError> {
+    // A nested source has to parse as one document, so the best we can do is say where it broke.
+    let json: Json = serde_json::from_reader(open_source(client, request_url, request)?)
+        .map_err(|err| if err.is_io() {
+            CacheRetrievalError::from(err)
+        } else {
+            CacheRetrievalError(format!("Source isn't valid JSON at line {}, column {}: {}",
+                                        err.line(), err.column(), err.description()))
+        })?;
+    match request.path {
+        Option::Some(ref path) => navigate(json, path),
+        Option::None => Result::Ok(json),
+    }
+}
+
+// Walks a dot-path like `result.quotes` (or `items.0` for arrays), naming the first segment that's missing.
+fn navigate(mut json: Json, path: &str) -> Result<Json, CacheRetrievalError> {
+    let mut walked: Vec<&str> = Vec::new();
+    for segment in path.split('.').filter(|segment| !segment.is_empty()) {
+        let next = match json {
+            Json::Object(ref mut map) => map.remove(segment),
+            Json::Array(ref mut items) => match segment.parse::<usize>() {
+                Result::Ok(index) if index < items.len() => Option::Some(items.swap_remove(index)),
+                _ => Option::None,
+            },
+            _ => Option::None,
+        };
+        json = match next {
+            Option::Some(next) => next,
+            Option::None => {
+                let parent = if walked.is_empty() {
+                    "the response root".to_string()
+                } else {
+                    format!("`{}`", walked.join("."))
+                };
+                return Result::Err(CacheRetrievalError(
+                    format!("Source path `{}`: no `{}` under {}", path, segment, parent)));
+            },
+        };
+        walked.push(segment);
+    }
+    Result::Ok(json)
+}
+
+fn fetch_source(client: &reqwest::Client, request_url: reqwest::Url, request: &SourceRequest, format: SourceFormat)
+    -> Result<ParsedQuotes, CacheRetrievalError> {
+    Result::Ok(match format {
+        SourceFormat::Auto => parse_quotes(perform_request(client, request_url, request)?)?,
+        SourceFormat::Nested => parse_quotes_nested(perform_request(client, request_url, request)?)
+            .map_err(|err| format_mismatch(err, "nested"))?,
+        SourceFormat::Array => parse_quotes_array(perform_request(client, request_url, request)?)
+            .map_err(|err| format_mismatch(err, "array"))?,
+        SourceFormat::Ndjson => parse_quotes_ndjson(open_source(client, request_url, request)?)?,
+    })
+}
+
+// A layout parser's complaint about the root, blamed on the configured source format.
+fn format_mismatch(CacheRetrievalError(err): CacheRetrievalError, format: &str) -> CacheRetrievalError {
+    CacheRetrievalError(format!("{}, but the source format is set to {}", err, format))
+}
+
+// Appends one source's quotes into the merged map, renumbering so ids stay unique within a month.
+fn merge_quotes(merged: &mut OrderMap<String, QuoteYear>, quotes: OrderMap<String, QuoteYear>, source: String) {
+    for (year_key, year) in quotes {
+        let merged_year = merged.entry(year_key)
+            .or_insert_with(|| QuoteYear { months: OrderMap::new() });
+        for (month_key, month) in year.months {
+            let merged_month = merged_year.months.entry(month_key)
+                .or_insert_with(|| QuoteMonth { quotes: Vec::new() });
+            for mut quote in month.quotes {
+                quote.index = merged_month.quotes.len();
+                quote.source = Option::Some(source.clone());
+                merged_month.quotes.push(quote);
+            }
+        }
+    }
+}
+
+// Fetches `source`, switching to its fallback URL if the primary fails. Only when both fail is it an error.
+fn fetch_with_fallback(client: &reqwest::Client, source: &Source, request: &SourceRequest, format: SourceFormat)
+    -> Result<ParsedQuotes, CacheRetrievalError> {
+    let err = match fetch_source(client, source.url.clone(), request, format) {
+        Result::Ok(parsed) => return Result::Ok(parsed),
+        Result::Err(err) => err,
+    };
+    let fallback = match source.fallback {
+        Option::Some(ref fallback) => fallback,
+        Option::None => return Result::Err(err),
+    };
+    log_event!(Warn, "fetch_fallback", format!("Source {} failed, trying its fallback: {}", source.name(), err),
+               source = source.name(), error = err.to_string());
+    let parsed = fetch_source(client, fallback.clone(), request, format)
+        .map_err(|fallback_err| CacheRetrievalError(format!("{} (the fallback failed too: {})", err, fallback_err)))?;
+    log_event!(Info, "fetch_fallback_done",
+               format!("Fallback for {} served {} quotes", source.name(), parsed.quote_count),
+               source = source.name(), quote_count = parsed.quote_count);
+    Result::Ok(parsed)
+}
+
+/// Fetches, merges and optionally dedups every source, returning the quotes, their count and how many
+/// malformed entries were skipped along the way.
+pub fn fetch_quotes(client: &reqwest::Client, sources: &[Source], request: &SourceRequest, format: SourceFormat,
+                    dedup: bool) -> Result<ParsedQuotes, CacheRetrievalError> {
+    let mut parsed = if sources.len() == 1 {
+        fetch_with_fallback(client, &sources[0], request, format)?
+    } else {
+        let mut merged = ParsedQuotes { quotes: OrderMap::new(), quote_count: 0, skipped: 0 };
+        for source in sources {
+            let parsed = fetch_with_fallback(client, source, request, format)?;
+            merge_quotes(&mut merged.quotes, parsed.quotes, source.name());
+            merged.quote_count += parsed.quote_count;
+            merged.skipped += parsed.skipped;
+        }
+        merged
+    };
+    if dedup {
+        let removed = dedup_quotes(&mut parsed.quotes);
+        if removed > 0 {
+            log_event!(Info, "dedup", format!("Removed {} duplicate quotes", removed), removed = removed);
+        }
+        parsed.quote_count -= removed;
+    }
+    Result::Ok(parsed)
+}
+
+// The latest modification time among the `file://` sources, or `None` if there are none to check.
+fn files_modified(sources: &[Source]) -> Option<SystemTime> {
+    sources.iter()
+        .filter(|source| source.url.scheme() == "file")
+        .filter_map(|source| source.url.to_file_path().ok())
+        .filter_map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .max()
+}
+
+impl QuoteCache {
+    /// Creates an empty cache; the first `get_quotes` call fetches immediately.
+    pub fn new(sources: Vec<Source>, request: SourceRequest, format: SourceFormat, dedup: bool, delay: Duration,
+               jitter: u64) -> Result<QuoteCache, CacheRetrievalError> {
+        Result::Ok(QuoteCache {
+            last_request_time: UNIX_EPOCH,
+            cache: Option::None,
+            cache_size: 0,
+            skipped: 0,
+            vocabulary: Vocabulary { frequencies: HashMap::new() },
+            stopwords: default_stopwords(),
+            tags: TagIndex::new(),
+            index: QuoteIndex::new(),
+            refreshes: LatencyWindow::new(),
+            client: http_client()?,
+            sources,
+            request,
+            format,
+            dedup,
+            delay,
+            jitter,
+            next_delay: delay,
+            refreshed_at: Option::None,
+            files_modified: Option::None,
+            last_error: Option::None,
+            background: false,
+            max_in_flight: 1,
+            in_flight: 0,
+            breaker: CircuitBreaker::new(DEFAULT_BREAKER_THRESHOLD, Duration::from_millis(DEFAULT_BREAKER_COOLDOWN)),
+            clock: Arc::new(SystemClock),
+            generation: 0,
+        })
+    }
+    /// When the last fetch started, successful or not, or `None` if none has.
+    pub fn last_attempt(&self) -> Option<SystemTime> {
+        if self.last_request_time == UNIX_EPOCH { Option::None } else { Option::Some(self.last_request_time) }
+    }
+    /// How long ago the last successful refresh was, or `None` if there hasn't been one.
+    pub fn age(&self) -> Option<Duration> {
+        self.refreshed_at.map(|refreshed_at| self.clock.now().duration_since(refreshed_at)
+            .unwrap_or_else(|_| Duration::from_secs(0)))
+    }
+    /// Claims a refresh if the delay has elapsed or a local source file has changed. After a failed fetch the
+    /// next one is due within a few seconds instead, so a flaky source doesn't hold up fresh (or, at startup, any)
+    /// quotes for the whole delay. The returned job can run without the cache (and any lock around it); hand its
+    /// result to `finish_refresh`.
+    pub fn begin_refresh(&mut self) -> Option<Refresh> {
+        let now = self.clock.now();
+        let modified = files_modified(&self.sources);
+        let changed = modified.is_some() && modified != self.files_modified;
+        match now.duration_since(self.last_request_time) {
+            Result::Ok(dur) if changed || dur >= self.next_delay => {},
+            _ => return Option::None,
+        }
+        if self.in_flight >= self.max_in_flight {
+            log_event!(Debug, "fetch_coalesced",
+                       format!("A refresh is due but {} already in flight; serving the current cache", self.in_flight),
+                       in_flight = self.in_flight);
+            return Option::None;
+        }
+        if !self.breaker.permits() {
+            return Option::None;
+        }
+        if changed && self.files_modified.is_some() {
+            log_event!(Info, "fetch_start", "Source file changed! Retrieving...", sources = self.sources.len());
+        } else {
+            log_event!(Info, "fetch_start", "Cache expired! Retrieving...", sources = self.sources.len());
+        }
+        self.last_request_time = now;
+        self.files_modified = modified;
+        self.next_delay = jittered(self.delay, self.jitter);
+        self.in_flight += 1;
+        Option::Some(Refresh {
+            client: self.client.clone(),
+            sources: self.sources.clone(),
+            request: self.request.clone(),
+            format: self.format,
+            dedup: self.dedup,
+            stopwords: self.stopwords.clone(),
+        })
+    }
+    /// Swaps in a finished refresh's quotes and indexes together, or records why it failed and keeps the old ones.
+    pub fn finish_refresh(&mut self, result: Result<Snapshot, CacheRetrievalError>) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        match result {
+            Result::Ok(snapshot) => {
+                self.breaker.record_success();
+                self.vocabulary = snapshot.vocabulary;
+                self.tags = snapshot.tags;
+                self.index = snapshot.index;
+                self.cache = Option::Some(snapshot.quotes);
+                self.cache_size = snapshot.quote_count;
+                self.skipped = snapshot.skipped;
+                self.refreshed_at = Option::Some(self.clock.now());
+                self.generation += 1;
+                self.last_error = Option::None;
+                let elapsed = snapshot.fetch_ms + snapshot.index_ms;
+                self.refreshes.record(elapsed);
+                log_event!(Info, "fetch_done", format!("Retrieved {} quotes in {}ms (indexes took {}ms)",
+                                                       self.cache_size, elapsed, snapshot.index_ms),
+                           quote_count = self.cache_size, latency_ms = elapsed, index_ms = snapshot.index_ms);
+            },
+            Result::Err(err) => {
+                let age = self.age().map_or(Json::Null, |age| Json::from(age.as_secs()));
+                log_event!(Error, "fetch_failed", format!("Cache retrieval failed: {}", err),
+                           error = err.to_string(), age_secs = age);
+                self.last_error = Option::Some(err.to_string());
+                self.next_delay = self.next_delay.min(Duration::from_millis(FAILED_RETRY_DELAY));
+                self.breaker.record_failure();
+            },
+        }
+    }
+    /// Releases a claimed refresh that will never finish, e.g. because it panicked. The breaker counts it as a
+    /// failure.
+    pub fn abandon_refresh(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        self.next_delay = self.next_delay.min(Duration::from_millis(FAILED_RETRY_DELAY));
+        self.breaker.record_failure();
+    }
+    /// Returns the cached quotes, refreshing first if one is due, unless a background worker owns refreshing.
+    pub fn get_quotes(&mut self) -> Result<&OrderMap<String, QuoteYear>, CacheError> {
+        if !self.background {
+            if let Option::Some(refresh) = self.begin_refresh() {
+                let result = refresh.run();
+                self.finish_refresh(result);
+            }
+        }
+        if let Option::Some(ref contents) = self.cache {
+            Result::Ok(contents)
+        } else {
+            Result::Err(CacheError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use search::QuoteRng;
+    use std::env;
+    use std::io::Write;
+    use std::process;
+    use std::sync::Mutex;
+
+    // A clock that only moves when `advance` is called, so expiry can be stepped through by hand.
+    struct FakeClock {
+        now: Mutex<SystemTime>,
+    }
+
+    impl FakeClock {
+        fn advance(&self, by: Duration) {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    fn fixture() -> OrderMap<String, QuoteYear> {
+        let json = serde_json::from_str(include_str!("../tests/fixtures/quotes.json")).unwrap();
+        parse_quotes_nested(json).unwrap().quotes
+    }
+
+    #[test]
+    fn quote_index_covers_every_quote_in_archive_order() {
+        let quotes = fixture();
+        let index = QuoteIndex::build(&quotes);
+        let archive: Vec<String> = quotes.values()
+            .flat_map(|year| year.months.values())
+            .flat_map(|month| month.quotes.iter())
+            .map(|quote| quote.id())
+            .collect();
+        let indexed: Vec<String> = (0..archive.len()).map(|position| index.get(&quotes, position).unwrap().id())
+            .collect();
+        assert_eq!(indexed, archive);
+        assert!(index.get(&quotes, archive.len()).is_none());
+        assert!(QuoteIndex::new().get(&quotes, 0).is_none());
+        for (position, id) in archive.iter().enumerate() {
+            assert_eq!(index.position(id), Option::Some(position));
+        }
+        assert_eq!(index.position("2018-3-9"), Option::None);
+    }
+
+    #[test]
+    fn quote_index_picks_a_single_quote_without_the_rng() {
+        let quotes = parse_quotes_nested(serde_json::from_str(r#"{ "2018": { "3": ["only"] } }"#).unwrap())
+            .unwrap().quotes;
+        let index = QuoteIndex::build(&quotes);
+        let mut rng = QuoteRng::new(Option::Some(7));
+        assert_eq!(index.pick(&quotes, &mut rng).unwrap().text, "only");
+        let mut untouched = QuoteRng::new(Option::Some(7));
+        assert_eq!(rng.next_u64(), untouched.next_u64());
+        assert!(QuoteIndex::new().pick(&quotes, &mut rng).is_none());
+    }
+
+    #[test]
+    fn quote_index_picks_around_avoided_positions() {
+        let quotes = fixture();
+        let index = QuoteIndex::build(&quotes);
+        let mut rng = QuoteRng::new(Option::Some(7));
+        for _ in 0..20 {
+            let picked = index.pick_avoiding(&quotes, &mut rng, &[0, 3, 7]).unwrap().id();
+            assert!(![0, 3, 7].iter().any(|&position| index.position(&picked) == Option::Some(position)), "{}", picked);
+        }
+        let avoid: Vec<usize> = (0..8).filter(|&position| position != 5).collect();
+        for _ in 0..10 {
+            assert_eq!(index.pick_avoiding(&quotes, &mut rng, &avoid).map(|quote| quote.id()),
+                       index.get(&quotes, 5).map(|quote| quote.id()));
+        }
+        // Once everything is avoided, any quote will do.
+        let everything: Vec<usize> = (0..8).collect();
+        assert!(index.pick_avoiding(&quotes, &mut rng, &everything).is_some());
+    }
+
+    // A cache over one local file that refreshes every minute, reading the time from the returned clock.
+    fn stepped_cache(name: &str) -> (QuoteCache, Arc<FakeClock>) {
+        let url = temp_file(name, br#"{ "2018": { "3": ["cached"] } }"#);
+        let source = Source { label: Option::None, url, weight: Option::None, fallback: Option::None };
+        let mut cache = QuoteCache::new(vec![source], SourceRequest::default(), SourceFormat::Auto, false,
+                                        Duration::from_secs(60), 0).unwrap();
+        let clock = Arc::new(FakeClock { now: Mutex::new(SystemTime::now()) });
+        cache.clock = clock.clone();
+        (cache, clock)
+    }
+
+    // A scratch file under the temp directory, unique to this test process.
+    fn temp_file(name: &str, contents: &[u8]) -> reqwest::Url {
+        let path = env::temp_dir().join(format!("arrayong-{}-{}", process::id(), name));
+        File::create(&path).and_then(|mut file| file.write_all(contents)).unwrap();
+        reqwest::Url::from_file_path(path).unwrap()
+    }
+
+    #[test]
+    fn bom_prefixed_bodies_parse() {
+        let url = temp_file("bom.json", b"\xEF\xBB\xBF{ \"2018\": { \"3\": [\"after a BOM\"] } }");
+        let parsed = fetch_source(&http_client().unwrap(), url, &SourceRequest::default(), SourceFormat::Auto)
+            .unwrap();
+        assert_eq!(parsed.quote_count, 1);
+        assert_eq!(parsed.quotes["2018"].months["3"].quotes[0].text, "after a BOM");
+    }
+
+    #[test]
+    fn utf16_bodies_are_named_in_the_error() {
+        let url = temp_file("utf16.json", b"\xFF\xFE{\x00}\x00");
+        match fetch_source(&http_client().unwrap(), url, &SourceRequest::default(), SourceFormat::Auto) {
+            Result::Err(CacheRetrievalError(err)) => assert!(err.contains("UTF-16"), "{}", err),
+            Result::Ok(_) => panic!("a UTF-16 body shouldn't parse"),
+        }
+    }
+
+    fn source(url: reqwest::Url, fallback: Option<reqwest::Url>) -> Source {
+        Source { label: Option::None, url, weight: Option::None, fallback }
+    }
+
+    #[test]
+    fn fallback_serves_when_the_primary_fails() {
+        let missing = env::temp_dir().join(format!("arrayong-{}-missing.json", process::id()));
+        let fallback = temp_file("fallback.json", br#"{ "2018": { "3": ["from the fallback"] } }"#);
+        let source = source(reqwest::Url::from_file_path(missing).unwrap(), Option::Some(fallback));
+        let parsed = fetch_with_fallback(&http_client().unwrap(), &source, &SourceRequest::default(),
+                                         SourceFormat::Auto).unwrap();
+        assert_eq!(parsed.quote_count, 1);
+        assert_eq!(parsed.quotes["2018"].months["3"].quotes[0].text, "from the fallback");
+    }
+
+    #[test]
+    fn both_failures_are_reported() {
+        let missing = env::temp_dir().join(format!("arrayong-{}-missing.json", process::id()));
+        let broken = temp_file("broken.json", b"[ not json");
+        let source = source(reqwest::Url::from_file_path(missing).unwrap(), Option::Some(broken));
+        match fetch_with_fallback(&http_client().unwrap(), &source, &SourceRequest::default(), SourceFormat::Auto) {
+            Result::Err(CacheRetrievalError(err)) => assert!(err.contains("the fallback failed too"), "{}", err),
+            Result::Ok(_) => panic!("neither URL should parse"),
+        }
+    }
+
+    fn is_open(breaker: &CircuitBreaker) -> bool {
+        if let BreakerState::Open(_) = breaker.state() { true } else { false }
+    }
+
+    #[test]
+    fn breaker_opens_at_the_threshold() {
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(3600));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.permits());
+        breaker.record_failure();
+        assert!(is_open(&breaker));
+        assert!(!breaker.permits());
+        assert_eq!(breaker.failures(), 3);
+    }
+
+    #[test]
+    fn breaker_with_no_threshold_stays_closed() {
+        let mut breaker = CircuitBreaker::new(0, Duration::from_secs(3600));
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.permits());
+    }
+
+    #[test]
+    fn breaker_lets_one_trial_through_after_the_cooldown() {
+        let mut breaker = CircuitBreaker::new(1, Duration::from_secs(0));
+        breaker.record_failure();
+        assert!(is_open(&breaker));
+        assert!(breaker.permits());
+        assert_eq!(breaker.state(), BreakerState::Trial);
+        assert!(!breaker.permits());
+    }
+
+    #[test]
+    fn successful_trial_closes_the_breaker() {
+        let mut breaker = CircuitBreaker::new(1, Duration::from_secs(0));
+        breaker.record_failure();
+        assert!(breaker.permits());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.failures(), 0);
+        assert!(breaker.permits());
+    }
+
+    #[test]
+    fn failed_trial_reopens_the_breaker() {
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(0));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.permits());
+        breaker.record_failure();
+        assert!(is_open(&breaker));
+        assert_eq!(breaker.failures(), 3);
+        // Reopened for another (here zero) cool-down, after which the next trial may start.
+        assert!(breaker.permits());
+        assert_eq!(breaker.state(), BreakerState::Trial);
+    }
+
+    #[test]
+    fn quotes_are_refetched_once_the_delay_passes() {
+        let (mut cache, clock) = stepped_cache("expiry.json");
+        cache.get_quotes().unwrap();
+        assert_eq!(cache.generation, 1);
+        clock.advance(Duration::from_secs(61));
+        cache.get_quotes().unwrap();
+        assert_eq!(cache.generation, 2);
+    }
+
+    #[test]
+    fn quotes_are_kept_within_the_delay() {
+        let (mut cache, clock) = stepped_cache("fresh.json");
+        cache.get_quotes().unwrap();
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(cache.get_quotes().unwrap()["2018"].months["3"].quotes[0].text, "cached");
+        assert_eq!(cache.generation, 1);
+    }
+
+    #[test]
+    fn failed_fetches_retry_soon_and_successes_restore_the_delay() {
+        let (mut cache, clock) = stepped_cache("flaky.json");
+        let path = cache.sources[0].url.to_file_path().unwrap();
+        fs::remove_file(&path).unwrap();
+        let started = clock.now();
+        assert!(cache.get_quotes().is_err());
+        assert_eq!(cache.last_request_time, started);
+        assert_eq!(cache.next_delay, Duration::from_millis(FAILED_RETRY_DELAY));
+
+        clock.advance(Duration::from_millis(FAILED_RETRY_DELAY - 1));
+        assert!(cache.get_quotes().is_err());
+        assert_eq!(cache.last_request_time, started);
+        clock.advance(Duration::from_millis(1));
+        assert!(cache.get_quotes().is_err());
+        assert_eq!(cache.last_request_time, clock.now());
+        assert_eq!(cache.next_delay, Duration::from_millis(FAILED_RETRY_DELAY));
+
+        temp_file("flaky.json", br#"{ "2018": { "3": ["back"] } }"#);
+        clock.advance(Duration::from_millis(FAILED_RETRY_DELAY));
+        assert!(cache.get_quotes().is_ok());
+        assert_eq!(cache.next_delay, cache.delay);
+        assert!(cache.last_error.is_none());
+    }
+}
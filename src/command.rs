@@ -0,0 +1,351 @@
+use search::{ looks_like_date, parse_date_query, parse_decade, parse_length_range, split_prefix_query,
+              split_regex_query, strip_keyword, MIN_PREFIX_CHARS };
+
+/// A parsed `says` query. Text it carries is a trimmed slice of the input.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ParsedCommand<'a> {
+    /// Nothing after `says`.
+    Empty,
+    Help,
+    /// `?text`, searched for as it is even when it looks like another form.
+    Search(&'a str),
+    /// `#tag [text]`, everything after the `#`.
+    Tag(&'a str),
+    /// `/pattern/ [-> template]`.
+    Regex(&'a str, Option<&'a str>),
+    /// `word*`, the stem before the `*`.
+    Prefix(&'a str),
+    Link(&'a str),
+    Month(&'a str),
+    Source(&'a str),
+    Best(&'a str),
+    /// `batch <count> [text]` or `<count> [text]`: that many distinct quotes, matching the text if there is any.
+    Batch(usize, &'a str),
+    Spread(usize),
+    /// `recent [count]`.
+    Recent(Option<usize>),
+    /// `since <date>`, as the year and maybe month to start from.
+    Since(u32, Option<u32>),
+    List(&'a str),
+    /// `len <min>-<max>`, with `min` no higher than `max`.
+    Length(usize, usize),
+    /// `random <year>`.
+    Year(&'a str),
+    /// `me`, or `for @user` with the id of the user mentioned.
+    Signature(Option<u64>),
+    ThisYear,
+    Now,
+    /// `longest` when true, `shortest` when false.
+    Extreme(bool),
+    /// A decade such as `2010s`, by its first year.
+    Decade(u32),
+    /// A partial date as `(year, month)`; at least one of them is set.
+    Date(Option<u32>, Option<u32>),
+    Exact(&'a str),
+    /// Free text for the layered search.
+    Query(&'a str),
+    /// A form that was recognised but couldn't be read: the toggle it falls under, if any, and what was wrong.
+    Invalid(Option<&'static str>, String),
+}
+
+impl<'a> ParsedCommand<'a> {
+    /// The name a server turns this form off by, if it can be.
+    pub fn mode(&self) -> Option<&'static str> {
+        match *self {
+            ParsedCommand::Tag(_) => Option::Some("tags"),
+            ParsedCommand::Regex(..) => Option::Some("regex"),
+            ParsedCommand::Link(_) => Option::Some("link"),
+            ParsedCommand::Month(_) => Option::Some("month"),
+            ParsedCommand::Source(_) => Option::Some("source"),
+            ParsedCommand::Best(_) => Option::Some("best"),
+            ParsedCommand::Batch(..) => Option::Some("batch"),
+            ParsedCommand::Spread(_) => Option::Some("spread"),
+            ParsedCommand::Recent(_) => Option::Some("recent"),
+            ParsedCommand::Since(..) => Option::Some("since"),
+            ParsedCommand::List(_) => Option::Some("list"),
+            ParsedCommand::Length(..) | ParsedCommand::Extreme(_) => Option::Some("len"),
+            ParsedCommand::Year(_) => Option::Some("random"),
+            ParsedCommand::Signature(_) => Option::Some("me"),
+            ParsedCommand::ThisYear => Option::Some("thisyear"),
+            ParsedCommand::Now => Option::Some("now"),
+            ParsedCommand::Decade(_) => Option::Some("decade"),
+            ParsedCommand::Exact(_) => Option::Some("exact"),
+            ParsedCommand::Invalid(mode, _) => mode,
+            _ => Option::None,
+        }
+    }
+}
+
+/// Parses a `says` query into a `ParsedCommand` before anything is looked up.
+///
+/// Forms are tried in a fixed order and the first one that matches wins: `help`, then `?text` (always a plain search),
+/// then `#tag`, then `/regex/`, then `word*`, then the keyword forms (`link`, `month`, `source`, `best` and `batch`),
+/// then a leading count like `3 socrates`, then the other keyword forms (`spread`, `recent`, `since`, `list`, `len`
+/// and `random`), then `me` and `for @user`, then `thisyear`, `now`, `longest` and `shortest`, then decades, then
+/// partial dates, then `exact`, and finally free text. A partial date is any mix of one year and one month, so
+/// `2018`, `March`, `mar 2018` and `2018-03` all count; a bare number like `3` is a count, not a month.
+///
+/// A form that matches but can't be read, like `batch ten`, comes back `Invalid` rather than falling through to a
+/// search for those words.
+pub fn parse_command(input: &str) -> ParsedCommand {
+    let query = input.trim();
+    if query.is_empty() {
+        return ParsedCommand::Empty;
+    }
+    if query.eq_ignore_ascii_case("help") {
+        return ParsedCommand::Help;
+    }
+    if query.starts_with('?') {
+        let text = query[1..].trim();
+        if !text.is_empty() {
+            return ParsedCommand::Search(text);
+        }
+    }
+    if query.starts_with('#') {
+        return ParsedCommand::Tag(&query[1..]);
+    }
+    if let Option::Some((pattern, rest)) = split_regex_query(query) {
+        return if rest.is_empty() {
+            ParsedCommand::Regex(pattern, Option::None)
+        } else if rest.starts_with("->") {
+            ParsedCommand::Regex(pattern, Option::Some(rest[2..].trim()))
+        } else {
+            ParsedCommand::Invalid(Option::Some("regex"), "Expected `-> template` after the pattern.".to_string())
+        };
+    }
+    if let Option::Some(stem) = split_prefix_query(query) {
+        return if stem.chars().count() < MIN_PREFIX_CHARS {
+            ParsedCommand::Invalid(Option::None, format!("Give me at least {} letters before the `*`, e.g. `meet*`.",
+                                                         MIN_PREFIX_CHARS))
+        } else {
+            ParsedCommand::Prefix(stem)
+        };
+    }
+    if let Option::Some(id) = strip_keyword(query, "link") {
+        return ParsedCommand::Link(id);
+    }
+    if let Option::Some(id) = strip_keyword(query, "month") {
+        return ParsedCommand::Month(id);
+    }
+    if let Option::Some(id) = strip_keyword(query, "source") {
+        return ParsedCommand::Source(id);
+    }
+    if let Option::Some(terms) = strip_keyword(query, "best") {
+        return ParsedCommand::Best(terms);
+    }
+    if let Option::Some(rest) = strip_keyword(query, "batch") {
+        let (count, text) = split_word(rest);
+        return match parse_count(count) {
+            Result::Ok(count) => ParsedCommand::Batch(count, text),
+            Result::Err(err) => ParsedCommand::Invalid(Option::Some("batch"), err),
+        };
+    }
+    // A leading number is a count, short of four digits (a year) or a month before a year like `3 2018`. One that
+    // can't be a count, like the `0` in `0 days`, leaves the query to be searched for.
+    let (count, text) = split_word(query);
+    if count.len() < 4 && count.chars().all(|c| c.is_ascii_digit())
+        && parse_date_query(query).map_or(true, |(year, _)| year.is_none()) {
+        match parse_count(count) {
+            Result::Ok(count) => return ParsedCommand::Batch(count, text),
+            Result::Err(err) => if text.is_empty() {
+                return ParsedCommand::Invalid(Option::Some("batch"), err);
+            },
+        }
+    }
+    if let Option::Some(count) = strip_keyword(query, "spread") {
+        return match parse_count(count) {
+            Result::Ok(count) => ParsedCommand::Spread(count),
+            Result::Err(err) => ParsedCommand::Invalid(Option::Some("spread"), err),
+        };
+    }
+    if query.eq_ignore_ascii_case("recent") {
+        return ParsedCommand::Recent(Option::None);
+    }
+    if let Option::Some(count) = strip_keyword(query, "recent") {
+        return match parse_count(count) {
+            Result::Ok(count) => ParsedCommand::Recent(Option::Some(count)),
+            Result::Err(err) => ParsedCommand::Invalid(Option::Some("recent"), err),
+        };
+    }
+    if let Option::Some(date) = strip_keyword(query, "since") {
+        return match parse_date_query(date) {
+            Option::Some((Option::Some(year), month)) => ParsedCommand::Since(year, month),
+            _ => ParsedCommand::Invalid(Option::Some("since"),
+                "Give me a year, or a month and year, to start from, e.g. `since 2023-06`.".to_string()),
+        };
+    }
+    if let Option::Some(terms) = strip_keyword(query, "list") {
+        return ParsedCommand::List(terms);
+    }
+    if let Option::Some(range) = strip_keyword(query, "len") {
+        return match parse_length_range(range) {
+            Option::Some((min, max)) if min <= max => ParsedCommand::Length(min, max),
+            Option::Some(_) => ParsedCommand::Invalid(Option::Some("len"),
+                "The range has to start no higher than it ends, e.g. `len 50-100`.".to_string()),
+            Option::None => ParsedCommand::Invalid(Option::Some("len"),
+                "Give me a length range in characters, e.g. `len 50-100`.".to_string()),
+        };
+    }
+    if let Option::Some(year) = strip_keyword(query, "random") {
+        return ParsedCommand::Year(year);
+    }
+    if query.eq_ignore_ascii_case("me") {
+        return ParsedCommand::Signature(Option::None);
+    }
+    if let Option::Some(user) = strip_keyword(query, "for").and_then(parse_mention) {
+        return ParsedCommand::Signature(Option::Some(user));
+    }
+    if query.eq_ignore_ascii_case("thisyear") {
+        return ParsedCommand::ThisYear;
+    }
+    if query.eq_ignore_ascii_case("now") {
+        return ParsedCommand::Now;
+    }
+    if query.eq_ignore_ascii_case("longest") || query.eq_ignore_ascii_case("shortest") {
+        return ParsedCommand::Extreme(query.eq_ignore_ascii_case("longest"));
+    }
+    if let Option::Some(decade) = parse_decade(query) {
+        return ParsedCommand::Decade(decade);
+    }
+    if let Option::Some((year, month)) = parse_date_query(query) {
+        return ParsedCommand::Date(year, month);
+    }
+    if looks_like_date(query) {
+        return ParsedCommand::Invalid(Option::None, format!(
+            "I couldn't read `{}` as a date. Try a year and month like `2018-03`, `03/2018` or `March 2018`.", query));
+    }
+    if let Option::Some(text) = strip_keyword(query, "exact") {
+        return ParsedCommand::Exact(text);
+    }
+    ParsedCommand::Query(query)
+}
+
+/// Reads a user mention like `<@123>` or `<@!123>` as the user's id.
+pub fn parse_mention(text: &str) -> Option<u64> {
+    let text = text.trim();
+    if !text.starts_with("<@") || !text.ends_with('>') {
+        return Option::None;
+    }
+    text[2..text.len() - 1].trim_left_matches('!').parse::<u64>().ok()
+}
+
+// Splits off the first word of `text`, returning it and the trimmed rest.
+fn split_word(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Option::Some(end) => (&text[..end], text[end..].trim()),
+        Option::None => (text, ""),
+    }
+}
+
+// A count of quotes, which has to be a whole number above zero.
+fn parse_count(count: &str) -> Result<usize, String> {
+    match count.parse::<usize>() {
+        Result::Ok(count) if count > 0 => Result::Ok(count),
+        _ => Result::Err(format!("`{}` isn't a number of quotes.", count)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid(query: &str) -> Option<&'static str> {
+        match parse_command(query) {
+            ParsedCommand::Invalid(mode, _) => mode,
+            parsed => panic!("{:?} parsed as {:?}", query, parsed),
+        }
+    }
+
+    #[test]
+    fn plain_forms() {
+        assert_eq!(parse_command("   "), ParsedCommand::Empty);
+        assert_eq!(parse_command(" HELP "), ParsedCommand::Help);
+        assert_eq!(parse_command("?hemlock"), ParsedCommand::Search("hemlock"));
+        assert_eq!(parse_command("#philosophy"), ParsedCommand::Tag("philosophy"));
+        assert_eq!(parse_command("/soc.*/"), ParsedCommand::Regex("soc.*", Option::None));
+        assert_eq!(parse_command("/(\\w+)/ -> $1!"), ParsedCommand::Regex("(\\w+)", Option::Some("$1!")));
+        assert_eq!(parse_command("meet*"), ParsedCommand::Prefix("meet"));
+        assert_eq!(parse_command("me"), ParsedCommand::Signature(Option::None));
+        assert_eq!(parse_command("for <@!42>"), ParsedCommand::Signature(Option::Some(42)));
+        assert_eq!(parse_command("thisyear"), ParsedCommand::ThisYear);
+        assert_eq!(parse_command("Now"), ParsedCommand::Now);
+        assert_eq!(parse_command("longest"), ParsedCommand::Extreme(true));
+        assert_eq!(parse_command("shortest"), ParsedCommand::Extreme(false));
+        assert_eq!(parse_command("2010s"), ParsedCommand::Decade(2010));
+        assert_eq!(parse_command("exact Summer of code"), ParsedCommand::Exact("Summer of code"));
+        assert_eq!(parse_command("  socrates hemlock "), ParsedCommand::Query("socrates hemlock"));
+    }
+
+    #[test]
+    fn keyword_forms() {
+        assert_eq!(parse_command("link 2018-3-1"), ParsedCommand::Link("2018-3-1"));
+        assert_eq!(parse_command("month 2018-3-1"), ParsedCommand::Month("2018-3-1"));
+        assert_eq!(parse_command("source 2018-3-1"), ParsedCommand::Source("2018-3-1"));
+        assert_eq!(parse_command("best socrates hemlock"), ParsedCommand::Best("socrates hemlock"));
+        assert_eq!(parse_command("batch 3 socrates"), ParsedCommand::Batch(3, "socrates"));
+        assert_eq!(parse_command("spread 4"), ParsedCommand::Spread(4));
+        assert_eq!(parse_command("recent"), ParsedCommand::Recent(Option::None));
+        assert_eq!(parse_command("recent 2"), ParsedCommand::Recent(Option::Some(2)));
+        assert_eq!(parse_command("since 2018"), ParsedCommand::Since(2018, Option::None));
+        assert_eq!(parse_command("since March 2018"), ParsedCommand::Since(2018, Option::Some(3)));
+        assert_eq!(parse_command("list socrates"), ParsedCommand::List("socrates"));
+        assert_eq!(parse_command("len 10-50"), ParsedCommand::Length(10, 50));
+        assert_eq!(parse_command("random 2018"), ParsedCommand::Year("2018"));
+    }
+
+    #[test]
+    fn unreadable_forms_are_invalid() {
+        assert_eq!(invalid("/a/ extra"), Option::Some("regex"));
+        assert_eq!(invalid("m*"), Option::None);
+        assert_eq!(invalid("batch ten"), Option::Some("batch"));
+        assert_eq!(invalid("batch 0 socrates"), Option::Some("batch"));
+        assert_eq!(invalid("spread many"), Option::Some("spread"));
+        assert_eq!(invalid("recent few"), Option::Some("recent"));
+        assert_eq!(invalid("since March"), Option::Some("since"));
+        assert_eq!(invalid("len 50-10"), Option::Some("len"));
+        assert_eq!(invalid("len long"), Option::Some("len"));
+        assert_eq!(invalid("2018-13"), Option::None);
+        assert_eq!(parse_command("batch ten"), ParsedCommand::Invalid(Option::Some("batch"),
+                                                                      "`ten` isn't a number of quotes.".to_string()));
+    }
+
+    #[test]
+    fn counts_and_years() {
+        assert_eq!(parse_command("3 socrates"), ParsedCommand::Batch(3, "socrates"));
+        assert_eq!(parse_command("3"), ParsedCommand::Batch(3, ""));
+        assert_eq!(parse_command("12"), ParsedCommand::Batch(12, ""));
+        assert_eq!(parse_command("12 angry men"), ParsedCommand::Batch(12, "angry men"));
+        assert_eq!(invalid("0"), Option::Some("batch"));
+        assert_eq!(parse_command("0 days"), ParsedCommand::Query("0 days"));
+        assert_eq!(parse_command("2018"), ParsedCommand::Date(Option::Some(2018), Option::None));
+        assert_eq!(parse_command("March 2018"), ParsedCommand::Date(Option::Some(2018), Option::Some(3)));
+        assert_eq!(parse_command("3 2018"), ParsedCommand::Date(Option::Some(2018), Option::Some(3)));
+        assert_eq!(parse_command("2018-03"), ParsedCommand::Date(Option::Some(2018), Option::Some(3)));
+        assert_eq!(parse_command("march"), ParsedCommand::Date(Option::None, Option::Some(3)));
+    }
+
+    #[test]
+    fn earlier_forms_win() {
+        // `?` keeps everything after it a plain search.
+        assert_eq!(parse_command("?3 socrates"), ParsedCommand::Search("3 socrates"));
+        assert_eq!(parse_command("?link 2018-3-1"), ParsedCommand::Search("link 2018-3-1"));
+        assert_eq!(parse_command("#link"), ParsedCommand::Tag("link"));
+        assert_eq!(parse_command("/help/"), ParsedCommand::Regex("help", Option::None));
+        assert_eq!(parse_command("link*"), ParsedCommand::Prefix("link"));
+        // A keyword needs something after it, or it's just a word to search for.
+        assert_eq!(parse_command("link"), ParsedCommand::Query("link"));
+        assert_eq!(parse_command("help me"), ParsedCommand::Query("help me"));
+        assert_eq!(parse_command("exact 2018"), ParsedCommand::Exact("2018"));
+        assert_eq!(parse_command("2018 march madness"), ParsedCommand::Query("2018 march madness"));
+    }
+
+    #[test]
+    fn modes_name_the_toggle() {
+        assert_eq!(parse_command("batch 3").mode(), Option::Some("batch"));
+        assert_eq!(parse_command("3 socrates").mode(), Option::Some("batch"));
+        assert_eq!(parse_command("shortest").mode(), Option::Some("len"));
+        assert_eq!(parse_command("len 50-10").mode(), Option::Some("len"));
+        assert_eq!(parse_command("socrates").mode(), Option::None);
+        assert_eq!(parse_command("2018").mode(), Option::None);
+    }
+}
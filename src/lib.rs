@@ -0,0 +1,24 @@
+//! Quote loading, caching and selection for Arrayong.
+//!
+//! The Discord bot in `main.rs` is a thin layer over this crate and is only built with the default
+//! `discord` feature. Build with `--no-default-features` to use the library without pulling in serenity.
+
+extern crate ordermap;
+extern crate rand;
+extern crate regex;
+extern crate reqwest;
+extern crate serde_json;
+extern crate sublime_fuzzy;
+extern crate unicode_segmentation;
+
+#[macro_use]
+pub mod logging;
+pub mod cache;
+pub mod command;
+pub mod quote;
+pub mod search;
+
+pub use cache::{ fetch_quotes, CacheError, CacheRetrievalError, QuoteCache, SourceRequest };
+pub use quote::{ export_quotes, parse_quotes, truncate, ParsedQuotes, Quote, QuoteMonth, QuoteYear, Source,
+                 SourceFormat };
+pub use search::{ select_quote, select_random, select_weighted, QuoteRng };
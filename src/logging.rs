@@ -0,0 +1,113 @@
+use cache::as_millis;
+use rand;
+use serde_json;
+use std::cell::Cell;
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+#[doc(hidden)]
+pub use serde_json::Value as Json;
+
+/// Logs an event with a message and optional `key = value` fields.
+///
+/// Nothing is formatted unless the level is enabled, so debug lines cost next to nothing in production.
+#[macro_export]
+macro_rules! log_event {
+    ($level:ident, $event:expr, $message:expr $(, $key:ident = $value:expr)*) => {
+        if $crate::logging::enabled($crate::logging::LogLevel::$level) {
+            $crate::logging::emit_log($crate::logging::LogLevel::$level, $event, $message.to_string(),
+                                      vec![$((stringify!($key), $crate::logging::Json::from($value))),*])
+        }
+    };
+}
+
+/// How log lines are written to stdout/stderr.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LogFormat {
+    Plain,
+    Json,
+}
+
+/// Severity of a log line; lines below the configured level are dropped.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+}
+
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LogLevel::Info as usize);
+thread_local! {
+    static TRACE_ID: Cell<Option<u32>> = Cell::new(Option::None);
+}
+
+/// Sets the output format and minimum level for all subsequent log lines.
+pub fn configure(format: LogFormat, level: LogLevel) {
+    JSON_LOGS.store(format == LogFormat::Json, Ordering::Relaxed);
+    LOG_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// Whether lines at `level` are written at all.
+pub fn enabled(level: LogLevel) -> bool {
+    level as usize >= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Tags log lines on this thread with a short random trace id until `end_request` is called.
+///
+/// Everything logged while handling one command, including any fetch it triggers, carries the same id.
+pub fn begin_request() -> String {
+    let id = rand::random::<u32>();
+    TRACE_ID.with(|trace| trace.set(Option::Some(id)));
+    format!("{:08x}", id)
+}
+
+pub fn end_request() {
+    TRACE_ID.with(|trace| trace.set(Option::None));
+}
+
+/// Writes one log line; use the `log_event!` macro rather than calling this directly.
+pub fn emit_log(level: LogLevel, event: &str, message: String, fields: Vec<(&str, Json)>) {
+    if !enabled(level) {
+        return;
+    }
+    let trace_id = TRACE_ID.with(|trace| trace.get()).map(|id| format!("{:08x}", id));
+    if JSON_LOGS.load(Ordering::Relaxed) {
+        let mut record = serde_json::Map::new();
+        record.insert("ts".to_string(), Json::from(
+            SystemTime::now().duration_since(UNIX_EPOCH).map(as_millis).unwrap_or(0)));
+        record.insert("level".to_string(), Json::from(level.name()));
+        record.insert("event".to_string(), Json::from(event));
+        record.insert("message".to_string(), Json::from(message));
+        if let Option::Some(id) = trace_id {
+            record.insert("trace_id".to_string(), Json::from(id));
+        }
+        for (key, value) in fields {
+            record.insert(key.to_string(), value);
+        }
+        println!("{}", Json::Object(record));
+    } else {
+        let mut line = message;
+        if let Option::Some(id) = trace_id {
+            line = format!("[{}] {}", id, line);
+        }
+        if level >= LogLevel::Warn {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
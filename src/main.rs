@@ -1,308 +1,5318 @@
+#[macro_use]
+extern crate arrayong;
+extern crate ctrlc;
+#[macro_use]
+extern crate lazy_static;
 extern crate ordermap;
 extern crate rand;
 extern crate regex;
 extern crate reqwest;
-extern crate serde;
 extern crate serde_json;
 extern crate serenity;
-extern crate sublime_fuzzy;
 extern crate typemap;
 
+use arrayong::{ export_quotes, fetch_quotes, select_quote, select_random, select_weighted, truncate, Quote, QuoteCache,
+                QuoteRng, QuoteYear, Source, SourceFormat, SourceRequest };
+use arrayong::cache::{ as_millis, default_stopwords, http_client, valid_header, BreakerState, CircuitBreaker,
+                       LatencyWindow, QuoteIndex, TagIndex, Vocabulary, DEFAULT_BREAKER_COOLDOWN,
+                       DEFAULT_BREAKER_THRESHOLD };
+use arrayong::command::{ parse_command, ParsedCommand };
+use arrayong::logging::{ self, begin_request, end_request, LogFormat, LogLevel };
+use arrayong::search::{ best_quote, check_template, compile_query_regex, correct_date_query, correct_keyword,
+                        date_quotes, decade_quotes, exact_quotes, extreme_quotes, find_quote, fuzzy_quote,
+                        interpolate, is_term_query, latest_quotes, length_quotes, normalize, ordered_quotes,
+                        prefix_quotes, quote_month, regex_quotes, search_quote, since_quotes, spread_quotes,
+                        step_quote, strip_keyword, substring_quotes, term_quotes, tokenize, without_blocked,
+                        year_quotes, FuzzyDistance, QuoteOrder, SearchLayer, Step, TermQuery, DEFAULT_SEARCH_ORDER,
+                        MAX_FUZZY_DISTANCE, REGEX_SCAN_LIMIT, REGEX_TIME_BUDGET_MS };
 use ordermap::OrderMap;
 use rand::Rng;
 use regex::Regex;
 use serde_json::Value as Json;
 use serenity::Client;
-use serenity::model::gateway::Ready;
-use serenity::model::channel::Message;
-use serenity::prelude::{ Context, EventHandler };
-use std::{ env, fmt };
+use serenity::builder::CreateMessage;
+use serenity::gateway::GatewayError;
+use serenity::http::HttpError;
+use serenity::model::gateway::{ Game, Ready };
+use serenity::model::channel::{ Message, Reaction, ReactionType };
+use serenity::model::event::MessageUpdateEvent;
+use serenity::model::guild::Guild;
+use serenity::model::id::{ ChannelId, GuildId, MessageId, RoleId, UserId };
+use serenity::model::ModelError;
+use serenity::model::permissions::Permissions;
+use serenity::prelude::{ Context, EventHandler, Mutex as DataMutex };
+use std::{ env, fmt, process, ptr };
+use std::collections::{ HashMap, HashSet, VecDeque };
 use std::error::Error;
+use std::panic::{ self, AssertUnwindSafe };
+use std::str::FromStr;
+use std::fs::{ self, File };
+use std::io::{ BufReader, Read, Write };
 use std::option::Option;
-use std::time::{ Duration, SystemTime, UNIX_EPOCH };
-use sublime_fuzzy::{ best_match };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use typemap::ShareMap;
 
+#[derive(Clone)]
 struct Config {
     url: String,
+    sources: Vec<Source>,
+    request: SourceRequest,
     token: String,
     delay: Duration,
+    jitter: u64,
+    format: SourceFormat,
+    dedup: bool,
+    prefix: String,
+    colour: u32,
+    help_text: String,
+    maintenance_text: String,
+    no_match: String,
+    admins: Vec<UserId>,
+    mod_roles: Vec<RoleId>,
+    cooldown: Duration,
+    state_file: String,
+    allowed_guilds: Vec<GuildId>,
+    leave_unapproved: bool,
+    // Whether `reload` and `maintenance on|off` wait for the admin to confirm with a reaction.
+    confirm_admin: bool,
+    // Whether commands from other bots and webhooks are handled, for setups that relay users through a bot.
+    allow_bots: bool,
+    // Whether a private message that isn't a command gets the help text back.
+    dm_help: bool,
+    words_count: usize,
+    stopwords: HashSet<String>,
+    handle_edits: bool,
+    welcome_dm: bool,
+    footer_index: bool,
+    footer_template: String,
+    age_reactions: Vec<(u32, String)>,
+    // Extra command words and what each stands for, in the order they were configured.
+    aliases: Vec<(String, String)>,
+    // Minutes east of UTC, for anything that depends on the local time of day.
+    utc_offset: i64,
+    // The words `says now` looks for in each of `TIME_PERIODS`, in the same order.
+    time_words: Vec<Vec<String>>,
+    silent: bool,
+    wrap_adjacent: bool,
+    strict_keywords: bool,
+    // Whether a guild's blocklist also hides quotes asked for by id, with `says source` or `says month`.
+    blocklist_lookups: bool,
+    background_refresh: bool,
+    max_fetches: usize,
+    // Failed fetches in a row before fetching pauses for `breaker_cooldown`; 0 keeps retrying on every expiry.
+    breaker_threshold: u32,
+    breaker_cooldown: Duration,
+    // How long the first quotes may take to load before the wait is logged as an error.
+    startup_grace: Duration,
+    link_capacity: usize,
+    recent_size: usize,
+    presence: String,
+    embed_title: Option<String>,
+    image_style: ImageStyle,
+    quote_style: QuoteStyle,
+    rendering: Rendering,
+    command_rendering: HashMap<String, Rendering>,
+    empty_query: EmptyQuery,
+    // What a bare mention of the bot does; defaults to `empty_query`.
+    empty_mention: EmptyQuery,
+    log_format: LogFormat,
+    log_level: LogLevel,
+    random_seed: Option<u64>,
+    fuzzy_distance: FuzzyDistance,
+    search_order: Vec<SearchLayer>,
+    quote_order: QuoteOrder,
+    max_batch: usize,
+    list_size: usize,
+    // How many quotes a bare `says recent` shows.
+    recent_count: usize,
+    // What `digest` is made of, in order.
+    digest: Vec<DigestSection>,
+    thousands_separator: String,
+    max_response_chars: usize,
+    max_stale: Option<Duration>,
+    refuse_stale: Option<Duration>,
+    qotd: Vec<QotdSchedule>,
+}
+
+impl typemap::Key for Config {
+    type Value = Arc<Config>;
+}
+
+#[derive(Debug)]
+struct ConfigError(String);
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bad configuration: {}", &self.0)
+    }
+}
+
+fn env_flag(key: &str) -> bool {
+    env_flag_or(key, false)
+}
+
+// Like `env_flag`, but `default` when the variable isn't set at all.
+fn env_flag_or(key: &str, default: bool) -> bool {
+    env::var(key).map(|res| res == "1" || res.eq_ignore_ascii_case("true")).unwrap_or(default)
+}
+
+fn env_ids(key: &str, name: &str) -> Result<Vec<u64>, ConfigError> {
+    let mut ids = Vec::new();
+    if let Result::Ok(res) = env::var(key) {
+        for id in res.split(',').map(|id| id.trim()).filter(|id| !id.is_empty()) {
+            ids.push(id.parse::<u64>().map_err(|_| ConfigError(format!("config->{}: {}", name, id)))?);
+        }
+    }
+    Result::Ok(ids)
+}
+
+fn env_millis(key: &str, name: &str) -> Result<Option<Duration>, ConfigError> {
+    match env::var(key) {
+        Result::Ok(res) => res.trim().parse::<u64>()
+            .map(|millis| Option::Some(Duration::from_millis(millis)))
+            .map_err(|_| ConfigError(format!("config->{}: {}", name, res))),
+        Result::Err(_) => Result::Ok(Option::None),
+    }
+}
+
+// The number in `key`, or `default` when it isn't set; anything unparseable is an error rather than the default.
+fn env_number<T: FromStr>(key: &str, name: &str, default: T) -> Result<T, ConfigError> {
+    match env::var(key) {
+        Result::Ok(res) => res.trim().parse::<T>().map_err(|_| ConfigError(format!("config->{}: {}", name, res))),
+        Result::Err(_) => Result::Ok(default),
+    }
+}
+
+fn parse_headers(headers: &str) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut parsed: Vec<(String, String)> = Vec::new();
+    for entry in headers.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            Option::Some(value) => value.trim(),
+            Option::None => return Result::Err(ConfigError(format!("config->headers: missing value for `{}`", name))),
+        };
+        if !valid_header(name, value) {
+            return Result::Err(ConfigError(format!("config->headers: invalid header `{}`", name)));
+        }
+        parsed.push((name.to_string(), value.to_string()));
+    }
+    Result::Ok(parsed)
+}
+
+// An optional restriction on which quotes a QOTD channel draws from.
+#[derive(Clone, PartialEq, Debug)]
+enum QotdFilter {
+    Year(String),
+    Source(String),
+}
+
+// One channel's quote of the day, posted daily at `minute` minutes past midnight UTC.
+#[derive(Clone, PartialEq, Debug)]
+struct QotdSchedule {
+    channel: ChannelId,
+    minute: u64,
+    filter: Option<QotdFilter>,
+}
+
+// Parses `channel=HH:MM[/filter]` entries separated by commas, where the filter is a year or a source label.
+fn parse_qotd(entries: &str) -> Result<Vec<QotdSchedule>, ConfigError> {
+    let mut schedules: Vec<QotdSchedule> = Vec::new();
+    for entry in entries.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let invalid = || ConfigError(format!("config->qotd: {}", entry));
+        let mut parts = entry.splitn(2, '=');
+        let channel = parts.next().and_then(|channel| channel.trim().parse::<u64>().ok()).ok_or_else(&invalid)?;
+        let mut schedule = parts.next().ok_or_else(&invalid)?.splitn(2, '/');
+        let mut time = schedule.next().unwrap_or("").trim().splitn(2, ':');
+        let minute = match (time.next().map(str::parse::<u64>), time.next().map(str::parse::<u64>)) {
+            (Option::Some(Result::Ok(hour)), Option::Some(Result::Ok(minute))) if hour < 24 && minute < 60 =>
+                hour * 60 + minute,
+            _ => return Result::Err(invalid()),
+        };
+        let filter = match schedule.next().map(str::trim) {
+            Option::Some("") => return Result::Err(invalid()),
+            Option::Some(filter) if filter.chars().all(|c| c.is_ascii_digit()) =>
+                Option::Some(QotdFilter::Year(filter.to_string())),
+            Option::Some(filter) => Option::Some(QotdFilter::Source(filter.to_string())),
+            Option::None => Option::None,
+        };
+        if schedules.iter().any(|existing| existing.channel == ChannelId(channel)) {
+            return Result::Err(ConfigError(format!("config->qotd: channel {} is listed twice", channel)));
+        }
+        schedules.push(QotdSchedule { channel: ChannelId(channel), minute, filter });
+    }
+    Result::Ok(schedules)
+}
+
+// Swaps `{env:NAME}` tokens in a template for that environment variable, so one config can serve several bots with
+// per-instance values. An unset variable is left out with a warning rather than shown verbatim. This is plain
+// substitution: the value is inserted as-is and never interpolated again.
+fn interpolate_env(template: &str, setting: &str) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Option::Some(start) = rest.find("{env:") {
+        let end = match rest[start..].find('}') {
+            Option::Some(end) => start + end,
+            Option::None => break,
+        };
+        filled.push_str(&rest[..start]);
+        let variable = &rest[start + 5..end];
+        match env::var(variable) {
+            Result::Ok(value) => filled.push_str(&value),
+            Result::Err(_) => log_event!(Warn, "config_unknown_token",
+                                         format!("config->{}: {{env:{}}} isn't set; leaving it out", setting, variable),
+                                         setting = setting, variable = variable),
+        }
+        rest = &rest[end + 1..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+// Fills the `{bot}` and `{guild}` tokens, which can only be known once connected and answering somewhere.
+fn interpolate_context(template: &str, bot: &str, guild: &str) -> String {
+    template.replace("{bot}", bot).replace("{guild}", guild)
+}
+
+// Parses `command=embed` or `command=plain` pairs separated by commas, e.g. `list=plain,says=embed`.
+fn parse_command_rendering(entries: &str) -> Result<HashMap<String, Rendering>, ConfigError> {
+    let mut rendering = HashMap::new();
+    for entry in entries.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        match (parts.next().map(|command| command.trim().to_lowercase()), parts.next().and_then(Rendering::parse)) {
+            (Option::Some(command), Option::Some(style)) if COMMAND_NAMES.contains(&command.as_str()) => {
+                rendering.insert(command, style);
+            },
+            _ => return Result::Err(ConfigError(format!("config->command_rendering: {}", entry))),
+        }
+    }
+    Result::Ok(rendering)
+}
+
+// Reads `+HH:MM`, `-HH:MM` or a bare `+HH` as minutes east of UTC.
+fn parse_utc_offset(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let (sign, rest) = match text.chars().next() {
+        Option::Some('-') => (-1, &text[1..]),
+        Option::Some('+') => (1, &text[1..]),
+        _ => (1, text),
+    };
+    let mut parts = rest.splitn(2, ':');
+    let hours = parts.next().and_then(|hours| hours.parse::<i64>().ok())?;
+    let minutes = match parts.next() {
+        Option::Some(minutes) => minutes.parse::<i64>().ok()?,
+        Option::None => 0,
+    };
+    if hours > 14 || minutes >= 60 {
+        return Option::None;
+    }
+    Option::Some(sign * (hours * 60 + minutes))
+}
+
+// Reads `period=word,word;period=word` overrides for the `says now` word lists; periods left out keep their
+// defaults.
+fn parse_time_words(entries: &str) -> Result<Vec<Vec<String>>, ConfigError> {
+    let mut words: Vec<Vec<String>> = DEFAULT_TIME_WORDS.iter()
+        .map(|list| list.split(',').map(str::to_string).collect())
+        .collect();
+    for entry in entries.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let period = parts.next().map(|period| period.trim().to_lowercase())
+            .and_then(|period| TIME_PERIODS.iter().position(|&(name, _)| name == period));
+        match (period, parts.next()) {
+            (Option::Some(period), Option::Some(list)) => words[period] = list.split(',')
+                .map(|word| word.trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+            _ => return Result::Err(ConfigError(format!("config->time_words: {}", entry))),
+        }
+    }
+    Result::Ok(words)
+}
+
+// Reads `alias=command [args]` entries. Every alias has to come out at a built-in command within
+// `MAX_ALIAS_DEPTH` expansions, so a loop is caught here rather than when someone uses it.
+fn parse_aliases(entries: &str) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut aliases: Vec<(String, String)> = Vec::new();
+    for entry in entries.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        let alias = parts.next().map_or(String::new(), |alias| alias.trim().to_lowercase());
+        let target = parts.next().map_or("", str::trim);
+        let valid = !alias.is_empty() && alias.chars().all(|c| c.is_alphanumeric() || c == '_')
+            && !COMMAND_NAMES.contains(&alias.as_str()) && alias != "setcolor" && !target.is_empty();
+        if !valid {
+            return Result::Err(ConfigError(format!("config->aliases: {}", entry)));
+        }
+        aliases.push((alias, target.to_string()));
+    }
+    for &(ref alias, _) in &aliases {
+        match expand_alias(&aliases, alias.clone(), Option::None) {
+            Option::Some((ref command, _)) if COMMAND_NAMES.contains(&command.as_str()) || command == "setcolor" => {},
+            Option::Some((command, _)) =>
+                return Result::Err(ConfigError(format!("config->aliases: {} leads to unknown command {}", alias,
+                                                       command))),
+            Option::None => return Result::Err(ConfigError(format!("config->aliases: {} loops back on itself", alias))),
+        }
+    }
+    Result::Ok(aliases)
+}
+
+// Reads `section[:count]` entries such as `longest,shortest,random:3`. There's no count on `longest` or
+// `shortest`, and the sections can't add up to more than `MAX_DIGEST_QUOTES` quotes.
+fn parse_digest(entries: &str) -> Result<Vec<DigestSection>, ConfigError> {
+    let mut sections = Vec::new();
+    for entry in entries.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.splitn(2, ':');
+        let name = parts.next().unwrap().trim().to_lowercase();
+        let count = match parts.next().map(|count| count.trim().parse::<usize>()) {
+            Option::Some(Result::Ok(count)) if count > 0 => Option::Some(count),
+            Option::Some(_) => return Result::Err(ConfigError(format!("config->digest: {}", entry))),
+            Option::None => Option::None,
+        };
+        sections.push(match (name.as_str(), count) {
+            ("longest", Option::None) => DigestSection::Longest,
+            ("shortest", Option::None) => DigestSection::Shortest,
+            ("random", count) => DigestSection::Random(count.unwrap_or(1)),
+            ("recent", count) => DigestSection::Recent(count.unwrap_or(1)),
+            _ => return Result::Err(ConfigError(format!("config->digest: {}", entry))),
+        });
+    }
+    let total: usize = sections.iter().map(DigestSection::size).sum();
+    if sections.is_empty() || total > MAX_DIGEST_QUOTES {
+        return Result::Err(ConfigError(format!("config->digest: {} has {} quotes, between 1 and {} fit", entries,
+                                               total, MAX_DIGEST_QUOTES)));
+    }
+    Result::Ok(sections)
+}
+
+// Replaces an alias with the command it stands for, putting any arguments it carries in front of the user's.
+// Gives up with `None` once `MAX_ALIAS_DEPTH` expansions haven't reached something that isn't an alias.
+fn expand_alias(aliases: &[(String, String)], mut command: String, mut args: Option<String>)
+    -> Option<(String, Option<String>)> {
+    for _ in 0..MAX_ALIAS_DEPTH + 1 {
+        let target = match aliases.iter().find(|&&(ref alias, _)| *alias == command) {
+            Option::Some(&(_, ref target)) => target,
+            Option::None => return Option::Some((command, args)),
+        };
+        let mut parts = target.splitn(2, char::is_whitespace);
+        let expanded = parts.next().unwrap_or("").to_lowercase();
+        let fixed = parts.next().map(str::trim).filter(|fixed| !fixed.is_empty());
+        args = match (fixed, args) {
+            (Option::Some(fixed), Option::Some(args)) => Option::Some(format!("{} {}", fixed, args)),
+            (Option::Some(fixed), Option::None) => Option::Some(fixed.to_string()),
+            (Option::None, args) => args,
+        };
+        command = expanded;
+    }
+    Option::None
+}
+
+// Parses `years=emoji` pairs separated by commas, e.g. `0=🆕,5=🕰️`, sorted oldest threshold first so the
+// first one a quote is old enough for wins.
+fn parse_age_reactions(entries: &str) -> Result<Vec<(u32, String)>, ConfigError> {
+    let mut reactions: Vec<(u32, String)> = Vec::new();
+    for entry in entries.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let mut parts = entry.splitn(2, '=');
+        match (parts.next().map(|years| years.trim().parse::<u32>()), parts.next().map(str::trim)) {
+            (Option::Some(Result::Ok(years)), Option::Some(emoji)) if !emoji.is_empty() =>
+                reactions.push((years, emoji.to_string())),
+            _ => return Result::Err(ConfigError(format!("config->age_reactions: {}", entry))),
+        }
+    }
+    reactions.sort_by(|a, b| b.0.cmp(&a.0));
+    Result::Ok(reactions)
+}
+
+// Splits a trailing `=weight` off a labelled source URL. Inside a query string the `=` only separates a
+// weight if the pair before it already has its own `=`, so `?page=2` stays part of the URL.
+fn split_weight(url: &str, allow_file: bool) -> (&str, Option<u32>) {
+    if let Option::Some(split) = url.rfind('=') {
+        let (head, tail) = (&url[..split], &url[split + 1..]);
+        let outside_query = !head.contains('?')
+            || head.rsplit(|c| c == '?' || c == '&').next().map_or(false, |pair| pair.contains('='));
+        if outside_query && source_url(head, allow_file).is_some() {
+            if let Result::Ok(weight) = tail.trim().parse::<u32>() {
+                return (head, Option::Some(weight));
+            }
+        }
+    }
+    (url, Option::None)
+}
+
+// Reads the token from a mounted secret rather than the environment, where it shows up in process listings. The
+// error names the file but never includes anything read from it.
+fn load_token(path: &str) -> Result<String, ConfigError> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| ConfigError(format!("config->token_file: {}: {}", path, err)))?;
+    let token = contents.trim();
+    if token.is_empty() {
+        return Result::Err(ConfigError(format!("config->token_file: {} is empty", path)));
+    }
+    Result::Ok(token.to_string())
+}
+
+// Reads a newline-delimited stopword list; blank lines and `#` comments are skipped.
+fn load_stopwords(path: &str, replace: bool) -> Result<HashSet<String>, ConfigError> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| ConfigError(format!("config->stopwords_file: {}: {}", path, err)))?;
+    let mut stopwords = if replace { HashSet::new() } else { default_stopwords() };
+    stopwords.extend(contents.lines()
+        .map(|line| line.trim().to_lowercase())
+        .filter(|line| !line.is_empty() && !line.starts_with('#')));
+    Result::Ok(stopwords)
+}
+
+// Parses a source URL. With `allow_file`, a bare path (relative to the working directory) also counts,
+// as a `file://` URL.
+fn source_url(url: &str, allow_file: bool) -> Option<reqwest::Url> {
+    match reqwest::Url::parse(url) {
+        Result::Ok(url) => Option::Some(url),
+        Result::Err(_) if allow_file && !url.is_empty() => env::current_dir()
+            .ok()
+            .and_then(|dir| reqwest::Url::from_file_path(dir.join(url)).ok()),
+        Result::Err(_) => Option::None,
+    }
+}
+
+fn parse_sources(urls: &str, allow_file: bool) -> Result<Vec<Source>, ConfigError> {
+    let mut sources: Vec<Source> = Vec::new();
+    for entry in urls.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        // A leading `label=` is only a label if it comes before any `:` or `/` and what follows it still parses
+        // as a URL, so the `=` in `https://host/q.json?a=b` stays part of the URL.
+        let (label, url) = match entry.find(|c| c == '=' || c == ':' || c == '/') {
+            Option::Some(split) if entry[split..].starts_with('=')
+                && source_url(&entry[split + 1..], allow_file).is_some() =>
+                (Option::Some(entry[..split].trim().to_string()), &entry[split + 1..]),
+            _ => (Option::None, entry),
+        };
+        let (url, weight) = if label.is_some() { split_weight(url, allow_file) } else { (url, Option::None) };
+        let url = checked_url(url, entry, "url", allow_file)?;
+        sources.push(Source { label: label.filter(|label| !label.is_empty()), url, weight, fallback: Option::None });
+    }
+    if sources.is_empty() {
+        return Result::Err(ConfigError("config->url".to_string()));
+    }
+    Result::Ok(sources)
+}
+
+fn checked_url(url: &str, entry: &str, name: &str, allow_file: bool) -> Result<reqwest::Url, ConfigError> {
+    let url = source_url(url, allow_file).ok_or_else(|| ConfigError(format!("config->{}: {}", name, entry)))?;
+    match url.scheme() {
+        "http" | "https" => Result::Ok(url),
+        "file" if allow_file => Result::Ok(url),
+        "file" => Result::Err(ConfigError(
+            format!("config->{}: {} (set BOT_ALLOW_FILE to read local files)", name, entry))),
+        _ => Result::Err(ConfigError(format!("config->{}: {} (only http and https are supported)", name, entry))),
+    }
+}
+
+// Fallbacks line up with the sources in `BOT_URL` by position; an empty entry leaves that source without one.
+fn parse_fallbacks(urls: &str, sources: &mut [Source], allow_file: bool) -> Result<(), ConfigError> {
+    let entries: Vec<&str> = urls.split(',').map(str::trim).collect();
+    if entries.len() > sources.len() {
+        return Result::Err(ConfigError(format!("config->fallback_url: {} entries for {} sources",
+                                               entries.len(), sources.len())));
+    }
+    for (source, entry) in sources.iter_mut().zip(entries).filter(|&(_, entry)| !entry.is_empty()) {
+        source.fallback = Option::Some(checked_url(entry, entry, "fallback_url", allow_file)?);
+    }
+    Result::Ok(())
+}
+
+impl Config {
+    fn load(require_token: bool) -> Result<Config, ConfigError> {
+        let url = env::var("BOT_URL").map_err(|_| ConfigError("config->url".to_string()))?;
+        let mut sources = parse_sources(&url, env_flag("BOT_ALLOW_FILE"))?;
+        if let Result::Ok(fallbacks) = env::var("BOT_FALLBACK_URL") {
+            parse_fallbacks(&fallbacks, &mut sources, env_flag("BOT_ALLOW_FILE"))?;
+        }
+        let token = match (env::var("BOT_TOKEN_FILE"), env::var("BOT_TOKEN")) {
+            (Result::Ok(path), _) => load_token(&path)?,
+            (_, Result::Ok(token)) => token,
+            _ if !require_token => String::new(),
+            _ => return Result::Err(ConfigError("config->token".to_string())),
+        };
+        let delay = Duration::from_millis(
+            if let Result::Ok(res) = env::var("BOT_REQ_DELAY") {
+                res.parse::<u64>().unwrap_or(DEFAULT_REQ_DELAY)
+            } else { DEFAULT_REQ_DELAY }
+        );
+        let jitter = match env::var("BOT_REQ_JITTER") {
+            Result::Ok(res) => match res.parse::<u64>() {
+                Result::Ok(jitter) if jitter <= 100 => jitter,
+                _ => return Result::Err(ConfigError(format!("config->jitter: {}", res))),
+            },
+            Result::Err(_) => 0,
+        };
+        let format = match env::var("BOT_SOURCE_FORMAT") {
+            Result::Ok(res) => match res.to_lowercase().as_str() {
+                "auto" => SourceFormat::Auto,
+                "nested" => SourceFormat::Nested,
+                "array" => SourceFormat::Array,
+                "ndjson" => SourceFormat::Ndjson,
+                _ => return Result::Err(ConfigError(format!("config->format: {}", res))),
+            },
+            Result::Err(_) => SourceFormat::Auto,
+        };
+        let method = match env::var("BOT_SOURCE_METHOD") {
+            Result::Ok(res) => res.trim().to_uppercase().parse::<reqwest::Method>()
+                .map_err(|_| ConfigError(format!("config->method: {}", res)))?,
+            Result::Err(_) => reqwest::Method::Get,
+        };
+        let body = env::var("BOT_SOURCE_BODY").ok().filter(|body| !body.is_empty());
+        let request = SourceRequest {
+            method,
+            content_type: env::var("BOT_SOURCE_CONTENT_TYPE").ok()
+                .or_else(|| body.as_ref().map(|_| "application/json".to_string())),
+            body,
+            headers: match env::var("BOT_SOURCE_HEADERS") {
+                Result::Ok(res) => parse_headers(&res)?,
+                Result::Err(_) => Vec::new(),
+            },
+            path: env::var("BOT_SOURCE_PATH").ok().filter(|path| !path.is_empty()),
+        };
+        let image_style = match env::var("BOT_IMAGE_STYLE") {
+            Result::Ok(res) => match res.to_lowercase().as_str() {
+                "image" => ImageStyle::Image,
+                "thumbnail" => ImageStyle::Thumbnail,
+                _ => return Result::Err(ConfigError(format!("config->image_style: {}", res))),
+            },
+            Result::Err(_) => ImageStyle::Image,
+        };
+        let quote_style = match env::var("BOT_QUOTE_STYLE") {
+            Result::Ok(res) => match res.to_lowercase().as_str() {
+                "embed" => QuoteStyle::Embed,
+                "blockquote" => QuoteStyle::Blockquote,
+                _ => return Result::Err(ConfigError(format!("config->quote_style: {}", res))),
+            },
+            Result::Err(_) => QuoteStyle::Embed,
+        };
+        let rendering = match env::var("BOT_RENDERING") {
+            Result::Ok(res) => Rendering::parse(&res)
+                .ok_or_else(|| ConfigError(format!("config->rendering: {}", res)))?,
+            Result::Err(_) => Rendering::Embed,
+        };
+        let command_rendering = parse_command_rendering(&env::var("BOT_COMMAND_RENDERING").unwrap_or_default())?;
+        let empty_query = match env::var("BOT_EMPTY_QUERY") {
+            Result::Ok(res) => EmptyQuery::parse(&res)
+                .ok_or_else(|| ConfigError(format!("config->empty_query: {}", res)))?,
+            Result::Err(_) => EmptyQuery::Random,
+        };
+        let empty_mention = match env::var("BOT_EMPTY_MENTION") {
+            Result::Ok(res) => EmptyQuery::parse(&res)
+                .ok_or_else(|| ConfigError(format!("config->empty_mention: {}", res)))?,
+            Result::Err(_) => empty_query,
+        };
+        let log_format = match env::var("BOT_LOG_FORMAT") {
+            Result::Ok(res) => match res.to_lowercase().as_str() {
+                "plain" => LogFormat::Plain,
+                "json" => LogFormat::Json,
+                _ => return Result::Err(ConfigError(format!("config->log_format: {}", res))),
+            },
+            Result::Err(_) => LogFormat::Plain,
+        };
+        let log_level = match env::var("BOT_LOG_LEVEL") {
+            Result::Ok(res) => match res.to_lowercase().as_str() {
+                "debug" => LogLevel::Debug,
+                "info" => LogLevel::Info,
+                "warn" => LogLevel::Warn,
+                "error" => LogLevel::Error,
+                _ => return Result::Err(ConfigError(format!("config->log_level: {}", res))),
+            },
+            Result::Err(_) => LogLevel::Info,
+        };
+        let search_order = match env::var("BOT_SEARCH_ORDER") {
+            Result::Ok(res) => {
+                let mut layers: Vec<SearchLayer> = Vec::new();
+                for name in res.split(',').filter(|name| !name.trim().is_empty()) {
+                    match SearchLayer::parse(name) {
+                        Option::Some(layer) => if !layers.contains(&layer) {
+                            layers.push(layer);
+                        },
+                        Option::None => return Result::Err(ConfigError(format!("config->search_order: {}", name))),
+                    }
+                }
+                if layers.is_empty() {
+                    return Result::Err(ConfigError(format!("config->search_order: {}", res)));
+                }
+                layers
+            },
+            Result::Err(_) => DEFAULT_SEARCH_ORDER.to_vec(),
+        };
+        let quote_order = match env::var("BOT_QUOTE_ORDER") {
+            Result::Ok(res) => QuoteOrder::parse(&res)
+                .ok_or_else(|| ConfigError(format!("config->quote_order: {}", res)))?,
+            Result::Err(_) => QuoteOrder::Chronological,
+        };
+        let max_stale = env_millis("BOT_MAX_STALE", "max_stale")?;
+        let refuse_stale = env_millis("BOT_REFUSE_STALE", "refuse_stale")?;
+        let qotd = parse_qotd(&env::var("BOT_QOTD").unwrap_or_default())?;
+        let age_reactions = parse_age_reactions(&env::var("BOT_AGE_REACTIONS").unwrap_or_default())?;
+        let aliases = parse_aliases(&env::var("BOT_ALIASES").unwrap_or_default())?;
+        let digest = parse_digest(&env::var("BOT_DIGEST").unwrap_or_else(|_| DEFAULT_DIGEST.to_string()))?;
+        let utc_offset = match env::var("BOT_UTC_OFFSET") {
+            Result::Ok(res) => parse_utc_offset(&res)
+                .ok_or_else(|| ConfigError(format!("config->utc_offset: {}", res)))?,
+            Result::Err(_) => 0,
+        };
+        let time_words = parse_time_words(&env::var("BOT_TIME_WORDS").unwrap_or_default())?;
+        let footer_template = interpolate_env(
+            &env::var("BOT_FOOTER_TEMPLATE").unwrap_or(DEFAULT_FOOTER_TEMPLATE.to_string()), "footer_template");
+        check_footer_template(&footer_template)?;
+        let stopwords = match env::var("BOT_STOPWORDS_FILE") {
+            Result::Ok(path) => load_stopwords(&path, match env::var("BOT_STOPWORDS_MODE") {
+                Result::Ok(ref mode) if mode.eq_ignore_ascii_case("replace") => true,
+                Result::Ok(ref mode) if mode.eq_ignore_ascii_case("merge") => false,
+                Result::Ok(mode) => return Result::Err(ConfigError(format!("config->stopwords_mode: {}", mode))),
+                Result::Err(_) => false,
+            })?,
+            Result::Err(_) => default_stopwords(),
+        };
+        let fuzzy_distance = match env::var("BOT_FUZZY_DISTANCE") {
+            Result::Ok(ref res) if res.eq_ignore_ascii_case("scaled") => FuzzyDistance::Scaled,
+            Result::Ok(res) => match res.trim().parse::<usize>() {
+                Result::Ok(distance) if distance <= MAX_FUZZY_DISTANCE => FuzzyDistance::Fixed(distance),
+                _ => return Result::Err(ConfigError(format!("config->fuzzy_distance: {}", res))),
+            },
+            Result::Err(_) => FuzzyDistance::Scaled,
+        };
+        let random_seed = match env::var("BOT_RANDOM_SEED") {
+            Result::Ok(res) => Option::Some(res.trim().parse::<u64>()
+                .map_err(|_| ConfigError(format!("config->random_seed: {}", res)))?),
+            Result::Err(_) => Option::None,
+        };
+        let colour = match env::var("BOT_COLOUR") {
+            Result::Ok(res) => u32::from_str_radix(res.trim_left_matches('#'), 16)
+                .map_err(|_| ConfigError(format!("config->colour: {}", res)))?,
+            Result::Err(_) => DEFAULT_COLOUR,
+        };
+        let admins = env_ids("BOT_ADMINS", "admins")?.into_iter().map(UserId).collect();
+        let mod_roles = env_ids("BOT_MOD_ROLES", "mod_roles")?.into_iter().map(RoleId).collect();
+        let cooldown = match env::var("BOT_COOLDOWN") {
+            Result::Ok(res) => Duration::from_secs(res.trim().parse::<u64>()
+                .map_err(|_| ConfigError(format!("config->cooldown: {}", res)))?),
+            Result::Err(_) => Duration::from_secs(0),
+        };
+        let allowed_guilds = env_ids("BOT_ALLOWED_GUILDS", "allowed_guilds")?.into_iter().map(GuildId).collect();
+        Result::Ok(Config {
+            url,
+            sources,
+            request,
+            token,
+            delay,
+            jitter,
+            format,
+            dedup: env_flag("BOT_DEDUP"),
+            prefix: env::var("BOT_PREFIX").unwrap_or_else(|_| DEFAULT_PREFIX.to_string()),
+            colour,
+            help_text: interpolate_env(&env::var("BOT_HELP_TEXT").unwrap_or_else(|_| HELP_TEXT.to_string()),
+                                       "help_text"),
+            maintenance_text: interpolate_env(
+                &env::var("BOT_MAINTENANCE_TEXT").unwrap_or_else(|_| MAINTENANCE_TEXT.to_string()), "maintenance_text"),
+            no_match: interpolate_env(&env::var("BOT_NO_MATCH").unwrap_or_else(|_| NO_MATCH_TEXT.to_string()),
+                                      "no_match"),
+            admins,
+            mod_roles,
+            cooldown,
+            state_file: env::var("BOT_STATE_FILE").unwrap_or_else(|_| DEFAULT_STATE_FILE.to_string()),
+            allowed_guilds,
+            leave_unapproved: env_flag("BOT_LEAVE_UNAPPROVED"),
+            confirm_admin: env_flag("BOT_CONFIRM_ADMIN"),
+            allow_bots: env_flag("BOT_ALLOW_BOTS"),
+            dm_help: env_flag_or("BOT_DM_HELP", true),
+            words_count: env_number("BOT_WORDS_COUNT", "words_count", DEFAULT_WORDS_COUNT)?,
+            handle_edits: env_flag("BOT_HANDLE_EDITS"),
+            welcome_dm: env_flag("BOT_WELCOME_DM"),
+            footer_index: env_flag("BOT_FOOTER_INDEX"),
+            footer_template,
+            age_reactions,
+            aliases,
+            utc_offset,
+            time_words,
+            silent: env_flag("BOT_SILENT"),
+            wrap_adjacent: env_flag("BOT_WRAP_ADJACENT"),
+            strict_keywords: env_flag("BOT_STRICT_KEYWORDS"),
+            blocklist_lookups: env_flag("BOT_BLOCKLIST_LOOKUPS"),
+            background_refresh: env_flag("BOT_BACKGROUND_REFRESH"),
+            max_fetches: env_number("BOT_MAX_FETCHES", "max_fetches", 1)?
+                .max(1),
+            breaker_threshold: env_number("BOT_BREAKER_THRESHOLD", "breaker_threshold", DEFAULT_BREAKER_THRESHOLD)?,
+            breaker_cooldown: Duration::from_millis(
+                env_number("BOT_BREAKER_COOLDOWN", "breaker_cooldown", DEFAULT_BREAKER_COOLDOWN)?),
+            startup_grace: Duration::from_millis(
+                env_number("BOT_STARTUP_GRACE", "startup_grace", DEFAULT_STARTUP_GRACE)?),
+            link_capacity: env_number("BOT_LINK_CACHE", "link_cache", DEFAULT_LINK_CAPACITY)?,
+            recent_size: env_number("BOT_RECENT_SIZE", "recent_size", DEFAULT_RECENT_SIZE)?,
+            image_style,
+            quote_style,
+            rendering,
+            command_rendering,
+            empty_query,
+            empty_mention,
+            log_format,
+            log_level,
+            random_seed,
+            fuzzy_distance,
+            search_order,
+            quote_order,
+            max_batch: env_number("BOT_MAX_BATCH", "max_batch", DEFAULT_MAX_BATCH)?
+                .max(1),
+            list_size: env_number("BOT_LIST_SIZE", "list_size", DEFAULT_LIST_SIZE)?
+                .max(1),
+            recent_count: env_number("BOT_RECENT_COUNT", "recent_count", DEFAULT_RECENT_COUNT)?
+                .max(1),
+            digest,
+            thousands_separator: env::var("BOT_THOUSANDS_SEPARATOR").unwrap_or_else(|_| ",".to_string()),
+            max_response_chars: env_number("BOT_MAX_RESPONSE_CHARS", "max_response_chars", EMBED_DESCRIPTION_LIMIT)?
+                .min(EMBED_DESCRIPTION_LIMIT),
+            max_stale,
+            refuse_stale,
+            qotd,
+            stopwords,
+            embed_title: env::var("BOT_EMBED_TITLE").ok()
+                .map(|title| interpolate_env(&title, "embed_title"))
+                .filter(|title| !title.is_empty()),
+            presence: interpolate_env(&env::var("BOT_PRESENCE").unwrap_or_else(|_| DEFAULT_PRESENCE.to_string()),
+                                      "presence"),
+        })
+    }
+    // Each merged source's label and weight, or nothing when picks should stay uniform.
+    fn source_weights(&self) -> Vec<(String, u32)> {
+        if self.sources.len() < 2 || self.sources.iter().all(|source| source.weight.is_none()) {
+            return Vec::new();
+        }
+        self.sources.iter().map(|source| (source.name(), source.weight.unwrap_or(1))).collect()
+    }
+    // How `command`'s responses go out: its own override if it has one, otherwise the global choice.
+    fn rendering(&self, command: &str) -> Rendering {
+        self.command_rendering.get(command).cloned().unwrap_or(self.rendering)
+    }
+    // `count` with its digits grouped by the configured separator.
+    fn count(&self, count: usize) -> String {
+        group_digits(count, &self.thousands_separator)
+    }
+    fn is_admin(&self, user: UserId) -> bool {
+        self.admins.contains(&user)
+    }
+    fn allows_guild(&self, guild: GuildId) -> bool {
+        self.allowed_guilds.is_empty() || self.allowed_guilds.contains(&guild)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum EmptyQuery {
+    Random,
+    Help,
+}
+
+impl EmptyQuery {
+    fn parse(name: &str) -> Option<EmptyQuery> {
+        match name.trim().to_lowercase().as_str() {
+            "random" => Option::Some(EmptyQuery::Random),
+            "help" => Option::Some(EmptyQuery::Help),
+            _ => Option::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ImageStyle {
+    Image,
+    Thumbnail,
+}
+
+// How a quote itself is laid out; `Blockquote` posts it as quoted content with a date line instead of an embed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum QuoteStyle {
+    Embed,
+    Blockquote,
+}
+
+// One part of a `digest`, with how many quotes it takes where that can vary.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum DigestSection {
+    Longest,
+    Shortest,
+    Random(usize),
+    Recent(usize),
+}
+
+impl DigestSection {
+    fn title(&self) -> &'static str {
+        match *self {
+            DigestSection::Longest => "Longest",
+            DigestSection::Shortest => "Shortest",
+            DigestSection::Random(_) => "Random",
+            DigestSection::Recent(_) => "Recent",
+        }
+    }
+    fn size(&self) -> usize {
+        match *self {
+            DigestSection::Longest | DigestSection::Shortest => 1,
+            DigestSection::Random(count) | DigestSection::Recent(count) => count,
+        }
+    }
+    // The section as it's written in `BOT_DIGEST`.
+    fn describe(&self) -> String {
+        match *self {
+            DigestSection::Random(count) | DigestSection::Recent(count) =>
+                format!("{}:{}", self.title().to_lowercase(), count),
+            _ => self.title().to_lowercase(),
+        }
+    }
+}
+
+// Plain content works in channels where the bot hasn't been granted Embed Links.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Rendering {
+    Embed,
+    Plain,
+}
+
+impl Rendering {
+    fn parse(name: &str) -> Option<Rendering> {
+        match name.trim().to_lowercase().as_str() {
+            "embed" => Option::Some(Rendering::Embed),
+            "plain" | "text" => Option::Some(Rendering::Plain),
+            _ => Option::None,
+        }
+    }
 }
 
 const DEFAULT_REQ_DELAY: u64 = 1000 * 60 * 30;
+const DEFAULT_PREFIX: &str = "[]";
+const DEFAULT_COLOUR: u32 = 0x2196F3;
+const ERROR_COLOUR: u32 = 0xF44336;
+const EMBED_TITLE_LIMIT: usize = 256;
+const EMBED_DESCRIPTION_LIMIT: usize = 2048;
+const MESSAGE_CONTENT_LIMIT: usize = 2000;
+const DEFAULT_MAX_BATCH: usize = 5;
+const DEFAULT_LIST_SIZE: usize = 10;
+const DEFAULT_RECENT_COUNT: usize = 3;
+const MAX_PINS: usize = 50;
+const DEFAULT_DIGEST: &str = "longest,shortest,random:3";
+// Keeps a digest to a skim, comfortably inside one message.
+const MAX_DIGEST_QUOTES: usize = 10;
+const REFRESH_TICK: u64 = 30;
+const REFRESH_RESTART_BACKOFF: u64 = 10;
+const DEFAULT_WORDS_COUNT: usize = 10;
+const MAX_WORDS_COUNT: usize = 25;
+const MAX_TRACKED_EDITS: usize = 256;
+const MAX_TRACKED_MESSAGES: usize = 512;
+const SEEN_MESSAGE_WINDOW: u64 = 300;
+const QOTD_TICK: u64 = 30;
+const STATE_FLUSH_INTERVAL: u64 = 30;
+const MAX_TRACKED_COOLDOWNS: usize = 1024;
+const COOLDOWN_REACTION: char = '⏳';
+const DEFAULT_STATE_FILE: &str = "arrayong-state.json";
+// `says` itself and `toggle` are deliberately absent so a server can't lock itself out.
+const TOGGLEABLE: [&str; 26] = ["regex", "link", "source", "best", "batch", "spread", "recent", "since", "list",
+    "random", "decade", "exact", "len", "month", "me", "thisyear", "now", "tags", "words", "similar", "stats",
+    "years", "histogram", "next", "prev", "digest"];
+const SAYS_KEYWORDS: [&str; 12] = ["link", "source", "best", "batch", "spread", "recent", "since", "list", "random",
+    "exact", "len", "month"];
+const COMMAND_NAMES: [&str; 23] = ["says", "similar", "next", "prev", "stats", "years", "tags", "histogram",
+    "words", "digest", "reload", "maintenance", "toggle", "cooldown", "setcolour", "setcategory", "setseed",
+    "block", "unblock", "diag", "config", "export", "help"];
+// Each part of the day and the local hour it starts at; the last one runs on past midnight.
+const TIME_PERIODS: [(&str, u64); 4] = [("morning", 5), ("afternoon", 12), ("evening", 17), ("night", 21)];
+const DEFAULT_TIME_WORDS: [&str; 4] = ["morning,breakfast,sunrise,dawn", "afternoon,lunch,noon",
+    "evening,dinner,sunset,dusk", "night,midnight,bedtime,tonight"];
+// How many aliases may stand for one another in a chain before the chain counts as a loop.
+const MAX_ALIAS_DEPTH: usize = 5;
+const MAX_LISTED_TAGS: usize = 20;
+const MONTH_PREVIEW_CHARS: usize = 80;
+const HISTOGRAM_BARS: usize = 20;
+const HISTOGRAM_WIDTH: usize = 30;
+const PAGE_LINES: usize = 15;
+const PAGE_CHARS: usize = 1800;
+const FOOTER_ICON: &str = "https://avatars1.githubusercontent.com/u/16021050?s=460&v=4";
+const PAGINATION_TIMEOUT: u64 = 120;
+const PAGE_PREV: char = '◀';
+const PAGE_NEXT: char = '▶';
+const CONFIRM_YES: char = '✅';
+const CONFIRM_NO: char = '❌';
+const CONFIRM_TIMEOUT: u64 = 30;
+const DEFAULT_LINK_CAPACITY: usize = 100;
+const DEFAULT_RECENT_SIZE: usize = 10;
+const MAX_RECENT_CHANNELS: usize = 1000;
+const DEFAULT_PRESENCE: &str = "with {count} quotes";
+const SEND_ATTEMPTS: u32 = 3;
+const SEND_BACKOFF: u64 = 500;
+const RATE_LIMIT_BACKOFF: u64 = 5000;
+const START_ATTEMPTS: u32 = 6;
+const START_BACKOFF: u64 = 2000;
+const DEFAULT_STARTUP_GRACE: u64 = 1000 * 60 * 2;
+const STARTUP_RETRY_TICK: u64 = 5;
 const HELP_TEXT: &str = "__Introducing... **Arrayong!**__
-A revolution in philosophy!
-Invoke me with `[]says [date|query]`";
+A revolution in philosophy!";
+const NO_MATCH_TEXT: &str = "No quotes found for `{query}`.";
+const MAINTENANCE_TEXT: &str = "Arrayong is down for maintenance; try again later.";
+const STALE_NOTE: &str = "quotes may be out of date";
+const ONLY_MATCH_NOTE: &str = "only match";
+const DEFAULT_FOOTER_TEMPLATE: &str = "Arraying, {month} {year}";
+const FOOTER_PLACEHOLDERS: [&str; 7] = ["month", "year", "count", "index", "total", "bot", "guild"];
+const NOT_READY_TEXT: &str = "I'm still starting up and the quotes aren't loaded yet; try again in a moment.";
 const MONTHS: [&str; 12] = ["January", "February", "March", "April", "May", "June",
     "July", "August", "September", "October", "November", "December"];
 
-struct QuoteYear {
-    months: OrderMap<String, QuoteMonth>,
-}
-
-struct QuoteMonth {
-    quotes: Vec<Quote>,
-}
-
-struct Quote {
-    year: String,
-    month: String,
-    text: String,
-}
-
-fn parse_quotes(years_dto: Json) -> (OrderMap<String, QuoteYear>, usize) {
-    if let Json::Object(years_map) = years_dto {
-        let mut years: OrderMap<String, QuoteYear> = OrderMap::new();
-        let mut quote_count: usize = 0;
-        for (year_key, months_dto) in years_map {
-            if let Json::Object(months_map) = months_dto {
-                let mut months: OrderMap<String, QuoteMonth> = OrderMap::new();
-                for (month_key, quotes_dto) in months_map {
-                    if let Json::Array(quotes_vec) = quotes_dto {
-                        let mut quotes: Vec<Quote> = Vec::with_capacity(quotes_vec.len());
-                        quote_count += quotes_vec.len();
-                        for quote_dto in quotes_vec {
-                            if let Json::String(quote) = quote_dto {
-                                quotes.push(Quote {
-                                    year: year_key.clone(),
-                                    month: month_key.clone(),
-                                    text: quote,
-                                });
-                            }
-                        }
-                        months.insert(month_key, QuoteMonth { quotes });
-                    }
+// One line of `[]help`. `toggle` names the per-guild switch that hides it, if it has one.
+struct CommandInfo {
+    syntax: &'static str,
+    description: &'static str,
+    admin_only: bool,
+    toggle: Option<&'static str>,
+}
+
+const COMMANDS: [CommandInfo; 49] = [
+    CommandInfo { syntax: "says [date|query]",
+        description: "for a quote matching the query, containing all of its words in any order",
+        admin_only: false, toggle: Option::None },
+    CommandInfo { syntax: "says \"<phrase>\" <word>", description: "for a quote containing every phrase and word",
+        admin_only: false, toggle: Option::None },
+    CommandInfo { syntax: "says <terms> | <terms> -<word>",
+        description: "to match either side of `|`, leaving out quotes with a `-`word",
+        admin_only: false, toggle: Option::None },
+    CommandInfo { syntax: "says random <year>", description: "for a random quote from a given year",
+        admin_only: false, toggle: Option::Some("random") },
+    CommandInfo { syntax: "says silent <query>", description: "to post a quote without notifying anyone",
+        admin_only: false, toggle: Option::None },
+    CommandInfo { syntax: "says pin [query]", description: "to post a quote and pin it in the channel",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "says [month] [year]", description: "for a quote from a month, a year, or both",
+        admin_only: false, toggle: Option::None },
+    CommandInfo { syntax: "says len <min>-<max>", description: "for a quote with that many characters",
+        admin_only: false, toggle: Option::Some("len") },
+    CommandInfo { syntax: "says longest|shortest", description: "for the longest or shortest quote there is",
+        admin_only: false, toggle: Option::Some("len") },
+    CommandInfo { syntax: "says me|for @user", description: "for someone's signature quote, the same every time",
+        admin_only: false, toggle: Option::Some("me") },
+    CommandInfo { syntax: "says <word>*", description: "for a quote with a word starting that way, like `meet*`",
+        admin_only: false, toggle: Option::None },
+    CommandInfo { syntax: "says thisyear", description: "for a quote from the current year",
+        admin_only: false, toggle: Option::Some("thisyear") },
+    CommandInfo { syntax: "says now", description: "for a quote mentioning the time of day, if there is one",
+        admin_only: false, toggle: Option::Some("now") },
+    CommandInfo { syntax: "says 2010s", description: "for a quote from a given decade",
+        admin_only: false, toggle: Option::Some("decade") },
+    CommandInfo { syntax: "says since <date>", description: "to page through every quote from a date on, oldest first",
+        admin_only: false, toggle: Option::Some("since") },
+    CommandInfo { syntax: "says [batch] <count> [text]", description: "for several different quotes, matching any text",
+        admin_only: false, toggle: Option::Some("batch") },
+    CommandInfo { syntax: "says spread <count>", description: "for several quotes spread across the years",
+        admin_only: false, toggle: Option::Some("spread") },
+    CommandInfo { syntax: "says recent [count]", description: "for a few quotes from the latest year to catch up on",
+        admin_only: false, toggle: Option::Some("recent") },
+    CommandInfo { syntax: "says list <query>", description: "to list the first few quotes matching a query",
+        admin_only: false, toggle: Option::Some("list") },
+    CommandInfo { syntax: "says best <query>", description: "for the single most relevant quote",
+        admin_only: false, toggle: Option::Some("best") },
+    CommandInfo { syntax: "says exact <text>", description: "to look up a quote verbatim",
+        admin_only: false, toggle: Option::Some("exact") },
+    CommandInfo { syntax: "says /pattern/ [-> template]",
+        description: "to search by regex, optionally rewriting with `$group`s",
+        admin_only: false, toggle: Option::Some("regex") },
+    CommandInfo { syntax: "says link <id>", description: "to find where a quote was last posted",
+        admin_only: false, toggle: Option::Some("link") },
+    CommandInfo { syntax: "says month <id>", description: "to list every quote filed in the same month as another",
+        admin_only: false, toggle: Option::Some("month") },
+    CommandInfo { syntax: "says source <id>", description: "to see which source a quote came from, with its link",
+        admin_only: false, toggle: Option::Some("source") },
+    CommandInfo { syntax: "says #tag [text]", description: "for a quote with that tag, optionally containing the text",
+        admin_only: false, toggle: Option::Some("tags") },
+    CommandInfo { syntax: "says ?<text>", description: "to search for the text as-is, ignoring the forms above",
+        admin_only: false, toggle: Option::None },
+    CommandInfo { syntax: "histogram", description: "for a chart of quotes per year",
+        admin_only: false, toggle: Option::Some("histogram") },
+    CommandInfo { syntax: "tags", description: "to list every tag and how many quotes carry it",
+        admin_only: false, toggle: Option::Some("tags") },
+    CommandInfo { syntax: "words [count]", description: "for the most common words",
+        admin_only: false, toggle: Option::Some("words") },
+    CommandInfo { syntax: "similar <id>", description: "for the quote sharing the most distinctive words with another",
+        admin_only: false, toggle: Option::Some("similar") },
+    CommandInfo { syntax: "next <id>", description: "for the quote after another one",
+        admin_only: false, toggle: Option::Some("next") },
+    CommandInfo { syntax: "prev <id>", description: "for the quote before another one",
+        admin_only: false, toggle: Option::Some("prev") },
+    CommandInfo { syntax: "stats", description: "for corpus and performance stats",
+        admin_only: false, toggle: Option::Some("stats") },
+    CommandInfo { syntax: "years", description: "to list the years with quotes",
+        admin_only: false, toggle: Option::Some("years") },
+    CommandInfo { syntax: "digest",
+        description: "for a few quotes in one post: the longest, the shortest and some at random",
+        admin_only: false, toggle: Option::Some("digest") },
+    CommandInfo { syntax: "help", description: "to show this list",
+        admin_only: false, toggle: Option::None },
+    CommandInfo { syntax: "toggle <command> on|off", description: "to enable or disable a command in this server",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "cooldown [seconds|reset]", description: "to show or change this server's cooldown",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "setcolour #RRGGBB|reset", description: "to change this server's embed colour",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "setcategory <tag>|reset",
+        description: "to have plain `says` in this channel draw only quotes with that tag",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "setseed <number>|guild|reset",
+        description: "to have everyone in this server see the same run of random quotes",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "block [word]", description: "to keep quotes with a word out of this server, or list them",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "unblock <word>", description: "to allow quotes with a blocked word again",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "reload", description: "to reload the configuration",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "maintenance on|off", description: "to stop answering everyone but admins",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "config", description: "to see the settings the bot is running with, secrets hidden",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "diag", description: "to see how the last fetch went and what the cache is using",
+        admin_only: true, toggle: Option::None },
+    CommandInfo { syntax: "export", description: "to receive the quotes as JSON in a DM",
+        admin_only: true, toggle: Option::None },
+];
+
+// The compiled command pattern, along with the prefix and bot user it was built from so an unchanged `ready`
+// can keep it.
+struct Prefix {
+    regex: Regex,
+    prefix: String,
+    user: UserId,
+}
+
+impl typemap::Key for Prefix {
+    type Value = Prefix;
+}
+
+impl Prefix {
+    fn build(prefix: &str, user: UserId) -> Prefix {
+        let prefix_pattern = format!(r"(?:{}(\w+)|<@!?{}>)\s*(?:(.*)\s*)?", regex::escape(prefix), user);
+        log_event!(Info, "prefix_built", format!("Pattern built: {}", prefix_pattern));
+        Prefix { regex: Regex::new(&prefix_pattern).unwrap(), prefix: prefix.to_string(), user }
+    }
+    fn built_from(&self, prefix: &str, user: UserId) -> bool {
+        self.prefix == prefix && self.user == user
+    }
+    // Whether `content` invokes the bot by mentioning it with nothing after the mention.
+    fn is_bare_mention(&self, content: &str) -> bool {
+        self.regex.captures(content).map_or(false, |groups| {
+            groups.get(1).is_none() && groups.get(2).map_or(true, |query| query.as_str().trim().is_empty())
+        })
+    }
+}
+
+// Builds the command pattern unless the one in `data` already came from this prefix and bot user, returning
+// whether it was rebuilt.
+fn refresh_prefix(data: &mut ShareMap, prefix: &str, user: UserId) -> bool {
+    if data.get::<Prefix>().map_or(false, |built| built.built_from(prefix, user)) {
+        log_event!(Debug, "startup", "Prefix pattern unchanged; keeping it");
+        return false;
+    }
+    log_event!(Info, "startup", "Building prefix pattern...");
+    data.insert::<Prefix>(Prefix::build(prefix, user));
+    true
+}
+
+struct Metrics {
+    commands: LatencyWindow,
+}
+
+impl typemap::Key for Metrics {
+    type Value = Metrics;
+}
+
+// When the bot started waiting for its first quotes, and how long they took to arrive once they have.
+struct Startup {
+    began: Instant,
+    loaded_after: Option<Duration>,
+}
+
+impl Startup {
+    fn loaded(&mut self) {
+        if self.loaded_after.is_none() {
+            self.loaded_after = Option::Some(self.began.elapsed());
+        }
+    }
+    fn status(&self, grace: Duration) -> String {
+        match self.loaded_after {
+            Option::Some(after) => format!("quotes loaded {}ms after starting", as_millis(after)),
+            Option::None if self.began.elapsed() < grace => format!("starting up, no quotes yet after {}ms",
+                                                                   as_millis(self.began.elapsed())),
+            Option::None => format!("still no quotes after {}ms, past the {}ms grace period",
+                                    as_millis(self.began.elapsed()), as_millis(grace)),
+        }
+    }
+}
+
+impl typemap::Key for Startup {
+    type Value = Startup;
+}
+
+struct SelectionRng;
+
+impl typemap::Key for SelectionRng {
+    type Value = Arc<Mutex<QuoteRng>>;
+}
+
+// A generator per guild with a seed from `setseed`, each carrying on from its last pick.
+struct GuildRngs(HashMap<GuildId, Arc<Mutex<QuoteRng>>>);
+
+impl typemap::Key for GuildRngs {
+    type Value = GuildRngs;
+}
+
+// The generator random picks for `guild` should come from: its own seeded one if it has a seed, so everyone there
+// sees the same run of quotes, and otherwise the bot-wide one.
+fn selection_rng(data: &mut ShareMap, guild: Option<GuildId>) -> Arc<Mutex<QuoteRng>> {
+    let seed = guild.and_then(|guild| data.get::<GuildSettings>().unwrap().seeds.get(&guild).cloned());
+    match (guild, seed) {
+        (Option::Some(guild), Option::Some(seed)) => data.get_mut::<GuildRngs>().unwrap().0.entry(guild)
+            .or_insert_with(|| Arc::new(Mutex::new(QuoteRng::new(Option::Some(seed)))))
+            .clone(),
+        _ => data.get::<SelectionRng>().unwrap().clone(),
+    }
+}
+
+struct QuoteCacheKey;
+
+impl typemap::Key for QuoteCacheKey {
+    type Value = QuoteCache;
+}
+
+lazy_static! {
+    static ref PERMISSION_WARNINGS: Mutex<HashSet<ChannelId>> = Mutex::new(HashSet::new());
+}
+
+static RATE_LIMITS: AtomicUsize = AtomicUsize::new(0);
+static RATE_LIMITED_MS: AtomicUsize = AtomicUsize::new(0);
+static REFRESH_STARTED: AtomicBool = AtomicBool::new(false);
+static REFRESH_RESTARTS: AtomicUsize = AtomicUsize::new(0);
+static QOTD_STARTED: AtomicBool = AtomicBool::new(false);
+static STARTUP_RETRYING: AtomicBool = AtomicBool::new(false);
+// How plain-text searches have ended since startup: hits by layer, the quotes the substring hits picked from in
+// all, and searches no layer answered.
+static SUBSTRING_HITS: AtomicUsize = AtomicUsize::new(0);
+static SUBSTRING_MATCHES: AtomicUsize = AtomicUsize::new(0);
+static FUZZY_HITS: AtomicUsize = AtomicUsize::new(0);
+static TYPO_HITS: AtomicUsize = AtomicUsize::new(0);
+static SEARCH_MISSES: AtomicUsize = AtomicUsize::new(0);
+
+fn missing_permissions(channel: ChannelId) -> Option<Permissions> {
+    let (guild_channel, user) = {
+        let cache = serenity::CACHE.read();
+        (cache.guild_channel(channel)?, cache.user.id)
+    };
+    let permissions = guild_channel.read().permissions_for(user).ok()?;
+    Option::Some((Permissions::SEND_MESSAGES | Permissions::EMBED_LINKS) - permissions)
+}
+
+fn warn_permissions(channel: ChannelId, err: &serenity::Error) {
+    if !PERMISSION_WARNINGS.lock().unwrap().insert(channel) {
+        return;
+    }
+    if is_embed_denied(channel, err) {
+        log_event!(Warn, "missing_permissions", format!(
+            "WARNING: the bot can't embed links in channel {}, so it will reply there in plain text. Grant Embed Links \
+             to the bot's role for embeds.", channel), channel_id = channel.0, missing = "EMBED_LINKS");
+        return;
+    }
+    match missing_permissions(channel) {
+        Option::Some(ref missing) if !missing.is_empty() => log_event!(Warn, "missing_permissions", format!(
+            "WARNING: cannot post in channel {}; the bot is missing {:?}. Grant these permissions to the bot's role.",
+            channel, missing), channel_id = channel.0, missing = format!("{:?}", missing)),
+        _ => log_event!(Warn, "missing_permissions", format!(
+            "WARNING: cannot post in channel {} ({}); check the bot has Send Messages and Embed Links there.",
+            channel, err), channel_id = channel.0, error = err.to_string()),
+    }
+}
+
+fn http_status(err: &serenity::Error) -> Option<u16> {
+    if let &serenity::Error::Http(HttpError::UnsuccessfulRequest(ref response)) = err {
+        Option::Some(response.status.to_u16())
+    } else {
+        Option::None
+    }
+}
+
+// Discord reports Retry-After in milliseconds; fall back to a fixed pause if it's missing or garbled.
+fn retry_after(err: &serenity::Error) -> Option<Duration> {
+    if let &serenity::Error::Http(HttpError::UnsuccessfulRequest(ref response)) = err {
+        if response.status.to_u16() != 429 {
+            return Option::None;
+        }
+        let header = response.headers.get_raw("Retry-After")
+            .and_then(|values| values.first())
+            .and_then(|value| String::from_utf8(value.clone()).ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
+        Option::Some(header.map_or(Duration::from_millis(RATE_LIMIT_BACKOFF), Duration::from_millis))
+    } else {
+        Option::None
+    }
+}
+
+fn record_search(query: &str, found: Option<(SearchLayer, Option<usize>)>) {
+    match found {
+        Option::Some((SearchLayer::Substring, matches)) => {
+            SUBSTRING_HITS.fetch_add(1, Ordering::Relaxed);
+            SUBSTRING_MATCHES.fetch_add(matches.unwrap_or(1), Ordering::Relaxed);
+        },
+        Option::Some((SearchLayer::Fuzzy, _)) => {
+            FUZZY_HITS.fetch_add(1, Ordering::Relaxed);
+        },
+        Option::Some((SearchLayer::Typo, _)) => {
+            TYPO_HITS.fetch_add(1, Ordering::Relaxed);
+        },
+        Option::None => {
+            SEARCH_MISSES.fetch_add(1, Ordering::Relaxed);
+        },
+    }
+    let layer = found.map_or("none", |(layer, _)| layer.name());
+    log_event!(Debug, "search_layer", format!("Search for {:?} answered by {} ({:?} matches)", query, layer,
+                                              found.and_then(|(_, matches)| matches)),
+               layer = layer, matches = found.and_then(|(_, matches)| matches).unwrap_or(0));
+}
+
+// Which layers have been answering searches, so a fuzzy distance or stopword list can be tuned from real use.
+fn search_summary(config: &Config) -> String {
+    let substring = SUBSTRING_HITS.load(Ordering::Relaxed);
+    let matches = SUBSTRING_MATCHES.load(Ordering::Relaxed);
+    let average = if substring == 0 { 0.0 } else { matches as f64 / substring as f64 };
+    format!("substring {} (avg {:.1} matches) · fuzzy {} · typo {} · no match {}", config.count(substring), average,
+            config.count(FUZZY_HITS.load(Ordering::Relaxed)), config.count(TYPO_HITS.load(Ordering::Relaxed)),
+            config.count(SEARCH_MISSES.load(Ordering::Relaxed)))
+}
+
+fn rate_limit_summary() -> String {
+    format!("{} hits, {}ms waited", RATE_LIMITS.load(Ordering::Relaxed), RATE_LIMITED_MS.load(Ordering::Relaxed))
+}
+
+fn is_permission_error(err: &serenity::Error) -> bool {
+    match err {
+        &serenity::Error::Model(ModelError::InvalidPermissions(_)) => true,
+        _ => http_status(err) == Option::Some(403),
+    }
+}
+
+// Whether `err` refused an embed because the bot may post in `channel` but not embed links there.
+fn is_embed_denied(channel: ChannelId, err: &serenity::Error) -> bool {
+    is_permission_error(err) && missing_permissions(channel).map_or(false, |missing| {
+        missing.contains(Permissions::EMBED_LINKS) && !missing.contains(Permissions::SEND_MESSAGES)
+    })
+}
+
+fn is_transient_error(err: &serenity::Error) -> bool {
+    match err {
+        &serenity::Error::Hyper(_) | &serenity::Error::Io(_) => true,
+        &serenity::Error::Http(HttpError::UnsuccessfulRequest(_)) => match http_status(err) {
+            Option::Some(status) => status == 429 || status >= 500,
+            Option::None => false,
+        },
+        &serenity::Error::Http(_) => true,
+        _ => false,
+    }
+}
+
+// Whether connecting could succeed on a later try. A rejected token or bad shard setup won't fix itself, so
+// those fail straight away.
+fn is_retryable_start_error(err: &serenity::Error) -> bool {
+    match err {
+        &serenity::Error::Gateway(GatewayError::InvalidAuthentication)
+        | &serenity::Error::Gateway(GatewayError::InvalidShardData)
+        | &serenity::Error::Gateway(GatewayError::OverloadedShard) => false,
+        &serenity::Error::Gateway(_) => true,
+        _ => is_transient_error(err),
+    }
+}
+
+fn send_message<F>(channel: ChannelId, f: F) -> Result<Message, serenity::Error>
+    where F: Fn(CreateMessage) -> CreateMessage {
+    let mut backoff = Duration::from_millis(SEND_BACKOFF);
+    let mut attempt = 1;
+    loop {
+        match channel.send_message(&f) {
+            Result::Ok(sent) => return Result::Ok(sent),
+            Result::Err(err) => {
+                if is_permission_error(&err) {
+                    warn_permissions(channel, &err);
+                    return Result::Err(err);
                 }
-                years.insert(year_key, QuoteYear { months });
-            }
+                let limited = retry_after(&err);
+                if let Option::Some(wait) = limited {
+                    RATE_LIMITS.fetch_add(1, Ordering::Relaxed);
+                    log_event!(Warn, "rate_limited", format!("Rate limited sending to channel {} for {}ms",
+                                                             channel, as_millis(wait)),
+                               channel_id = channel.0, wait_ms = as_millis(wait));
+                }
+                if attempt >= SEND_ATTEMPTS || !is_transient_error(&err) {
+                    return Result::Err(err);
+                }
+                let wait = limited.unwrap_or(backoff);
+                if limited.is_some() {
+                    RATE_LIMITED_MS.fetch_add(as_millis(wait) as usize, Ordering::Relaxed);
+                }
+                log_event!(Warn, "send_retry", format!("Send to channel {} failed (attempt {}/{}), retrying in {}ms: {}",
+                                                       channel, attempt, SEND_ATTEMPTS, as_millis(wait), err),
+                           channel_id = channel.0, attempt = attempt, wait_ms = as_millis(wait));
+                thread::sleep(wait);
+                backoff *= 2;
+                attempt += 1;
+            },
+        }
+    }
+}
+
+fn log_send(msg: &Message, sent: Result<Message, serenity::Error>) -> Option<Message> {
+    match sent {
+        Result::Ok(sent) => Option::Some(sent),
+        Result::Err(err) => {
+            log_event!(Error, "send_failed", format!("Failed to send message: {}", err),
+                       channel_id = msg.channel_id.0, error = err.to_string());
+            Option::None
+        },
+    }
+}
+
+fn send_text(msg: &Message, text: &str) -> Option<Message> {
+    log_send(msg, send_message(msg.channel_id, |m| m.content(text)))
+}
+
+fn send_embed(msg: &Message, title: &str, description: &str, colour: u32) -> Option<Message> {
+    log_send(msg, send_message(msg.channel_id, |m| m
+        .embed(|e| e
+            .title(title)
+            .description(description)
+            .colour(colour)
+        )
+    ))
+}
+
+// Everything a response shows, so `send_card` alone decides whether it goes out as an embed or as plain content.
+struct Card<'a> {
+    title: Option<&'a str>,
+    description: &'a str,
+    footer: Option<&'a str>,
+    icon: bool,
+    image: Option<&'a str>,
+    // Where the content originally came from, shown as a link.
+    context: Option<&'a str>,
+    colour: u32,
+}
+
+impl<'a> Card<'a> {
+    fn new(title: Option<&'a str>, description: &'a str, colour: u32) -> Card<'a> {
+        Card {
+            title,
+            description,
+            footer: Option::None,
+            icon: false,
+            image: Option::None,
+            context: Option::None,
+            colour,
+        }
+    }
+    fn plain(&self) -> String {
+        let mut parts: Vec<String> = Vec::new();
+        parts.extend(self.title.map(|title| format!("**{}**", title)));
+        parts.push(self.description.to_string());
+        parts.extend(self.image.map(str::to_string));
+        // Angle brackets keep Discord from unfurling a preview under the quote.
+        parts.extend(self.context.map(|url| format!("Context: <{}>", url)));
+        parts.extend(self.footer.filter(|footer| !footer.is_empty()).map(|footer| format!("_{}_", footer)));
+        parts.retain(|part| !part.is_empty());
+        parts.join("\n")
+    }
+}
+
+// Breaks `text` into messages of at most `limit` characters, preferring line breaks and only cutting a line
+// that is longer than a whole message on its own.
+fn split_content(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks: Vec<String> = Vec::new();
+    let mut chunk = String::new();
+    let mut chunk_chars = 0;
+    for line in text.split('\n') {
+        let mut line: Vec<char> = line.chars().collect();
+        loop {
+            let separator = if chunk_chars > 0 { 1 } else { 0 };
+            if chunk_chars + separator + line.len() <= limit {
+                if separator > 0 {
+                    chunk.push('\n');
+                }
+                chunk.extend(line.iter());
+                chunk_chars += separator + line.len();
+                break;
+            }
+            if chunk_chars > 0 {
+                chunks.push(chunk);
+                chunk = String::new();
+                chunk_chars = 0;
+                continue;
+            }
+            let rest = line.split_off(limit);
+            chunks.push(line.into_iter().collect());
+            line = rest;
+        }
+    }
+    if chunk_chars > 0 || chunks.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+// Sends `card` as plain content. Anything past Discord's limit goes out as several messages; the first is
+// returned, since that's where a jump link should land.
+fn send_plain(channel: ChannelId, card: &Card, silent: bool) -> Result<Message, serenity::Error> {
+    let mut first: Option<Message> = Option::None;
+    for chunk in split_content(&card.plain(), MESSAGE_CONTENT_LIMIT) {
+        let sent = send_message(channel, |m| silenced(silent, m.content(&chunk)))?;
+        first = first.or(Option::Some(sent));
+    }
+    Result::Ok(first.unwrap())
+}
+
+// Sends `card` the way `config` renders `command`. An embed refused for want of Embed Links is sent again as
+// plain content, so the channel still gets an answer.
+fn send_card(channel: ChannelId, config: &Config, command: &str, card: &Card, silent: bool)
+    -> Result<Message, serenity::Error> {
+    if config.rendering(command) == Rendering::Plain {
+        return send_plain(channel, card, silent);
+    }
+    let sent = send_message(channel, |m| silenced(silent, m
+        .embed(|e| {
+            let mut e = e
+                .description(card.description)
+                .colour(card.colour);
+            if let Option::Some(footer) = card.footer {
+                e = e.footer(|f| if card.icon { f.text(footer).icon_url(FOOTER_ICON) } else { f.text(footer) });
+            }
+            if let Option::Some(url) = card.context {
+                e = e.field("Context", format!("[Original context]({})", url), false);
+            }
+            if let Option::Some(image) = card.image {
+                e = match config.image_style {
+                    ImageStyle::Image => e.image(image),
+                    ImageStyle::Thumbnail => e.thumbnail(image),
+                };
+            }
+            match card.title {
+                Option::Some(title) => e.title(title),
+                Option::None => e,
+            }
+        })
+    ));
+    match sent {
+        Result::Err(ref err) if is_embed_denied(channel, err) => send_plain(channel, card, silent),
+        sent => sent,
+    }
+}
+
+// An informational reply to `msg`, rendered the way `config` renders `command`.
+fn send_info(msg: &Message, config: &Config, command: &str, title: &str, description: &str) -> Option<Message> {
+    let card = Card::new(Option::Some(title), description, config.colour);
+    log_send(msg, send_card(msg.channel_id, config, command, &card, false))
+}
+
+struct PostedLinks {
+    capacity: usize,
+    // The last few quotes posted in each channel, newest at the back, kept even when `capacity` is 0 so
+    // random picks can avoid repeating them. `channels` orders channels by use so the quietest is dropped
+    // first once `MAX_RECENT_CHANNELS` is reached. These are ids rather than index positions, which move with
+    // every refresh and differ between a guild's blocked view and the full cache; `QuoteIndex::position`
+    // resolves them without a walk.
+    recent_size: usize,
+    recent: HashMap<ChannelId, VecDeque<String>>,
+    channels: VecDeque<ChannelId>,
+    order: VecDeque<String>,
+    links: HashMap<String, String>,
+}
+
+impl typemap::Key for PostedLinks {
+    type Value = Arc<Mutex<PostedLinks>>;
+}
+
+impl PostedLinks {
+    fn new(capacity: usize, recent_size: usize) -> PostedLinks {
+        PostedLinks {
+            capacity,
+            recent_size: recent_size.max(1),
+            recent: HashMap::new(),
+            channels: VecDeque::new(),
+            order: VecDeque::new(),
+            links: HashMap::new(),
+        }
+    }
+    fn remember(&mut self, channel: ChannelId, id: &str) {
+        if let Option::Some(position) = self.channels.iter().position(|&entry| entry == channel) {
+            self.channels.remove(position);
+        }
+        self.channels.push_back(channel);
+        while self.channels.len() > MAX_RECENT_CHANNELS {
+            if let Option::Some(quietest) = self.channels.pop_front() {
+                self.recent.remove(&quietest);
+            }
+        }
+        let recent = self.recent.entry(channel).or_insert_with(VecDeque::new);
+        recent.retain(|entry| entry != id);
+        recent.push_back(id.to_string());
+        while recent.len() > self.recent_size {
+            recent.pop_front();
+        }
+    }
+    fn recent(&self, channel: ChannelId) -> Vec<String> {
+        self.recent.get(&channel).map_or(Vec::new(), |recent| recent.iter().cloned().collect())
+    }
+    fn touch(&mut self, id: &str) {
+        if let Option::Some(position) = self.order.iter().position(|entry| entry == id) {
+            if let Option::Some(entry) = self.order.remove(position) {
+                self.order.push_back(entry);
+            }
+        }
+    }
+    fn record(&mut self, channel: ChannelId, id: String, link: String) {
+        self.remember(channel, &id);
+        if self.capacity == 0 {
+            return;
+        }
+        if self.links.insert(id.clone(), link).is_some() {
+            self.touch(&id);
+        } else {
+            self.order.push_back(id);
+            while self.order.len() > self.capacity {
+                if let Option::Some(oldest) = self.order.pop_front() {
+                    self.links.remove(&oldest);
+                }
+            }
+        }
+    }
+    fn get(&mut self, id: &str) -> Option<String> {
+        let link = self.links.get(id).cloned();
+        if link.is_some() {
+            self.touch(id);
+        }
+        link
+    }
+}
+
+fn jump_link(msg: &Message) -> String {
+    format!("https://discordapp.com/channels/{}/{}/{}",
+            msg.guild_id().map_or("@me".to_string(), |guild| guild.to_string()), msg.channel_id, msg.id)
+}
+
+fn send_error(msg: &Message, text: &str) -> Option<Message> {
+    send_embed(msg, "Oops!", text, ERROR_COLOUR)
+}
+
+// Discord's SUPPRESS_NOTIFICATIONS message flag.
+const SILENT_FLAG: u64 = 1 << 12;
+
+// Marks a message silent so it doesn't push-notify anyone. serenity has no builder method for message
+// flags yet, so this sets the raw field; it covers the whole message, content and embeds alike.
+fn silenced(silent: bool, mut m: CreateMessage) -> CreateMessage {
+    if silent {
+        m.0.insert("flags", Json::from(SILENT_FLAG));
+    }
+    m
+}
+
+// Writes `count` with `separator` between each group of three digits, so 12000 reads as "12,000".
+fn group_digits(count: usize, separator: &str) -> String {
+    let digits = count.to_string();
+    let mut grouped = String::new();
+    for (position, digit) in digits.chars().enumerate() {
+        if position > 0 && (digits.len() - position) % 3 == 0 {
+            grouped.push_str(separator);
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+// Fills in the footer template for `quote`. `{count}` and `{total}` need the corpus, so they come out empty
+// without it; empty placeholders take their separators with them rather than leaving `Arraying, ·`.
+fn render_footer(template: &str, quote: &Quote, quotes: Option<&OrderMap<String, QuoteYear>>, separator: &str)
+    -> String {
+    let month = quote.month.parse::<usize>().ok()
+        .and_then(|month| MONTHS.get(month.wrapping_sub(1)))
+        .map_or("", |month| *month);
+    let count = quotes
+        .and_then(|quotes| quotes.get(&quote.year))
+        .and_then(|year| year.months.get(&quote.month))
+        .map_or(String::new(), |month| group_digits(month.quotes.len(), separator));
+    let total = quotes.map_or(String::new(), |quotes| group_digits(quotes.values()
+        .flat_map(|year| year.months.values())
+        .map(|month| month.quotes.len())
+        .sum::<usize>(), separator));
+    let rendered = template
+        .replace("{month}", month)
+        .replace("{year}", &quote.year_display)
+        .replace("{count}", &count)
+        .replace("{index}", &(quote.index + 1).to_string())
+        .replace("{total}", &total);
+    rendered.split('·')
+        .map(|segment| segment.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .map(|segment| segment.trim_matches(|c: char| c == ',' || c.is_whitespace()).to_string())
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<String>>()
+        .join(" · ")
+}
+
+// Rejects any `{placeholder}` `render_footer` wouldn't fill in, so a typo fails at startup instead of showing up
+// verbatim under every quote.
+fn check_footer_template(template: &str) -> Result<(), ConfigError> {
+    let mut rest = template;
+    while let Option::Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Option::Some(end) => start + end,
+            Option::None => return Result::Err(ConfigError(format!("config->footer_template: {}", template))),
+        };
+        let name = &rest[start + 1..end];
+        if !FOOTER_PLACEHOLDERS.contains(&name) {
+            return Result::Err(ConfigError(format!("config->footer_template: unknown placeholder {{{}}}", name)));
+        }
+        rest = &rest[end + 1..];
+    }
+    Result::Ok(())
+}
+
+// `quote` as a markdown blockquote, one `> ` per line so a split message stays quoted, then who said it when.
+fn blockquote_text(quote: &Quote) -> String {
+    let mut lines: Vec<String> = truncate(&quote.text, EMBED_DESCRIPTION_LIMIT).lines()
+        .map(|line| format!("> {}", escape_markdown(line)))
+        .collect();
+    let when = format!("{} {}", month_name(&quote.month), quote.year_display);
+    lines.push(format!("— {}", when.trim()));
+    lines.join("\n")
+}
+
+fn send_quote(channel: ChannelId, quote: &Quote, quotes: Option<&OrderMap<String, QuoteYear>>, config: &Config,
+              links: &Mutex<PostedLinks>, command: &str, title: Option<&str>, note: Option<&str>, silent: bool)
+    -> Result<Message, serenity::Error> {
+    let blockquote = config.quote_style == QuoteStyle::Blockquote;
+    // A blockquote has its own date line, so the footer template would only repeat it.
+    let mut segments = if blockquote {
+        Vec::new()
+    } else {
+        vec![render_footer(&config.footer_template, quote, quotes, &config.thousands_separator)]
+    };
+    // The index is the quote's position in its month in the source, so it only moves if the source does.
+    if config.footer_index {
+        segments.push(format!("#{}", quote.index + 1));
+    }
+    segments.push(quote.id());
+    segments.extend(note.map(str::to_string));
+    segments.extend(quote.source.clone());
+    segments.retain(|segment| !segment.is_empty());
+    let footer = segments.join(" · ");
+    let text = if blockquote {
+        blockquote_text(quote)
+    } else {
+        truncate(&quote.text, EMBED_DESCRIPTION_LIMIT)
+    };
+    let card = Card {
+        footer: Option::Some(footer.as_str()),
+        icon: true,
+        image: quote.image.as_ref().map(|image| image.as_str()),
+        context: quote.url.as_ref().map(|url| url.as_str()),
+        ..Card::new(title, &text, config.colour)
+    };
+    let sent = if blockquote {
+        send_plain(channel, &card, silent)?
+    } else {
+        send_card(channel, config, command, &card, silent)?
+    };
+    links.lock().unwrap().record(sent.channel_id, quote.id(), jump_link(&sent));
+    react_age(config, quote, &sent);
+    Result::Ok(sent)
+}
+
+enum CommandResponse<'a> {
+    Quote(&'a Quote, Option<String>),
+    Pages(String, Vec<String>),
+    // The quotes, how many were asked for, and anything to add to the footer.
+    Batch(Vec<&'a Quote>, usize, Option<String>),
+    // The matches and anything to add to the footer.
+    Listing(Vec<&'a Quote>, Option<String>),
+    Transformed(&'a Quote, String, Option<String>),
+    Text(String),
+    NoMatch(String),
+    Help,
+    Error(String),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Staleness {
+    Fresh,
+    Stale,
+    Refused,
+}
+
+fn staleness(config: &Config, cache: &QuoteCache) -> Staleness {
+    let age = match cache.age() {
+        Option::Some(age) => age,
+        Option::None => return Staleness::Fresh,
+    };
+    if config.refuse_stale.map_or(false, |limit| age >= limit) {
+        Staleness::Refused
+    } else if config.max_stale.map_or(false, |limit| age >= limit) {
+        Staleness::Stale
+    } else {
+        Staleness::Fresh
+    }
+}
+
+// Splits a leading modifier such as `silent` or `pin` off `says` arguments; the modifier alone applies to a
+// random quote.
+fn take_modifier(args: &Option<String>, modifier: &str) -> (bool, Option<String>) {
+    match args.as_ref().map(|args| args.trim()) {
+        Option::Some(query) if query.eq_ignore_ascii_case(modifier) => (true, Option::None),
+        Option::Some(query) => match strip_keyword(query, modifier) {
+            Option::Some(rest) => (true, Option::Some(rest.to_string())),
+            Option::None => (false, args.clone()),
+        },
+        Option::None => (false, Option::None),
+    }
+}
+
+// Reads a mistyped command name such as `reloda` as the command it is one edit away from, unless strict.
+fn correct_command(config: &Config, command: String) -> String {
+    match correct_keyword(&command, &COMMAND_NAMES) {
+        Option::Some(corrected) if !config.strict_keywords => {
+            log_event!(Debug, "keyword_corrected", format!("Interpreting command {} as {}", command, corrected));
+            corrected.to_string()
+        },
+        _ => command,
+    }
+}
+
+// Rewrites near-miss `says` keywords and month names, returning the corrected query and a note for each change.
+// The leading keyword only counts when a query follows it, mirroring `strip_keyword`. Plenty of words and names
+// are a near miss for something, so this is only worth trying on a query that has already failed.
+fn correct_query(query: &str) -> (String, Vec<String>) {
+    let mut parts = query.trim().splitn(2, char::is_whitespace);
+    if let (Option::Some(word), Option::Some(rest)) = (parts.next(), parts.next()) {
+        if let Option::Some(keyword) = correct_keyword(word, &SAYS_KEYWORDS) {
+            if !rest.trim().is_empty() {
+                return (format!("{} {}", keyword, rest.trim()),
+                        vec![format!("interpreting '{}' as {}", word, keyword)]);
+            }
+        }
+    }
+    match correct_date_query(query) {
+        Option::Some((corrected, months)) => (corrected, months.iter()
+            .map(|&(ref word, month)| format!("interpreting '{}' as {}", word, MONTHS[month as usize - 1]))
+            .collect()),
+        Option::None => (query.to_string(), Vec::new()),
+    }
+}
+
+// Narrows a plain `says` in a channel with a default category to `#category`, keeping any text as the search
+// within it. Anything but free text (a `#tag`, `?text`, regex, `word*`, keyword form or date) picks its own quotes
+// and is left alone, as is an empty query that shows help.
+fn with_category(config: &Config, category: Option<&String>, args: Option<String>) -> Option<String> {
+    let category = match category {
+        Option::Some(category) => category,
+        Option::None => return args,
+    };
+    let query = args.as_ref().map_or("", |args| args.trim()).to_string();
+    if query.is_empty() {
+        return if config.empty_query == EmptyQuery::Help { args } else { Option::Some(format!("#{}", category)) };
+    }
+    match parse_command(&query) {
+        ParsedCommand::Query(_) => Option::Some(format!("#{} {}", category, query)),
+        _ => args,
+    }
+}
+
+// Answers a `says` query from `cache`, which the caller has just tried to refresh; `ready` is whether that worked.
+fn do_command<'a>(cache: &'a QuoteCache, view: Option<&'a BlockedView>, ready: bool, config: &Config,
+                  links: &Mutex<PostedLinks>, rng: &mut QuoteRng, channel: ChannelId, author: UserId,
+                  disabled: &HashSet<String>, args: &Option<String>) -> CommandResponse<'a> {
+    let query = args.as_ref().map_or("", |args| args.trim());
+    let command = parse_command(query);
+    if let Option::Some(mode) = command.mode() {
+        if disabled.contains(mode) {
+            return CommandResponse::Text(format!("`says {}` is disabled here.", mode));
+        }
+    }
+    if !ready {
+        return CommandResponse::Text(NOT_READY_TEXT.to_string());
+    }
+    let staleness = staleness(config, cache);
+    if staleness == Staleness::Refused {
+        return CommandResponse::Error("The quotes are too far out of date to serve right now.".to_string());
+    }
+    let mut answer = |command: &ParsedCommand, query: &str| {
+        // A lookup by id names the quote it wants, so the blocklist only gets in its way if configured to.
+        let lookup = match *command {
+            ParsedCommand::Source(_) | ParsedCommand::Month(_) => true,
+            _ => false,
+        };
+        let (quotes, tags, index) = match view {
+            Option::Some(view) if !lookup || config.blocklist_lookups => (&view.quotes, &view.tags, &view.index),
+            _ => (cache.cache.as_ref().unwrap(), &cache.tags, &cache.index),
+        };
+        query_response(quotes, tags, index, config, links, rng, channel, author, query, command)
+    };
+    let mut response = answer(&command, query);
+    let mut notes = Vec::new();
+    // Near misses are only corrected once the query as written has failed, so a search for `mary` or `lost keys`
+    // isn't read as `may` or `list keys` when there are quotes to find.
+    if !config.strict_keywords && is_miss(&command, &response) {
+        let (corrected, corrections) = correct_query(query);
+        let retry = parse_command(&corrected);
+        if !corrections.is_empty() && !retry.mode().map_or(false, |mode| disabled.contains(mode)) {
+            let retried = answer(&retry, &corrected);
+            if !is_miss(&retry, &retried) {
+                response = retried;
+                notes = corrections;
+            }
+        }
+    }
+    if staleness == Staleness::Stale {
+        notes.push(STALE_NOTE.to_string());
+    }
+    with_notes(response, notes)
+}
+
+// Whether a query failed outright: it couldn't be read, or nothing matched it.
+fn is_miss(command: &ParsedCommand, response: &CommandResponse) -> bool {
+    match (command, response) {
+        (&ParsedCommand::Invalid(..), _) | (_, &CommandResponse::NoMatch(_)) => true,
+        _ => false,
+    }
+}
+
+// Adds `notes` wherever a response shows quotes: after the footer note of a quote, batch or listing, and in the
+// title of paged results, which keep it from page to page.
+fn with_notes(response: CommandResponse, notes: Vec<String>) -> CommandResponse {
+    if notes.is_empty() {
+        return response;
+    }
+    let merged = |note: Option<String>| Option::Some(note.into_iter().chain(notes.clone()).collect::<Vec<String>>()
+        .join(" · "));
+    match response {
+        CommandResponse::Quote(quote, note) => CommandResponse::Quote(quote, merged(note)),
+        CommandResponse::Transformed(quote, text, note) => CommandResponse::Transformed(quote, text, merged(note)),
+        CommandResponse::Batch(quotes, requested, note) => CommandResponse::Batch(quotes, requested, merged(note)),
+        CommandResponse::Listing(matches, note) => CommandResponse::Listing(matches, merged(note)),
+        CommandResponse::Pages(title, pages) => CommandResponse::Pages(format!("{} · {}", title, notes.join(" · ")),
+                                                                       pages),
+        response => response,
+    }
+}
+
+// Answers a parsed `says` query; `query` is the text it was parsed from, for replies that echo it.
+fn query_response<'a>(quotes: &'a OrderMap<String, QuoteYear>, tags: &TagIndex, index: &QuoteIndex,
+                      config: &Config, links: &Mutex<PostedLinks>, rng: &mut QuoteRng, channel: ChannelId,
+                      author: UserId, query: &str, command: &ParsedCommand) -> CommandResponse<'a> {
+    let recent_positions = recent_positions(index, links, channel);
+    let recent: Vec<&Quote> = recent_positions.iter().filter_map(|&position| index.get(quotes, position)).collect();
+    match *command {
+        ParsedCommand::Empty => {
+            if config.empty_query == EmptyQuery::Help {
+                return CommandResponse::Help;
+            }
+            // A uniform pick comes straight from the index, stepping around quotes posted here recently. Only a
+            // weighted one needs every quote collected, since it groups them by source first.
+            let weights = config.source_weights();
+            if weights.is_empty() {
+                let quote = match index.pick(quotes, rng) {
+                    Option::Some(quote) if recent.iter().any(|&recent| ptr::eq(quote, recent)) =>
+                        index.pick_avoiding(quotes, rng, &recent_positions),
+                    picked => picked,
+                };
+                return match quote {
+                    Option::Some(quote) => CommandResponse::Quote(quote, Option::None),
+                    Option::None => CommandResponse::Error("There are no quotes to choose from.".to_string()),
+                };
+            }
+            let all: Vec<&Quote> = quotes.values()
+                .flat_map(|year| year.months.values())
+                .flat_map(|month| month.quotes.iter())
+                .collect();
+            match select_weighted(&all, &weights, rng, &recent) {
+                Option::Some(quote) => CommandResponse::Quote(quote, Option::None),
+                Option::None => CommandResponse::Error("There are no quotes to choose from.".to_string()),
+            }
+        },
+        ParsedCommand::Help => CommandResponse::Help,
+        ParsedCommand::Search(text) | ParsedCommand::Query(text) => layered_response(quotes, config, rng, text),
+        ParsedCommand::Tag(tag) => tag_response(quotes, tags, config, &recent, rng, tag),
+        ParsedCommand::Regex(pattern, template) => {
+            let regex = match compile_query_regex(pattern) {
+                Result::Ok(regex) => regex,
+                Result::Err(err) => return CommandResponse::Error(err),
+            };
+            if let Option::Some(template) = template {
+                if let Result::Err(err) = check_template(&regex, template) {
+                    return CommandResponse::Error(err);
+                }
+            }
+            let matches = regex_quotes(quotes, &regex, REGEX_SCAN_LIMIT,
+                                       Duration::from_millis(REGEX_TIME_BUDGET_MS));
+            if !matches.complete {
+                log_event!(Info, "regex_capped", format!("Regex search stopped after {} quotes", matches.scanned),
+                           scanned = matches.scanned, matched = matches.quotes.len());
+            }
+            let quote = match select_random(&matches.quotes, rng, &recent) {
+                Option::Some(quote) => quote,
+                Option::None if !matches.complete => return CommandResponse::Text(format!(
+                    "No matches in the first {} quotes; the search stopped early. Try a more specific pattern.",
+                    config.count(matches.scanned))),
+                Option::None => return CommandResponse::NoMatch(query.to_string()),
+            };
+            let note = if matches.quotes.len() == 1 {
+                ONLY_MATCH_NOTE.to_string()
+            } else if matches.complete {
+                format!("1 of {} matches", config.count(matches.quotes.len()))
+            } else {
+                format!("1 of {} matches in the first {} quotes searched", config.count(matches.quotes.len()),
+                        config.count(matches.scanned))
+            };
+            match (template, regex.captures(&quote.text)) {
+                (Option::Some(template), Option::Some(captures)) =>
+                    CommandResponse::Transformed(quote, interpolate(&captures, template), Option::Some(note)),
+                _ => CommandResponse::Quote(quote, Option::Some(note)),
+            }
+        },
+        ParsedCommand::Prefix(stem) => {
+            let matches = prefix_quotes(quotes, stem);
+            match select_random(&matches, rng, &recent) {
+                Option::Some(quote) => CommandResponse::Quote(quote, Option::Some(
+                    pick_note(config, matches.len(), &format!("with a word starting with \"{}\"", stem)))),
+                Option::None => CommandResponse::NoMatch(query.to_string()),
+            }
+        },
+        ParsedCommand::Link(id) => match links.lock().unwrap().get(id) {
+            Option::Some(link) => CommandResponse::Text(
+                format!("Quote `{}` was last posted here: {}", id, link)),
+            Option::None => CommandResponse::Text(
+                format!("I haven't posted quote `{}` recently.", id)),
+        },
+        ParsedCommand::Month(id) => match quote_month(quotes, id) {
+            Option::Some((anchor, month)) => CommandResponse::Pages(
+                format!("{} {} — {} quotes", month_name(&anchor.month), anchor.year_display,
+                        config.count(month.quotes.len())),
+                paginate_lines(&month_lines(anchor, &month.quotes))),
+            Option::None => CommandResponse::Text(format!("There's no quote with id `{}`.", id)),
+        },
+        ParsedCommand::Source(id) => match find_quote(quotes, id) {
+            Option::Some(&Quote { url: Option::Some(ref url), ref source, .. }) => CommandResponse::Text(
+                match *source {
+                    Option::Some(ref source) => format!("Quote `{}` came from {}: <{}>", id, source, url),
+                    Option::None => format!("Quote `{}` came from <{}>", id, url),
+                }),
+            Option::Some(&Quote { source: Option::Some(ref source), .. }) => CommandResponse::Text(
+                format!("Quote `{}` came from {}.", id, source)),
+            Option::Some(_) => CommandResponse::Text(
+                format!("Quote `{}` came from the only configured source.", id)),
+            Option::None => CommandResponse::NoMatch(id.to_string()),
+        },
+        ParsedCommand::Best(terms) => {
+            // Stopwords only narrow `best` needlessly, unless they're all there is to go on.
+            let tokens = tokenize(terms);
+            let significant: Vec<String> = tokens.iter()
+                .filter(|token| !config.stopwords.contains(*token))
+                .cloned()
+                .collect();
+            match best_quote(quotes, if significant.is_empty() { &tokens } else { &significant }) {
+                Option::Some(quote) => CommandResponse::Quote(quote, Option::None),
+                Option::None => CommandResponse::NoMatch(terms.to_string()),
+            }
+        },
+        ParsedCommand::Batch(requested, "") => {
+            let all = quotes.values()
+                .flat_map(|year| year.months.values())
+                .flat_map(|month| month.quotes.iter());
+            let mut batch = rand::sample(rng, all, requested.min(config.max_batch));
+            if batch.is_empty() {
+                return CommandResponse::Error("There are no quotes to choose from.".to_string());
+            }
+            // Sampling keeps the archive's order for most of what it picks, so mix them up.
+            rng.shuffle(&mut batch);
+            CommandResponse::Batch(batch, requested, Option::None)
+        },
+        ParsedCommand::Batch(requested, terms) => {
+            let matches = if is_term_query(terms) {
+                match TermQuery::parse(terms) {
+                    Result::Ok(terms) => term_quotes(quotes, &terms),
+                    Result::Err(err) => return CommandResponse::Error(err),
+                }
+            } else {
+                substring_quotes(quotes, terms)
+            };
+            let total = matches.len();
+            let mut batch = rand::sample(rng, matches, requested.min(config.max_batch));
+            if batch.is_empty() {
+                // Without the keyword, `12 angry men` was as likely a search as a count, so try it as one.
+                if strip_keyword(query, "batch").is_none() {
+                    return layered_response(quotes, config, rng, query);
+                }
+                return CommandResponse::NoMatch(terms.to_string());
+            }
+            rng.shuffle(&mut batch);
+            CommandResponse::Batch(batch, requested, Option::Some(
+                format!("{} matching \"{}\"", config.count(total), terms)))
+        },
+        ParsedCommand::Spread(requested) => {
+            let spread = spread_quotes(quotes, requested.min(config.max_batch), rng, &recent);
+            if spread.is_empty() {
+                return CommandResponse::Error("There are no quotes to choose from.".to_string());
+            }
+            CommandResponse::Batch(spread, requested, Option::None)
+        },
+        ParsedCommand::Recent(requested) => {
+            let requested = requested.unwrap_or(config.recent_count);
+            let (latest, years) = latest_quotes(quotes, requested.min(config.max_batch), rng);
+            let span = match (years.last(), years.first()) {
+                (Option::Some(oldest), Option::Some(newest)) if oldest != newest => format!("{}–{}", oldest, newest),
+                (_, Option::Some(newest)) => newest.to_string(),
+                _ => return CommandResponse::Error("There are no dated quotes to choose from.".to_string()),
+            };
+            CommandResponse::Batch(latest, requested, Option::Some(format!("from {}", span)))
+        },
+        ParsedCommand::Since(year, month) => {
+            let from = match month {
+                Option::Some(month) => format!("{} {}", MONTHS[month as usize - 1], year),
+                Option::None => year.to_string(),
+            };
+            let matches = since_quotes(quotes, year, month);
+            if matches.is_empty() {
+                return CommandResponse::Text(format!("There are no quotes from {} on.", from));
+            }
+            let lines: Vec<String> = matches.iter().map(|quote| preview_line(quote)).collect();
+            CommandResponse::Pages(format!("Since {} — {} quotes", from, config.count(matches.len())),
+                                   paginate_lines(&lines))
+        },
+        ParsedCommand::List(terms) => {
+            let matches = if is_term_query(terms) {
+                match TermQuery::parse(terms) {
+                    Result::Ok(terms) => term_quotes(quotes, &terms),
+                    Result::Err(err) => return CommandResponse::Error(err),
+                }
+            } else {
+                substring_quotes(quotes, terms)
+            };
+            if matches.is_empty() {
+                return CommandResponse::NoMatch(terms.to_string());
+            }
+            CommandResponse::Listing(matches, Option::None)
+        },
+        ParsedCommand::Length(min, max) => {
+            let matches = length_quotes(quotes, min, max);
+            match select_random(&matches, rng, &recent) {
+                Option::Some(quote) => CommandResponse::Quote(quote, Option::Some(
+                    pick_note(config, matches.len(), &format!("{}-{} characters long", min, max)))),
+                Option::None => CommandResponse::Text(
+                    format!("No quotes are between {} and {} characters long.", min, max)),
+            }
+        },
+        ParsedCommand::Year(year) => {
+            let matches = year_quotes(quotes, year);
+            match select_random(&matches, rng, &recent) {
+                Option::Some(quote) => CommandResponse::Quote(quote, Option::Some(
+                    pick_note(config, matches.len(), &format!("from {}", year)))),
+                Option::None => CommandResponse::Error(format!("There are no quotes from {}.", year)),
+            }
+        },
+        ParsedCommand::Signature(user) => {
+            let user = user.map_or(author, UserId);
+            match signature_quote(quotes, config, user) {
+                Option::Some(quote) => CommandResponse::Quote(quote,
+                    Option::Some(format!("signature quote for {}", user_name(user)))),
+                Option::None => CommandResponse::Error("There are no quotes to choose from.".to_string()),
+            }
+        },
+        ParsedCommand::ThisYear => {
+            let year = current_year().to_string();
+            let matches = year_quotes(quotes, &year);
+            match select_random(&matches, rng, &recent) {
+                Option::Some(quote) => CommandResponse::Quote(quote, Option::Some(
+                    pick_note(config, matches.len(), &format!("from {}", year)))),
+                Option::None => CommandResponse::Text(format!("There are no quotes from {} yet.", year)),
+            }
+        },
+        ParsedCommand::Now => time_response(quotes, config, &recent, rng),
+        ParsedCommand::Extreme(longest) => {
+            let superlative = if longest { "longest" } else { "shortest" };
+            let matches = extreme_quotes(quotes, longest);
+            match select_random(&matches, rng, &recent) {
+                Option::Some(quote) => {
+                    let length = quote.text.chars().count();
+                    CommandResponse::Quote(quote, Option::Some(if matches.len() == 1 {
+                        format!("{} quote · {} characters", superlative, length)
+                    } else {
+                        format!("1 of {} tied {} · {} characters", config.count(matches.len()), superlative, length)
+                    }))
+                },
+                Option::None => CommandResponse::Text("There are no quotes to measure yet.".to_string()),
+            }
+        },
+        ParsedCommand::Decade(decade) => {
+            let matches = decade_quotes(quotes, decade);
+            match select_random(&matches, rng, &recent) {
+                Option::Some(quote) => CommandResponse::Quote(quote, Option::Some(
+                    pick_note(config, matches.len(), &format!("from the {}s", decade)))),
+                Option::None => CommandResponse::Error(
+                    format!("There are no quotes from the {}s.", decade)),
+            }
+        },
+        ParsedCommand::Date(year, month) => {
+            let when = match (month, year) {
+                (Option::Some(month), Option::Some(year)) => format!("{} {}", MONTHS[month as usize - 1], year),
+                (Option::Some(month), Option::None) => format!("any {}", MONTHS[month as usize - 1]),
+                (Option::None, Option::Some(year)) => year.to_string(),
+                (Option::None, Option::None) => unreachable!(),
+            };
+            let matches = date_quotes(quotes, year, month);
+            match select_random(&matches, rng, &recent) {
+                Option::Some(quote) => CommandResponse::Quote(quote, Option::Some(
+                    pick_note(config, matches.len(), &format!("from {}", when)))),
+                Option::None => CommandResponse::Error(format!("There are no quotes from {}.", when)),
+            }
+        },
+        ParsedCommand::Exact(text) => {
+            let matches = exact_quotes(quotes, text);
+            if let Option::Some(quote) = select_random(&matches, rng, &recent) {
+                let note = if matches.len() > 1 {
+                    Option::Some(format!("1 of {} identical quotes", config.count(matches.len())))
+                } else {
+                    Option::None
+                };
+                return CommandResponse::Quote(quote, note);
+            }
+            match fuzzy_quote(quotes, text) {
+                Option::Some(quote) => CommandResponse::Quote(quote,
+                    Option::Some("no exact match; closest quote".to_string())),
+                Option::None => CommandResponse::Text(format!("No quote matches `{}` exactly.", text)),
+            }
+        },
+        ParsedCommand::Invalid(_, ref reason) => CommandResponse::Error(reason.clone()),
+    }
+}
+
+// A quote mentioning the current local hour or part of the day, or a random one when nothing does.
+fn time_response<'a>(quotes: &'a OrderMap<String, QuoteYear>, config: &Config, recent: &[&Quote],
+                     rng: &mut QuoteRng) -> CommandResponse<'a> {
+    let hour = ((utc_now().1 as i64 + config.utc_offset) % 1440 + 1440) % 1440 / 60;
+    let period = TIME_PERIODS.iter().rposition(|&(_, start)| hour as u64 >= start).unwrap_or(TIME_PERIODS.len() - 1);
+    let clock = if hour % 12 == 0 { 12 } else { hour % 12 };
+    let suffix = if hour < 12 { "am" } else { "pm" };
+    let mut words = config.time_words[period].clone();
+    words.extend(vec![format!("{}{}", clock, suffix), format!("{} {}", clock, suffix), format!("{} o'clock", clock),
+                      format!("{:02}:", hour)]);
+    let matches = term_quotes(quotes, &TermQuery::any(&words));
+    let name = TIME_PERIODS[period].0;
+    if let Option::Some(quote) = select_random(&matches, rng, recent) {
+        return CommandResponse::Quote(quote,
+            Option::Some(pick_note(config, matches.len(), &format!("for the {}", name))));
+    }
+    let all: Vec<&Quote> = quotes.values()
+        .flat_map(|year| year.months.values())
+        .flat_map(|month| month.quotes.iter())
+        .collect();
+    match select_random(&all, rng, recent) {
+        Option::Some(quote) => CommandResponse::Quote(quote,
+            Option::Some(format!("random pick · nothing mentions the {}", name))),
+        Option::None => CommandResponse::Error("There are no quotes to choose from.".to_string()),
+    }
+}
+
+// A random quote tagged with the first word of `query`, narrowed to those containing the rest of it.
+fn tag_response<'a>(quotes: &'a OrderMap<String, QuoteYear>, tags: &TagIndex, config: &Config,
+                    recent: &[&Quote], rng: &mut QuoteRng, query: &str) -> CommandResponse<'a> {
+    let mut parts = query.splitn(2, char::is_whitespace);
+    let tag = parts.next().unwrap_or("").to_lowercase();
+    let text = parts.next().map_or("", str::trim);
+    let tagged = tags.quotes(quotes, &tag);
+    if tagged.is_empty() {
+        let known = tags.counts();
+        if known.is_empty() {
+            return CommandResponse::Error("None of the quotes are tagged.".to_string());
+        }
+        let listing = known.iter().take(MAX_LISTED_TAGS)
+            .map(|&(tag, _)| format!("#{}", tag))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let more = if known.len() > MAX_LISTED_TAGS { ", …" } else { "" };
+        return CommandResponse::Error(format!("There's no `#{}` tag. Try {}{}", tag, listing, more));
+    }
+    let candidates: Vec<&Quote> = if text.is_empty() {
+        tagged
+    } else {
+        let text = normalize(text);
+        tagged.into_iter().filter(|quote| normalize(&quote.text).contains(&text)).collect()
+    };
+    match select_random(&candidates, rng, recent) {
+        Option::Some(quote) => CommandResponse::Quote(quote, Option::Some(
+            pick_note(config, candidates.len(), &format!("tagged #{}", tag)))),
+        Option::None => CommandResponse::NoMatch(format!("#{} {}", tag, text)),
+    }
+}
+
+// The quotes posted recently in `channel` that are still in the cache, for random picks to steer around.
+fn recent_positions(index: &QuoteIndex, links: &Mutex<PostedLinks>, channel: ChannelId) -> Vec<usize> {
+    let recent = links.lock().unwrap().recent(channel);
+    recent.iter().filter_map(|id| index.position(id)).collect()
+}
+
+// The user's name from the cache, falling back to their id; embed footers can't render mentions.
+fn user_name(user: UserId) -> String {
+    match serenity::CACHE.read().user(user) {
+        Option::Some(cached) => cached.read().name.clone(),
+        Option::None => user.to_string(),
+    }
+}
+
+// The same quote for the same user every time, at least until the corpus changes: a generator seeded with their
+// id picks a position in the chronological listing.
+fn signature_quote<'a>(quotes: &'a OrderMap<String, QuoteYear>, config: &Config, user: UserId) -> Option<&'a Quote> {
+    let ordered = ordered_quotes(quotes, config.quote_order);
+    if ordered.is_empty() {
+        return Option::None;
+    }
+    let pick = QuoteRng::new(Option::Some(user.0)).gen_range(0, ordered.len());
+    Option::Some(ordered[pick])
+}
+
+// Footer note for a pick out of `count` candidates, e.g. `1 of 12 from 2018`, or `only match from 2018`
+// when nothing was left to chance.
+fn pick_note(config: &Config, count: usize, detail: &str) -> String {
+    if count == 1 {
+        format!("{} {}", ONLY_MATCH_NOTE, detail)
+    } else {
+        format!("1 of {} {}", config.count(count), detail)
+    }
+}
+
+fn layered_response<'a>(quotes: &'a OrderMap<String, QuoteYear>, config: &Config, rng: &mut QuoteRng,
+                        query: &str) -> CommandResponse<'a> {
+    let terms = if is_term_query(query) {
+        match TermQuery::parse(query) {
+            Result::Ok(terms) => Option::Some(terms),
+            Result::Err(err) => return CommandResponse::Error(err),
+        }
+    } else {
+        Option::None
+    };
+    let found = search_quote(quotes, query, &config.search_order, config.fuzzy_distance, rng);
+    record_search(query, found.map(|(_, layer, candidates)| (layer, candidates)));
+    match found {
+        Option::Some((quote, SearchLayer::Substring, Option::Some(count))) if terms.is_some() => {
+            let terms = terms.unwrap();
+            CommandResponse::Quote(quote, Option::Some(pick_note(config, count, &match terms.term_count() {
+                1 if terms.is_conjunction() => "matching the phrase".to_string(),
+                len if terms.is_conjunction() => format!("matching all {} terms", len),
+                _ => "matching the query".to_string(),
+            })))
+        },
+        Option::Some((quote, layer, candidates)) => CommandResponse::Quote(quote, Option::Some(
+            if candidates == Option::Some(1) {
+                format!("{} match (only match)", layer.name())
+            } else {
+                format!("{} match", layer.name())
+            })),
+        Option::None => CommandResponse::NoMatch(query.to_string()),
+    }
+}
+
+fn embed_title(config: &Config, query: &str) -> Option<String> {
+    config.embed_title.as_ref().map(|template| truncate(&template.replace("{query}", query), EMBED_TITLE_LIMIT))
+}
+
+// Lists the commands this user can run here, leaving out admin commands and anything disabled in the guild.
+fn help_text(config: &Config, msg: &Message, disabled: &HashSet<String>) -> String {
+    let is_admin = config.is_admin(msg.author.id);
+    let mut lines = vec![config.help_text.clone(), String::new()];
+    lines.extend(COMMANDS.iter()
+        .filter(|command| is_admin || !command.admin_only)
+        .filter(|command| command.toggle.map_or(true, |toggle| !disabled.contains(toggle)))
+        .map(|command| format!("`{}{}` {}", config.prefix, command.syntax, command.description)));
+    lines.extend(config.aliases.iter()
+        .map(|&(ref alias, ref target)| format!("`{}{}` is short for `{}{}`", config.prefix, alias, config.prefix,
+                                                target)));
+    lines.push(match config.empty_query {
+        EmptyQuery::Random => format!("Use `{}says` on its own for a random quote", config.prefix),
+        EmptyQuery::Help => format!("Use `{}says` on its own to show this help", config.prefix),
+    });
+    truncate(&lines.join("\n"), EMBED_DESCRIPTION_LIMIT)
+}
+
+// Joins as many of `quotes` as fit in `limit` chars, returning the text and the quotes that made it in. The
+// first quote is truncated rather than dropped if it doesn't fit on its own.
+fn pack_quotes<'a>(quotes: &[&'a Quote], limit: usize, numbered: bool) -> (String, Vec<&'a Quote>) {
+    let mut description = String::new();
+    let mut shown: Vec<&Quote> = Vec::new();
+    for quote in quotes {
+        let attribution = format!("— {} {} · `{}`", month_name(&quote.month), quote.year_display, quote.id());
+        let entry = if numbered {
+            format!("**{}.** {}\n{}", shown.len() + 1, quote.text, attribution)
+        } else {
+            format!("{}\n{}", quote.text, attribution)
+        };
+        let separator = if description.is_empty() { 0 } else { 2 };
+        if description.chars().count() + separator + entry.chars().count() > limit {
+            if shown.is_empty() {
+                description = truncate(&entry, limit);
+                shown.push(quote);
+            }
+            break;
+        }
+        if separator > 0 {
+            description.push_str("\n\n");
+        }
+        description.push_str(&entry);
+        shown.push(quote);
+    }
+    (description, shown)
+}
+
+fn record_links(links: &Mutex<PostedLinks>, sent: &Message, quotes: &[&Quote]) {
+    let link = jump_link(sent);
+    let mut links = links.lock().unwrap();
+    for quote in quotes {
+        links.record(sent.channel_id, quote.id(), link.clone());
+    }
+}
+
+// Lists as many of `quotes` as fit in the response cap, noting anything that was clamped in the footer.
+fn send_batch(msg: &Message, config: &Config, links: &Mutex<PostedLinks>, title: Option<&str>,
+              quotes: &[&Quote], requested: usize, note: Option<&str>, silent: bool)
+    -> Result<Message, serenity::Error> {
+    let (description, shown) = pack_quotes(quotes, config.max_response_chars, false);
+    let mut footer = format!("Arraying · {} quotes", config.count(shown.len()));
+    if let Option::Some(note) = note {
+        footer.push_str(&format!(" · {}", note));
+    }
+    if requested > config.max_batch {
+        footer.push_str(&format!(" · clamped from {} to {}", config.count(requested), config.count(config.max_batch)));
+    }
+    if shown.len() < quotes.len() {
+        footer.push_str(&format!(" · {} more didn't fit", config.count(quotes.len() - shown.len())));
+    }
+    let card = Card { footer: Option::Some(footer.as_str()), ..Card::new(title, &description, config.colour) };
+    let sent = send_card(msg.channel_id, config, "says", &card, silent)?;
+    record_links(links, &sent, &shown);
+    Result::Ok(sent)
+}
+
+// The first `list_size` matches in one numbered message, for channels where paging by reaction isn't an option.
+fn send_listing(msg: &Message, config: &Config, links: &Mutex<PostedLinks>, title: Option<&str>,
+                matches: &[&Quote], note: Option<&str>, silent: bool) -> Result<Message, serenity::Error> {
+    let first = &matches[..matches.len().min(config.list_size)];
+    let (description, shown) = pack_quotes(first, config.max_response_chars, true);
+    let mut footer = format!("Arraying · showing {} of {}", config.count(shown.len()), config.count(matches.len()));
+    if let Option::Some(note) = note {
+        footer.push_str(&format!(" · {}", note));
+    }
+    let card = Card { footer: Option::Some(footer.as_str()), ..Card::new(title, &description, config.colour) };
+    let sent = send_card(msg.channel_id, config, "says", &card, silent)?;
+    record_links(links, &sent, &shown);
+    Result::Ok(sent)
+}
+
+// Backslash-escapes Discord markdown so user text can't restyle (or break out of) a template.
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\*_~`|>".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Puts `query` into a template's `{query}`. Inside a code span, as in `` `{query}` ``, backslashes would show up
+// literally, so only backticks are swapped out there; anywhere else the query's markdown is escaped.
+fn fill_query(template: &str, query: &str) -> String {
+    if template.contains("`{query}`") {
+        template.replace("`{query}`", &format!("`{}`", query.replace('`', "'")))
+            .replace("{query}", &escape_markdown(query))
+    } else {
+        template.replace("{query}", &escape_markdown(query))
+    }
+}
+
+// Sends any response but `Pages`, which needs the share map for its page state and so goes to `send_paginated`.
+fn respond(msg: &Message, config: &Config, links: &Mutex<PostedLinks>, quotes: Option<&OrderMap<String, QuoteYear>>,
+           disabled: &HashSet<String>, query: &str, silent: bool, response: CommandResponse) -> Option<Message> {
+    let sent = match response {
+        CommandResponse::Quote(quote, note) => {
+            let title = embed_title(config, query);
+            send_quote(msg.channel_id, quote, quotes, config, links, "says",
+                       title.as_ref().map(|title| title.as_str()), note.as_ref().map(|note| note.as_str()),
+                       silent)
+        },
+        CommandResponse::Batch(quotes, requested, note) => {
+            let title = embed_title(config, query);
+            send_batch(msg, config, links, title.as_ref().map(|title| title.as_str()), &quotes, requested,
+                       note.as_ref().map(|note| note.as_str()), silent)
+        },
+        CommandResponse::Listing(matches, note) => {
+            let title = embed_title(config, query);
+            send_listing(msg, config, links, title.as_ref().map(|title| title.as_str()), &matches,
+                         note.as_ref().map(|note| note.as_str()), silent)
+        },
+        CommandResponse::Transformed(quote, text, note) => {
+            let title = embed_title(config, query);
+            let transformed = Quote { text, ..quote.clone() };
+            send_quote(msg.channel_id, &transformed, quotes, config, links, "says",
+                       title.as_ref().map(|title| title.as_str()), note.as_ref().map(|note| note.as_str()), silent)
+        },
+        CommandResponse::Text(text) => return send_text(msg, &text),
+        CommandResponse::Pages(..) => unreachable!("paged responses are sent by send_paginated"),
+        CommandResponse::NoMatch(query) =>
+            return send_text(msg, &fill_query(&config.no_match, &query)),
+        CommandResponse::Help => return send_info(msg, config, "help", "Commands", &help_text(config, msg, disabled)),
+        CommandResponse::Error(text) => return send_error(msg, &text),
+    };
+    log_send(msg, sent)
+}
+
+fn do_export(cache: &mut QuoteCache, config: &Config, msg: &Message) {
+    if !config.is_admin(msg.author.id) {
+        send_text(msg, "Only bot admins can export the quotes.");
+        return;
+    }
+    let export = match cache.get_quotes() {
+        Result::Ok(quotes) => export_quotes(quotes).to_string(),
+        Result::Err(_) => {
+            send_text(msg, NOT_READY_TEXT);
+            return;
+        },
+    };
+    let sent = msg.author.create_dm_channel().and_then(|dm| dm.id.send_files(
+        vec![(export.as_bytes(), "quotes.json")],
+        |m| m.content(&format!("Exported {} quotes.", config.count(cache.cache_size)))));
+    match sent {
+        Result::Ok(_) => {
+            log_event!(Info, "export", format!("Exported {} quotes for {}", cache.cache_size, msg.author.id),
+                       user_id = msg.author.id.0, bytes = export.len());
+            if msg.guild_id().is_some() {
+                send_text(msg, "Sent you the export.");
+            }
+        },
+        Result::Err(err) => {
+            log_event!(Error, "export_failed", format!("Failed to send export: {}", err), error = err.to_string());
+            send_error(msg, "I couldn't DM you the export.");
+        },
+    }
+}
+
+fn do_words(cache: &mut QuoteCache, config: &Config, msg: &Message, args: &Option<String>) {
+    if cache.get_quotes().is_err() {
+        send_text(msg, NOT_READY_TEXT);
+        return;
+    }
+    let count = args.as_ref()
+        .and_then(|arg| arg.trim().parse::<usize>().ok())
+        .unwrap_or(config.words_count)
+        .min(MAX_WORDS_COUNT);
+    let words = cache.vocabulary.top(count);
+    if words.is_empty() {
+        send_text(msg, "There aren't any interesting words yet.");
+        return;
+    }
+    let listing = words.iter().enumerate()
+        .map(|(i, &(word, frequency))| format!("{}. **{}** ({})", i + 1, word, frequency))
+        .collect::<Vec<String>>()
+        .join("\n");
+    send_info(msg, config, "words", "Most common words", &listing);
+}
+
+fn do_similar(cache: &mut QuoteCache, config: &Config, links: &Mutex<PostedLinks>, msg: &Message,
+              args: &Option<String>) {
+    let id = match args.as_ref().map(|args| args.trim()).filter(|args| !args.is_empty()) {
+        Option::Some(id) => id,
+        Option::None => {
+            send_error(msg, &format!("Give me a quote id, e.g. `{}similar 2018-03-2`.", config.prefix));
+            return;
+        },
+    };
+    // Refresh first, then borrow the cache and the vocabulary it was indexed into side by side.
+    let ready = cache.get_quotes().is_ok();
+    let quotes = match cache.cache.as_ref() {
+        Option::Some(quotes) if ready => quotes,
+        _ => {
+            send_text(msg, NOT_READY_TEXT);
+            return;
+        },
+    };
+    let target = match find_quote(quotes, id) {
+        Option::Some(target) => target,
+        Option::None => {
+            send_error(msg, &format!("There's no quote with id `{}`.", id));
+            return;
+        },
+    };
+    match cache.vocabulary.similar(quotes, target, 1).first() {
+        Option::Some(&(quote, _)) => {
+            let note = format!("most similar to {}", id);
+            log_send(msg, send_quote(msg.channel_id, quote, Option::Some(quotes), config, links, "similar",
+                                     Option::None, Option::Some(&note), config.silent));
+        },
+        Option::None => {
+            send_text(msg, &format!("No other quote shares any distinctive words with `{}`.", id));
+        },
+    }
+}
+
+// Picks the quotes for each of `sections` in turn. A quote is only used once per digest, so a section on a small
+// corpus can come up short or empty.
+fn digest_quotes<'a, R: Rng>(quotes: &'a OrderMap<String, QuoteYear>, sections: &[DigestSection], rng: &mut R)
+    -> Vec<(DigestSection, Vec<&'a Quote>)> {
+    let mut picked: Vec<&'a Quote> = Vec::new();
+    let mut digest = Vec::new();
+    for &section in sections {
+        // Asking for extra covers the ones dropped below for already being in the digest.
+        let candidates = match section {
+            DigestSection::Longest => extreme_quotes(quotes, true),
+            DigestSection::Shortest => extreme_quotes(quotes, false),
+            DigestSection::Random(count) => spread_quotes(quotes, count + picked.len(), rng, &picked),
+            DigestSection::Recent(count) => latest_quotes(quotes, count + picked.len(), rng).0,
+        };
+        let fresh: Vec<&'a Quote> = candidates.into_iter()
+            .filter(|quote| !picked.iter().any(|other| other.id() == quote.id()))
+            .take(section.size())
+            .collect();
+        picked.extend(fresh.iter().cloned());
+        digest.push((section, fresh));
+    }
+    digest
+}
+
+// Posts `config.digest`'s sections together in one message, leaving out anything this server blocks.
+fn do_digest(data: &mut ShareMap, config: &Config, links: &Mutex<PostedLinks>, msg: &Message) {
+    let ready = data.get_mut::<QuoteCacheKey>().unwrap().get_quotes().is_ok();
+    refresh_blocked_view(data, msg.guild_id());
+    let rng = selection_rng(data, msg.guild_id());
+    let view = msg.guild_id().and_then(|guild| data.get::<BlockedViews>().unwrap().0.get(&guild));
+    let quotes = match view.map(|view| &view.quotes).or(data.get::<QuoteCacheKey>().unwrap().cache.as_ref()) {
+        Option::Some(quotes) if ready => quotes,
+        _ => {
+            send_text(msg, NOT_READY_TEXT);
+            return;
+        },
+    };
+    let digest = digest_quotes(quotes, &config.digest, &mut *rng.lock().unwrap());
+    let mut description = String::new();
+    let mut shown: Vec<&Quote> = Vec::new();
+    for &(section, ref picked) in digest.iter().filter(|&&(_, ref picked)| !picked.is_empty()) {
+        let heading = format!("**{}**\n", section.title());
+        let separator = if description.is_empty() { 0 } else { 2 };
+        let used = description.chars().count() + separator + heading.chars().count();
+        if used >= config.max_response_chars {
+            break;
+        }
+        let (text, fitted) = pack_quotes(picked, config.max_response_chars - used, false);
+        if separator > 0 {
+            description.push_str("\n\n");
+        }
+        description.push_str(&heading);
+        description.push_str(&text);
+        shown.extend(fitted);
+    }
+    if shown.is_empty() {
+        send_text(msg, "There are no quotes to make a digest from.");
+        return;
+    }
+    let total: usize = digest.iter().map(|&(_, ref picked)| picked.len()).sum();
+    let mut footer = format!("Arraying · digest of {} quotes", config.count(shown.len()));
+    if shown.len() < total {
+        footer.push_str(&format!(" · {} more didn't fit", config.count(total - shown.len())));
+    }
+    let card = Card { footer: Option::Some(footer.as_str()),
+                      ..Card::new(Option::Some("Digest"), &description, config.colour) };
+    if let Option::Some(sent) = log_send(msg, send_card(msg.channel_id, config, "digest", &card, config.silent)) {
+        record_links(links, &sent, &shown);
+    }
+}
+
+// Posts the quote after (or before) the one with the given id, so a conversation can be read around it.
+fn do_adjacent(cache: &mut QuoteCache, config: &Config, links: &Mutex<PostedLinks>, msg: &Message,
+               args: &Option<String>, forward: bool) {
+    let command = if forward { "next" } else { "prev" };
+    let id = match args.as_ref().map(|args| args.trim()).filter(|args| !args.is_empty()) {
+        Option::Some(id) => id,
+        Option::None => {
+            send_error(msg, &format!("Give me a quote id, e.g. `{}{} 2018-03-2`.", config.prefix, command));
+            return;
+        },
+    };
+    let quotes = match cache.get_quotes() {
+        Result::Ok(quotes) => quotes,
+        Result::Err(_) => {
+            send_text(msg, NOT_READY_TEXT);
+            return;
+        },
+    };
+    match step_quote(quotes, id, config.quote_order, forward, config.wrap_adjacent) {
+        Step::Moved(quote, position, total) => {
+            let note = format!("{} of {}", position + 1, total);
+            log_send(msg, send_quote(msg.channel_id, quote, Option::Some(quotes), config, links, command,
+                                     Option::None, Option::Some(&note), config.silent));
+        },
+        Step::Edge => {
+            send_text(msg, &format!("`{}` is the {} quote.", id, if forward { "last" } else { "first" }));
+        },
+        Step::Unknown => {
+            send_error(msg, &format!("There's no quote with id `{}`.", id));
+        },
+    }
+}
+
+struct Pagination {
+    channel: ChannelId,
+    title: String,
+    pages: Vec<String>,
+    current: usize,
+    colour: u32,
+    last_used: Instant,
+}
+
+struct Paginations(HashMap<MessageId, Pagination>);
+
+impl typemap::Key for Paginations {
+    type Value = Paginations;
+}
+
+impl Paginations {
+    fn expire(&mut self) {
+        let timeout = Duration::from_secs(PAGINATION_TIMEOUT);
+        let expired: Vec<MessageId> = self.0.iter()
+            .filter(|&(_, pagination)| pagination.last_used.elapsed() >= timeout)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            if let Option::Some(pagination) = self.0.remove(&id) {
+                if let Result::Err(err) = serenity::http::delete_message_reactions(pagination.channel.0, id.0) {
+                    log_event!(Debug, "pagination_cleanup_failed",
+                               format!("Could not clear page reactions on {}: {}", id, err), message_id = id.0);
+                }
+            }
+        }
+    }
+}
+
+fn paginate_lines(lines: &[String]) -> Vec<String> {
+    let mut pages: Vec<String> = Vec::new();
+    let mut page = String::new();
+    let mut page_lines = 0;
+    for line in lines {
+        if page_lines >= PAGE_LINES || (page_lines > 0 && page.len() + line.len() + 1 > PAGE_CHARS) {
+            pages.push(page);
+            page = String::new();
+            page_lines = 0;
+        }
+        if page_lines > 0 {
+            page.push('\n');
+        }
+        page.push_str(line);
+        page_lines += 1;
+    }
+    if page_lines > 0 || pages.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+fn page_footer(current: usize, total: usize) -> String {
+    format!("Page {}/{}", current + 1, total)
+}
+
+// Plain content can't be edited into place nicely, so a plain-rendered command gets every page at once instead,
+// as does a channel where the bot can't embed links.
+fn send_paginated(data: &mut ShareMap, msg: &Message, config: &Config, command: &str, title: &str,
+                  pages: Vec<String>) {
+    let colour = config.colour;
+    if config.rendering(command) == Rendering::Plain {
+        send_info(msg, config, command, title, &pages.join("\n"));
+        return;
+    }
+    let sent = match send_message(msg.channel_id, |m| m
+        .embed(|e| e
+            .title(title)
+            .description(&pages[0])
+            .colour(colour)
+            .footer(|f| f.text(page_footer(0, pages.len())))
+        )
+    ) {
+        Result::Err(ref err) if is_embed_denied(msg.channel_id, err) => {
+            let description = pages.join("\n");
+            let card = Card::new(Option::Some(title), &description, colour);
+            log_send(msg, send_plain(msg.channel_id, &card, false));
+            return;
+        },
+        sent => match log_send(msg, sent) {
+            Option::Some(sent) => sent,
+            Option::None => return,
+        },
+    };
+    if pages.len() < 2 {
+        return;
+    }
+    for &reaction in &[PAGE_PREV, PAGE_NEXT] {
+        if let Result::Err(err) = sent.react(reaction) {
+            log_event!(Warn, "pagination_react_failed", format!("Could not add page reactions: {}", err),
+                       channel_id = msg.channel_id.0, error = err.to_string());
+            return;
+        }
+    }
+    let paginations = data.get_mut::<Paginations>().unwrap();
+    paginations.expire();
+    paginations.0.insert(sent.id, Pagination {
+        channel: msg.channel_id,
+        title: title.to_string(),
+        pages,
+        current: 0,
+        colour,
+        last_used: Instant::now(),
+    });
+}
+
+fn turn_page(ctx: &Context, reaction: &Reaction) {
+    let forward = match reaction.emoji {
+        ReactionType::Unicode(ref name) => match name.trim_right_matches('\u{FE0F}').chars().next() {
+            Option::Some(PAGE_PREV) => false,
+            Option::Some(PAGE_NEXT) => true,
+            _ => return,
+        },
+        _ => return,
+    };
+    let (title, page, footer, colour) = {
+        let mut data = ctx.data.lock();
+        let paginations = match data.get_mut::<Paginations>() {
+            Option::Some(paginations) => paginations,
+            Option::None => return,
+        };
+        paginations.expire();
+        let pagination = match paginations.0.get_mut(&reaction.message_id) {
+            Option::Some(pagination) => pagination,
+            Option::None => return,
+        };
+        let total = pagination.pages.len();
+        pagination.current = if forward {
+            (pagination.current + 1) % total
+        } else {
+            (pagination.current + total - 1) % total
+        };
+        pagination.last_used = Instant::now();
+        (pagination.title.clone(), pagination.pages[pagination.current].clone(),
+         page_footer(pagination.current, total), pagination.colour)
+    };
+    if let Result::Err(err) = reaction.channel_id.edit_message(reaction.message_id, |m| m
+        .embed(|e| e
+            .title(&title)
+            .description(&page)
+            .colour(colour)
+            .footer(|f| f.text(&footer))
+        )
+    ) {
+        log_event!(Error, "pagination_edit_failed", format!("Failed to turn page: {}", err),
+                   message_id = reaction.message_id.0, error = err.to_string());
+    }
+    if let Result::Err(err) = reaction.delete() {
+        log_event!(Debug, "pagination_unreact_failed", format!("Could not remove page reaction: {}", err),
+                   message_id = reaction.message_id.0);
+    }
+}
+
+fn breaker_status(breaker: &CircuitBreaker) -> String {
+    match breaker.state() {
+        BreakerState::Closed if breaker.failures() == 0 => "closed".to_string(),
+        BreakerState::Closed => format!("closed ({} failures in a row)", breaker.failures()),
+        BreakerState::Open(remaining) => format!("open after {} failures; next try in {}s", breaker.failures(),
+                                                 remaining.as_secs()),
+        BreakerState::Trial => "trial fetch running".to_string(),
+    }
+}
+
+fn do_stats(data: &mut ShareMap, config: &Config, msg: &Message) {
+    let lines = {
+        let cache = data.get::<QuoteCacheKey>().unwrap();
+        let metrics = data.get::<Metrics>().unwrap();
+        let years = cache.cache.as_ref().map_or(0, |quotes| quotes.len());
+        vec![
+            format!("**Quotes:** {}", config.count(cache.cache_size)),
+            format!("**Skipped entries:** {}", config.count(cache.skipped)),
+            format!("**Years:** {}", years),
+            format!("**Command latency:** {}", metrics.commands.summary()),
+            format!("**Refresh latency:** {}", cache.refreshes.summary()),
+            format!("**Circuit breaker:** {}", breaker_status(&cache.breaker)),
+            format!("**Rate limits:** {}", rate_limit_summary()),
+            format!("**Searches:** {}", search_summary(config)),
+            format!("**Refresh restarts:** {}", REFRESH_RESTARTS.load(Ordering::Relaxed)),
+            format!("**Source weights:** {}", weight_summary(&config.source_weights())),
+            format!("**Freshness:** {}", match (cache.age(), staleness(config, cache)) {
+                (Option::None, _) => "never refreshed".to_string(),
+                (Option::Some(age), Staleness::Fresh) => format!("refreshed {}s ago", age.as_secs()),
+                (Option::Some(age), Staleness::Stale) => format!("refreshed {}s ago (stale)", age.as_secs()),
+                (Option::Some(age), Staleness::Refused) =>
+                    format!("refreshed {}s ago (too stale to serve)", age.as_secs()),
+            }),
+        ]
+    };
+    send_paginated(data, msg, config, "stats", "Stats", paginate_lines(&lines));
+}
+
+// A source URL safe to show in a channel: credentials are dropped and query values blanked, since
+// either may carry an API key.
+fn redact_url(url: &reqwest::Url) -> String {
+    let mut redacted = url.clone();
+    let _ = redacted.set_username("");
+    let _ = redacted.set_password(Option::None);
+    let keys: Vec<String> = url.query_pairs().map(|(key, _)| key.into_owned()).collect();
+    if keys.is_empty() {
+        redacted.set_query(Option::None);
+    } else {
+        redacted.query_pairs_mut().clear().extend_pairs(keys.iter().map(|key| (key.as_str(), "…")));
+    }
+    redacted.to_string()
+}
+
+fn source_lines(sources: &[Source]) -> Vec<String> {
+    sources.iter().map(|source| {
+        let fallback = source.fallback.as_ref()
+            .map_or(String::new(), |url| format!(" (fallback {})", redact_url(url)));
+        match source.label {
+            Option::Some(ref label) => format!("**Source {}:** {}{}", label, redact_url(&source.url), fallback),
+            Option::None => format!("**Source:** {}{}", redact_url(&source.url), fallback),
+        }
+    }).collect()
+}
+
+// The in-Discord equivalent of tailing the logs: how the last fetch went and what the cache is working with.
+fn do_diag(data: &mut ShareMap, config: &Config, msg: &Message) {
+    if !config.is_admin(msg.author.id) {
+        send_text(msg, "Only bot admins can see diagnostics.");
+        return;
+    }
+    let lines = {
+        let cache = data.get::<QuoteCacheKey>().unwrap();
+        let ago = |time: SystemTime| SystemTime::now().duration_since(time).map_or(0, |since| since.as_secs());
+        let mut lines = vec![
+            format!("**Startup:** {}", data.get::<Startup>().unwrap().status(config.startup_grace)),
+            format!("**Last fetch:** {}", match (cache.last_attempt(), cache.last_error.as_ref()) {
+                (Option::None, _) => "not attempted yet".to_string(),
+                (Option::Some(time), Option::None) => format!("succeeded {}s ago", ago(time)),
+                (Option::Some(time), Option::Some(err)) => format!("failed {}s ago: {}", ago(time), err),
+            }),
+            format!("**Last successful refresh:** {}",
+                    cache.age().map_or("never".to_string(), |age| format!("{}s ago", age.as_secs()))),
+            format!("**Quotes:** {}", config.count(cache.cache_size)),
+            format!("**Circuit breaker:** {} (opens after {} failures, {}ms cool-down)",
+                    breaker_status(&cache.breaker), cache.breaker.threshold, as_millis(cache.breaker.cooldown)),
+            format!("**Refresh delay:** {}ms (±{}% jitter)", as_millis(cache.delay), cache.jitter),
+            "**Request timeout:** the HTTP client's default".to_string(),
+            format!("**Request:** {:?}", cache.request),
+            format!("**Format:** {:?}", cache.format),
+        ];
+        lines.extend(source_lines(&cache.sources));
+        if let Option::Some(view) = msg.guild_id().and_then(|guild| data.get::<BlockedViews>().unwrap().0.get(&guild)) {
+            lines.push(format!("**Blocklist:** {} quotes hidden here by {} words", config.count(view.removed),
+                               view.words.len()));
+        }
+        lines
+    };
+    send_paginated(data, msg, config, "diag", "Diagnostics", paginate_lines(&lines));
+}
+
+fn list_or_none<T: fmt::Display>(items: &[T]) -> String {
+    if items.is_empty() {
+        "none".to_string()
+    } else {
+        items.iter().map(|item| item.to_string()).collect::<Vec<String>>().join(", ")
+    }
+}
+
+// The settings this guild is actually running with, after env vars, reloads and per-guild overrides. The token
+// and header values are never shown, and source URLs go through `redact_url`.
+fn do_config(data: &mut ShareMap, config: &Config, msg: &Message) {
+    if !config.is_admin(msg.author.id) {
+        send_text(msg, "Only bot admins can see the configuration.");
+        return;
+    }
+    let lines = {
+        let settings = data.get::<GuildSettings>().unwrap();
+        let mut disabled: Vec<String> = settings.disabled_in(msg.guild_id()).into_iter().collect();
+        disabled.sort();
+        let flags: Vec<&str> = [
+            ("dedup", config.dedup), ("silent", config.silent), ("handle_edits", config.handle_edits),
+            ("welcome_dm", config.welcome_dm), ("footer_index", config.footer_index),
+            ("wrap_adjacent", config.wrap_adjacent), ("strict_keywords", config.strict_keywords),
+            ("background_refresh", config.background_refresh), ("leave_unapproved", config.leave_unapproved),
+            ("blocklist_lookups", config.blocklist_lookups), ("confirm_admin", config.confirm_admin),
+            ("allow_bots", config.allow_bots), ("dm_help", config.dm_help),
+        ].iter().filter(|&&(_, on)| on).map(|&(name, _)| name).collect();
+        let mut plain: Vec<&str> = config.command_rendering.iter()
+            .filter(|&(_, &rendering)| rendering == Rendering::Plain)
+            .map(|(command, _)| command.as_str())
+            .collect();
+        plain.sort();
+        let mut lines = vec![
+            format!("**Prefix:** `{}`", config.prefix),
+            format!("**Token:** {}", if config.token.is_empty() { "not set" } else { "set (hidden)" }),
+            format!("**Request:** {:?} · {:?} format", config.request, config.format),
+            format!("**Refresh delay:** {}ms (±{}% jitter, {} in flight at most)", as_millis(config.delay),
+                    config.jitter, config.max_fetches),
+            format!("**Circuit breaker:** opens after {} failures for {}ms", config.breaker_threshold,
+                    as_millis(config.breaker_cooldown)),
+            format!("**Startup grace:** {}ms", as_millis(config.startup_grace)),
+            "**Request timeout:** the HTTP client's default".to_string(),
+            format!("**Colour:** #{:06X}", config.colour),
+            format!("**Rendering:** {:?} (plain for: {}) · **Quote style:** {:?}", config.rendering,
+                    list_or_none(&plain), config.quote_style),
+            format!("**Empty query:** {:?} · **Bare mention:** {:?}", config.empty_query, config.empty_mention),
+            format!("**Search order:** {}",
+                    config.search_order.iter().map(|layer| layer.name()).collect::<Vec<&str>>().join(", ")),
+            format!("**Fuzzy distance:** {:?} · **Quote order:** {:?}", config.fuzzy_distance, config.quote_order),
+            format!("**Random seed:** {}", config.random_seed.map_or("none".to_string(), |seed| seed.to_string())),
+            format!("**Batch limit:** {} · **List size:** {} · **Recent count:** {} · **Response limit:** {} \
+                     characters", config.max_batch, config.list_size, config.recent_count, config.max_response_chars),
+            format!("**Thousands separator:** {}",
+                    if config.thousands_separator.is_empty() { "none".to_string() }
+                    else { format!("`{}`", config.thousands_separator) }),
+            format!("**Cooldown:** {}ms", as_millis(config.cooldown)),
+            format!("**Digest:** {}",
+                    config.digest.iter().map(DigestSection::describe).collect::<Vec<String>>().join(", ")),
+            format!("**Aliases:** {}", list_or_none(&config.aliases.iter()
+                .map(|&(ref alias, ref target)| format!("{} → {}", alias, target))
+                .collect::<Vec<String>>())),
+            format!("**UTC offset:** {}{:02}:{:02}", if config.utc_offset < 0 { "-" } else { "+" },
+                    config.utc_offset.abs() / 60, config.utc_offset.abs() % 60),
+            format!("**Stale after:** {} · **Refused after:** {}",
+                    config.max_stale.map_or("never".to_string(), |limit| format!("{}s", limit.as_secs())),
+                    config.refuse_stale.map_or("never".to_string(), |limit| format!("{}s", limit.as_secs()))),
+            format!("**Admins:** {} · **Mod roles:** {} · **Allowed guilds:** {}", config.admins.len(),
+                    config.mod_roles.len(), if config.allowed_guilds.is_empty() {
+                        "all".to_string()
+                    } else {
+                        config.allowed_guilds.len().to_string()
+                    }),
+            format!("**QOTD channels:** {}", config.qotd.len()),
+            format!("**Logging:** {:?} at {:?}", config.log_format, config.log_level),
+            format!("**Flags on:** {}", list_or_none(&flags)),
+            format!("**Disabled here:** {}", list_or_none(&disabled)),
+            format!("**Maintenance:** {}", if settings.maintenance { "on" } else { "off" }),
+        ];
+        lines.extend(source_lines(&config.sources));
+        lines
+    };
+    send_paginated(data, msg, config, "config", "Configuration", paginate_lines(&lines));
+}
+
+fn weight_summary(weights: &[(String, u32)]) -> String {
+    let total: u64 = weights.iter().map(|&(_, weight)| weight as u64).sum();
+    if total == 0 {
+        return "uniform".to_string();
+    }
+    weights.iter()
+        .map(|&(ref label, weight)| format!("{} {} ({}%)", label, weight, weight as u64 * 100 / total))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Quote counts per year in numeric order, or per decade if there are too many years for one bar each.
+fn histogram_rows(quotes: &OrderMap<String, QuoteYear>) -> (Vec<(String, usize)>, bool) {
+    let mut years: Vec<(u32, usize)> = quotes.iter()
+        .filter_map(|(year_key, year)| year_key.parse::<u32>().ok()
+            .map(|value| (value, year.months.values().map(|month| month.quotes.len()).sum())))
+        .collect();
+    years.sort();
+    if years.len() <= HISTOGRAM_BARS {
+        return (years.into_iter().map(|(year, count)| (year.to_string(), count)).collect(), false);
+    }
+    let mut decades: Vec<(String, usize)> = Vec::new();
+    for (year, count) in years {
+        let label = format!("{}s", year / 10 * 10);
+        if decades.last().map_or(false, |&(ref last, _)| *last == label) {
+            decades.last_mut().unwrap().1 += count;
+        } else {
+            decades.push((label, count));
+        }
+    }
+    // Decades can still overflow on a very long archive; keep the most recent ones.
+    let skip = decades.len().saturating_sub(HISTOGRAM_BARS);
+    (decades.into_iter().skip(skip).collect(), true)
+}
+
+fn do_histogram(cache: &mut QuoteCache, config: &Config, msg: &Message) {
+    let (rows, by_decade) = match cache.get_quotes() {
+        Result::Ok(quotes) => histogram_rows(quotes),
+        Result::Err(_) => {
+            send_text(msg, NOT_READY_TEXT);
+            return;
+        },
+    };
+    let max = rows.iter().map(|&(_, count)| count).max().unwrap_or(0);
+    if max == 0 {
+        send_text(msg, "There are no dated quotes to chart.");
+        return;
+    }
+    let label_width = rows.iter().map(|&(ref label, _)| label.len()).max().unwrap_or(0);
+    let chart = rows.iter()
+        .map(|&(ref label, count)| {
+            // Any nonzero count gets at least one block so small years don't vanish.
+            let width = (count * HISTOGRAM_WIDTH + max - 1) / max;
+            format!("{:>label$} │{} {}", label, "█".repeat(width), count, label = label_width)
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let title = if by_decade { "Quotes per decade" } else { "Quotes per year" };
+    send_info(msg, config, "histogram", title, &format!("```\n{}\n```", chart));
+}
+
+fn do_tags(data: &mut ShareMap, config: &Config, msg: &Message) {
+    let lines = {
+        let cache = data.get_mut::<QuoteCacheKey>().unwrap();
+        if cache.get_quotes().is_err() {
+            send_text(msg, NOT_READY_TEXT);
+            return;
+        }
+        cache.tags.counts().iter()
+            .map(|&(tag, count)| format!("**#{}** — {} quotes", tag, config.count(count)))
+            .collect::<Vec<String>>()
+    };
+    if lines.is_empty() {
+        send_text(msg, "This corpus has no tags.");
+        return;
+    }
+    send_paginated(data, msg, config, "tags", "Tags", paginate_lines(&lines));
+}
+
+// One line per quote in a month, with the quote that was asked about marked so it's easy to find on its page.
+fn month_lines(anchor: &Quote, quotes: &[Quote]) -> Vec<String> {
+    quotes.iter()
+        .map(|quote| format!("{}{}", if quote.index == anchor.index { "▶ " } else { "" }, preview_line(quote)))
+        .collect()
+}
+
+// A quote's id and the start of its text on one line.
+fn preview_line(quote: &Quote) -> String {
+    format!("`{}` {}", quote.id(),
+            truncate(&quote.text.split_whitespace().collect::<Vec<&str>>().join(" "), MONTH_PREVIEW_CHARS))
+}
+
+fn month_name(month_key: &str) -> &str {
+    match month_key.parse::<usize>() {
+        Result::Ok(month) if month >= 1 && month <= 12 => MONTHS[month - 1],
+        _ => month_key,
+    }
+}
+
+fn do_years(data: &mut ShareMap, config: &Config, msg: &Message) {
+    let lines = match data.get_mut::<QuoteCacheKey>().unwrap().get_quotes() {
+        Result::Ok(quotes) => quotes.iter()
+            .map(|(year_key, year)| format!("**{}** — {} quotes ({})", year_key,
+                config.count(year.months.values().map(|month| month.quotes.len()).sum::<usize>()),
+                year.months.keys().map(|month_key| month_name(month_key)).collect::<Vec<&str>>().join(", ")))
+            .collect::<Vec<String>>(),
+        Result::Err(_) => {
+            send_text(msg, NOT_READY_TEXT);
+            return;
+        },
+    };
+    send_paginated(data, msg, config, "years", "Years", paginate_lines(&lines));
+}
+
+fn do_reload(data: &mut ShareMap, msg: &Message) {
+    let old = data.get::<Config>().unwrap().clone();
+    if !old.is_admin(msg.author.id) {
+        send_text(msg, "Only bot admins can reload the configuration.");
+        return;
+    }
+    let mut config = match Config::load(true) {
+        Result::Ok(config) => config,
+        Result::Err(err) => {
+            log_event!(Error, "reload_failed", format!("Reload failed: {}", err), error = err.to_string());
+            send_text(msg, &format!("Reload failed: {}", err));
+            return;
+        },
+    };
+    let mut applied: Vec<&str> = Vec::new();
+    let mut restart: Vec<&str> = Vec::new();
+    if config.token != old.token {
+        config.token = old.token.clone();
+        restart.push("token");
+    }
+    if config.prefix != old.prefix {
+        let user = serenity::CACHE.read().user.id;
+        data.insert::<Prefix>(Prefix::build(&config.prefix, user));
+        applied.push("prefix");
+    }
+    if config.colour != old.colour {
+        applied.push("colour");
+    }
+    if config.help_text != old.help_text {
+        applied.push("help text");
+    }
+    if config.admins != old.admins {
+        applied.push("admins");
+    }
+    if config.random_seed != old.random_seed {
+        restart.push("random seed");
+    }
+    // The scheduler reads schedules live, but it only starts if there were any at startup.
+    if config.qotd != old.qotd {
+        if QOTD_STARTED.load(Ordering::SeqCst) {
+            applied.push("QOTD schedules");
+        } else {
+            restart.push("QOTD schedules");
+        }
+    }
+    if config.sources != old.sources || config.request != old.request || config.format != old.format
+        || config.dedup != old.dedup
+        || config.delay != old.delay || config.jitter != old.jitter {
+        let cache = data.get_mut::<QuoteCacheKey>().unwrap();
+        cache.sources = config.sources.clone();
+        cache.request = config.request.clone();
+        cache.format = config.format;
+        cache.dedup = config.dedup;
+        cache.delay = config.delay;
+        cache.jitter = config.jitter;
+        applied.push("source");
+    }
+    if config.breaker_threshold != old.breaker_threshold || config.breaker_cooldown != old.breaker_cooldown {
+        let breaker = &mut data.get_mut::<QuoteCacheKey>().unwrap().breaker;
+        breaker.threshold = config.breaker_threshold;
+        breaker.cooldown = config.breaker_cooldown;
+        applied.push("circuit breaker");
+    }
+    if config.stopwords != old.stopwords {
+        let cache = data.get_mut::<QuoteCacheKey>().unwrap();
+        cache.stopwords = config.stopwords.clone();
+        if let Option::Some(ref quotes) = cache.cache {
+            cache.vocabulary = Vocabulary::build(quotes, &cache.stopwords);
+        }
+        applied.push("stopwords");
+    }
+    logging::configure(config.log_format, config.log_level);
+    data.insert::<Config>(Arc::new(config));
+    log_event!(Info, "reload", format!("Configuration reloaded by {}: applied [{}], needs restart [{}]",
+                                       msg.author.id, applied.join(", "), restart.join(", ")),
+               user_id = msg.author.id.0);
+    let mut reply = if applied.is_empty() {
+        "Configuration reloaded; nothing changed.".to_string()
+    } else {
+        format!("Configuration reloaded; updated {}.", applied.join(", "))
+    };
+    if !restart.is_empty() {
+        reply.push_str(&format!(" Changes to {} require a restart.", restart.join(", ")));
+    }
+    send_text(msg, &reply);
+}
+
+struct HandledEdits {
+    order: VecDeque<MessageId>,
+    contents: HashMap<MessageId, String>,
+}
+
+impl typemap::Key for HandledEdits {
+    type Value = HandledEdits;
+}
+
+impl HandledEdits {
+    fn record(&mut self, id: MessageId, content: &str) -> bool {
+        if self.contents.get(&id).map_or(false, |previous| previous == content) {
+            return false;
+        }
+        if self.contents.insert(id, content.to_string()).is_none() {
+            self.order.push_back(id);
+            if self.order.len() > MAX_TRACKED_EDITS {
+                if let Option::Some(oldest) = self.order.pop_front() {
+                    self.contents.remove(&oldest);
+                }
+            }
+        }
+        true
+    }
+}
+
+// Message ids handled recently, so a create event redelivered around a reconnect isn't answered twice.
+struct SeenMessages {
+    order: VecDeque<(MessageId, Instant)>,
+    ids: HashSet<MessageId>,
+}
+
+impl typemap::Key for SeenMessages {
+    type Value = SeenMessages;
+}
+
+impl SeenMessages {
+    // Returns false if `id` was already seen within the window. Only a new id makes room for itself, so a
+    // redelivery can't evict its own first delivery.
+    fn record(&mut self, id: MessageId) -> bool {
+        let window = Duration::from_secs(SEEN_MESSAGE_WINDOW);
+        while self.order.front().map_or(false, |&(_, seen)| seen.elapsed() > window) {
+            self.evict_oldest();
+        }
+        if self.ids.contains(&id) {
+            return false;
+        }
+        while self.order.len() >= MAX_TRACKED_MESSAGES {
+            self.evict_oldest();
+        }
+        self.ids.insert(id);
+        self.order.push_back((id, Instant::now()));
+        true
+    }
+    fn evict_oldest(&mut self) {
+        if let Option::Some((oldest, _)) = self.order.pop_front() {
+            self.ids.remove(&oldest);
+        }
+    }
+}
+
+struct GuildSettings {
+    path: String,
+    cooldowns: HashMap<GuildId, u64>,
+    disabled: HashMap<GuildId, HashSet<String>>,
+    // The UTC day number each QOTD channel last got its quote, so a restart doesn't post twice.
+    qotd_posted: HashMap<ChannelId, u64>,
+    // Bot-wide rather than per guild, but persisted alongside everything else.
+    maintenance: bool,
+    // Users who have already been sent the welcome DM.
+    welcomed: HashSet<UserId>,
+    // Embed colours chosen with `setcolour`, used instead of `BOT_COLOUR` in that guild.
+    colours: HashMap<GuildId, u32>,
+    // Default categories chosen with `setcategory`: a plain `says` there only draws quotes with that tag.
+    categories: HashMap<ChannelId, String>,
+    // Words added with `block`; quotes containing any of them are left out of `says` in that guild.
+    blocked: HashMap<GuildId, HashSet<String>>,
+    // Seeds chosen with `setseed`, so random picks in that guild follow one shared sequence.
+    seeds: HashMap<GuildId, u64>,
+    // Set by `mark_dirty`; changes reach the disk on the next `flush`.
+    dirty: bool,
+}
+
+impl typemap::Key for GuildSettings {
+    type Value = GuildSettings;
+}
+
+impl GuildSettings {
+    fn load(path: &str) -> GuildSettings {
+        let mut settings = GuildSettings {
+            path: path.to_string(),
+            cooldowns: HashMap::new(),
+            disabled: HashMap::new(),
+            qotd_posted: HashMap::new(),
+            maintenance: false,
+            welcomed: HashSet::new(),
+            colours: HashMap::new(),
+            categories: HashMap::new(),
+            blocked: HashMap::new(),
+            seeds: HashMap::new(),
+            dirty: false,
+        };
+        let state = match File::open(path) {
+            Result::Ok(file) => match serde_json::from_reader::<_, Json>(BufReader::new(file)) {
+                Result::Ok(state) => state,
+                Result::Err(err) => {
+                    log_event!(Warn, "state_unreadable", format!("Ignoring unreadable state file {}: {}", path, err),
+                               path = path, error = err.to_string());
+                    return settings;
+                },
+            },
+            Result::Err(_) => return settings,
+        };
+        if let Option::Some(&Json::Object(ref cooldowns)) = state.get("cooldowns") {
+            for (guild, seconds) in cooldowns {
+                if let (Result::Ok(guild), Option::Some(seconds)) = (guild.parse::<u64>(), seconds.as_u64()) {
+                    settings.cooldowns.insert(GuildId(guild), seconds);
+                }
+            }
+        }
+        if let Option::Some(&Json::Object(ref disabled)) = state.get("disabled") {
+            for (guild, commands) in disabled {
+                if let (Result::Ok(guild), &Json::Array(ref commands)) = (guild.parse::<u64>(), commands) {
+                    settings.disabled.insert(GuildId(guild), commands.iter()
+                        .filter_map(|command| command.as_str())
+                        .filter(|command| TOGGLEABLE.contains(command))
+                        .map(|command| command.to_string())
+                        .collect());
+                }
+            }
+        }
+        settings.maintenance = state.get("maintenance").and_then(Json::as_bool).unwrap_or(false);
+        if let Option::Some(&Json::Array(ref welcomed)) = state.get("welcomed") {
+            settings.welcomed.extend(welcomed.iter().filter_map(Json::as_u64).map(UserId));
+        }
+        if let Option::Some(&Json::Object(ref colours)) = state.get("colours") {
+            for (guild, colour) in colours {
+                if let (Result::Ok(guild), Option::Some(colour)) = (guild.parse::<u64>(), colour.as_u64()) {
+                    settings.colours.insert(GuildId(guild), colour as u32 & 0xFF_FF_FF);
+                }
+            }
+        }
+        if let Option::Some(&Json::Object(ref categories)) = state.get("categories") {
+            for (channel, category) in categories {
+                if let (Result::Ok(channel), Option::Some(category)) = (channel.parse::<u64>(), category.as_str()) {
+                    settings.categories.insert(ChannelId(channel), category.to_string());
+                }
+            }
+        }
+        if let Option::Some(&Json::Object(ref blocked)) = state.get("blocked") {
+            for (guild, words) in blocked {
+                if let (Result::Ok(guild), &Json::Array(ref words)) = (guild.parse::<u64>(), words) {
+                    settings.blocked.insert(GuildId(guild), words.iter()
+                        .filter_map(|word| word.as_str())
+                        .map(|word| word.to_string())
+                        .collect());
+                }
+            }
+        }
+        if let Option::Some(&Json::Object(ref seeds)) = state.get("seeds") {
+            for (guild, seed) in seeds {
+                if let (Result::Ok(guild), Option::Some(seed)) = (guild.parse::<u64>(), seed.as_u64()) {
+                    settings.seeds.insert(GuildId(guild), seed);
+                }
+            }
+        }
+        if let Option::Some(&Json::Object(ref posted)) = state.get("qotd") {
+            for (channel, day) in posted {
+                if let (Result::Ok(channel), Option::Some(day)) = (channel.parse::<u64>(), day.as_u64()) {
+                    settings.qotd_posted.insert(ChannelId(channel), day);
+                }
+            }
+        }
+        settings
+    }
+    fn to_json(&self) -> Json {
+        let mut cooldowns = serde_json::Map::new();
+        for (guild, &seconds) in &self.cooldowns {
+            cooldowns.insert(guild.0.to_string(), Json::from(seconds));
+        }
+        let mut disabled = serde_json::Map::new();
+        for (guild, commands) in &self.disabled {
+            if !commands.is_empty() {
+                let mut commands: Vec<String> = commands.iter().cloned().collect();
+                commands.sort();
+                disabled.insert(guild.0.to_string(), Json::from(commands));
+            }
+        }
+        let mut colours = serde_json::Map::new();
+        for (guild, &colour) in &self.colours {
+            colours.insert(guild.0.to_string(), Json::from(colour));
+        }
+        let mut blocked = serde_json::Map::new();
+        for (guild, words) in &self.blocked {
+            if !words.is_empty() {
+                let mut words: Vec<String> = words.iter().cloned().collect();
+                words.sort();
+                blocked.insert(guild.0.to_string(), Json::from(words));
+            }
+        }
+        let mut categories = serde_json::Map::new();
+        for (channel, category) in &self.categories {
+            categories.insert(channel.0.to_string(), Json::from(category.as_str()));
+        }
+        let mut seeds = serde_json::Map::new();
+        for (guild, &seed) in &self.seeds {
+            seeds.insert(guild.0.to_string(), Json::from(seed));
+        }
+        let mut qotd = serde_json::Map::new();
+        for (channel, &day) in &self.qotd_posted {
+            qotd.insert(channel.0.to_string(), Json::from(day));
+        }
+        let mut state = serde_json::Map::new();
+        state.insert("cooldowns".to_string(), Json::Object(cooldowns));
+        state.insert("disabled".to_string(), Json::Object(disabled));
+        state.insert("colours".to_string(), Json::Object(colours));
+        state.insert("categories".to_string(), Json::Object(categories));
+        state.insert("blocked".to_string(), Json::Object(blocked));
+        state.insert("seeds".to_string(), Json::Object(seeds));
+        state.insert("qotd".to_string(), Json::Object(qotd));
+        state.insert("maintenance".to_string(), Json::from(self.maintenance));
+        let mut welcomed: Vec<u64> = self.welcomed.iter().map(|user| user.0).collect();
+        welcomed.sort();
+        state.insert("welcomed".to_string(), Json::from(welcomed));
+        Json::Object(state)
+    }
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+    // Writes the state if anything changed since the last flush. The new file is written beside the old
+    // one and renamed over it, so a crash mid-write leaves the previous state intact.
+    fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        let temp = format!("{}.tmp", self.path);
+        let written = File::create(&temp)
+            .and_then(|mut file| {
+                file.write_all(self.to_json().to_string().as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&temp, &self.path));
+        match written {
+            Result::Ok(()) => self.dirty = false,
+            Result::Err(err) => log_event!(Error, "state_save_failed",
+                                           format!("Failed to save state to {}: {}", self.path, err),
+                                           path = self.path.as_str(), error = err.to_string()),
+        }
+    }
+    // Turns `command` on or off in `guild`, or says why it can't be toggled.
+    fn toggle(&mut self, guild: GuildId, command: &str, enable: bool) -> Result<(), String> {
+        if !TOGGLEABLE.contains(&command) {
+            return Result::Err(format!("`{}` can't be toggled. Try one of {}", command, TOGGLEABLE.join(", ")));
+        }
+        {
+            let disabled = self.disabled.entry(guild).or_insert_with(HashSet::new);
+            if enable {
+                disabled.remove(command);
+            } else {
+                disabled.insert(command.to_string());
+            }
+        }
+        self.mark_dirty();
+        Result::Ok(())
+    }
+    fn disabled_in(&self, guild: Option<GuildId>) -> HashSet<String> {
+        guild.and_then(|guild| self.disabled.get(&guild)).cloned().unwrap_or_else(HashSet::new)
+    }
+    fn cooldown(&self, config: &Config, guild: Option<GuildId>) -> Duration {
+        guild.and_then(|guild| self.cooldowns.get(&guild))
+            .map_or(config.cooldown, |&seconds| Duration::from_secs(seconds))
+    }
+    // `config` with its embed colour swapped for the guild's own, if it has set one.
+    fn themed(&self, config: &Config, guild: Option<GuildId>) -> Config {
+        let mut config = config.clone();
+        if let Option::Some(&colour) = guild.and_then(|guild| self.colours.get(&guild)) {
+            config.colour = colour;
+        }
+        let (bot, guild_name) = {
+            let cache = serenity::CACHE.read();
+            let guild_name = guild.and_then(|guild| cache.guild(guild)).map(|guild| guild.read().name.clone());
+            (cache.user.name.clone(), guild_name.unwrap_or_else(|| "direct messages".to_string()))
+        };
+        for template in vec![&mut config.help_text, &mut config.maintenance_text, &mut config.no_match,
+                             &mut config.footer_template] {
+            *template = interpolate_context(template, &bot, &guild_name);
+        }
+        if let Option::Some(ref mut title) = config.embed_title {
+            *title = interpolate_context(title, &bot, &guild_name);
+        }
+        config
+    }
+}
+
+struct Cooldowns(HashMap<UserId, Instant>);
+
+impl typemap::Key for Cooldowns {
+    type Value = Cooldowns;
+}
+
+// Bot admins, or members who can manage the server the message came from.
+fn is_guild_admin(config: &Config, msg: &Message) -> bool {
+    if config.is_admin(msg.author.id) {
+        return true;
+    }
+    match msg.guild_id().map(|guild| guild.member(msg.author.id)) {
+        Option::Some(Result::Ok(member)) =>
+            member.permissions().map_or(false, |permissions| permissions.manage_guild()),
+        _ => false,
+    }
+}
+
+// Parses `#RRGGBB` (the `#` is optional) into a colour.
+fn parse_colour(text: &str) -> Option<u32> {
+    let hex = text.trim().trim_left_matches('#');
+    if hex.len() != 6 {
+        return Option::None;
+    }
+    u32::from_str_radix(hex, 16).ok()
+}
+
+fn do_setcolour(data: &mut ShareMap, msg: &Message, args: &Option<String>) {
+    let config = data.get::<Config>().unwrap().clone();
+    if !is_guild_admin(&config, msg) {
+        send_text(msg, "Only server admins can change the embed colour.");
+        return;
+    }
+    let guild = match msg.guild_id() {
+        Option::Some(guild) => guild,
+        Option::None => {
+            send_text(msg, "Embed colours are set per server; use this in a server channel.");
+            return;
+        },
+    };
+    let settings = data.get_mut::<GuildSettings>().unwrap();
+    match args.as_ref().map(|args| args.trim()).filter(|args| !args.is_empty()) {
+        Option::Some(arg) if arg.eq_ignore_ascii_case("reset") => {
+            settings.colours.remove(&guild);
+            settings.mark_dirty();
+            send_embed(msg, "Embed colour", &format!("Reset to the default, #{:06X}.", config.colour), config.colour);
+        },
+        Option::Some(arg) => match parse_colour(arg) {
+            Option::Some(colour) => {
+                settings.colours.insert(guild, colour);
+                settings.mark_dirty();
+                log_event!(Info, "colour_set", format!("{} set the embed colour in {} to #{:06X}",
+                                                       msg.author.id, guild, colour),
+                           guild_id = guild.0, colour = colour);
+                send_embed(msg, "Embed colour", &format!("Set to #{:06X}.", colour), colour);
+            },
+            Option::None => {
+                send_error(msg, &format!("`{}` isn't a colour; use hex like `#1E90FF`.", arg));
+            },
+        },
+        Option::None => {
+            send_error(msg, "Usage: `setcolour #RRGGBB|reset`");
+        },
+    }
+}
+
+// Seeds `guild`'s random picks, or with `None` returns it to the bot-wide generator. Its generator is dropped
+// either way, so the next pick starts the seed's run from the beginning.
+fn apply_seed(data: &mut ShareMap, guild: GuildId, seed: Option<u64>) {
+    data.get_mut::<GuildRngs>().unwrap().0.remove(&guild);
+    let settings = data.get_mut::<GuildSettings>().unwrap();
+    match seed {
+        Option::Some(seed) => settings.seeds.insert(guild, seed),
+        Option::None => settings.seeds.remove(&guild),
+    };
+    settings.mark_dirty();
+}
+
+// Seeds this guild's random picks so everyone sees the same run of quotes, e.g. for an event. That only syncs
+// what people see; anyone who knows the seed can work out what comes next, so it's no good for anything that
+// has to be unpredictable. `guild` derives the seed from the guild id and `reset` goes back to the bot-wide
+// generator. Setting a seed, even the same one again, restarts its sequence.
+fn do_setseed(data: &mut ShareMap, msg: &Message, args: &Option<String>) {
+    let config = data.get::<Config>().unwrap().clone();
+    if !is_guild_admin(&config, msg) {
+        send_text(msg, "Only server admins can change how quotes are picked.");
+        return;
+    }
+    let guild = match msg.guild_id() {
+        Option::Some(guild) => guild,
+        Option::None => {
+            send_text(msg, "Seeds are set per server; use this in a server channel.");
+            return;
+        },
+    };
+    let arg = args.as_ref().map(|args| args.trim().to_lowercase());
+    let seed = match arg.as_ref().map(|arg| arg.as_str()).filter(|arg| !arg.is_empty()) {
+        Option::Some("reset") => Option::None,
+        Option::Some("guild") => Option::Some(guild.0),
+        Option::Some(arg) => match arg.parse::<u64>() {
+            Result::Ok(seed) => Option::Some(seed),
+            Result::Err(_) => {
+                send_error(msg, "Usage: `setseed <number>|guild|reset`");
+                return;
+            },
+        },
+        Option::None => {
+            let current = data.get::<GuildSettings>().unwrap().seeds.get(&guild).cloned();
+            send_text(msg, &match current {
+                Option::Some(seed) => format!("Random picks here follow seed {}.", seed),
+                Option::None => "Random picks here aren't seeded.".to_string(),
+            });
+            return;
+        },
+    };
+    apply_seed(data, guild, seed);
+    log_event!(Info, "seed_set", format!("{} set the seed in {} to {:?}", msg.author.id, guild, seed),
+               guild_id = guild.0, seed = seed.map_or(Json::Null, Json::from));
+    send_embed(msg, "Seed", &match seed {
+        Option::Some(seed) => format!("Random picks here now follow seed {}, the same for everyone.", seed),
+        Option::None => "Random picks here are independent again.".to_string(),
+    }, config.colour);
+}
+
+fn do_setcategory(data: &mut ShareMap, msg: &Message, args: &Option<String>) {
+    let config = data.get::<Config>().unwrap().clone();
+    if !is_guild_admin(&config, msg) {
+        send_text(msg, "Only server admins can change a channel's category.");
+        return;
+    }
+    let arg = args.as_ref().map(|args| args.trim().trim_left_matches('#').to_lowercase());
+    match arg.as_ref().map(|arg| arg.as_str()).filter(|arg| !arg.is_empty()) {
+        Option::Some("reset") => {
+            let settings = data.get_mut::<GuildSettings>().unwrap();
+            settings.categories.remove(&msg.channel_id);
+            settings.mark_dirty();
+            send_embed(msg, "Category", "This channel draws from every quote again.", config.colour);
+        },
+        Option::Some(category) => {
+            // Checked only once the tags are loaded, so a category can still be set while they're being fetched.
+            let known = {
+                let cache = data.get::<QuoteCacheKey>().unwrap();
+                cache.cache.is_none() || cache.tags.counts().iter().any(|&(tag, _)| tag == category)
+            };
+            if !known {
+                send_error(msg, &format!("There's no `#{}` tag; see `{}tags` for the ones there are.", category,
+                                         config.prefix));
+                return;
+            }
+            let settings = data.get_mut::<GuildSettings>().unwrap();
+            settings.categories.insert(msg.channel_id, category.to_string());
+            settings.mark_dirty();
+            log_event!(Info, "category_set", format!("{} set the category in {} to #{}",
+                                                     msg.author.id, msg.channel_id, category),
+                       channel_id = msg.channel_id.0, category = category);
+            send_embed(msg, "Category",
+                       &format!("Plain `says` here now draws from `#{}`. Use `#tag` or `?text` to look further.",
+                                category), config.colour);
+        },
+        Option::None => {
+            send_error(msg, "Usage: `setcategory <tag>|reset`");
+        },
+    }
+}
+
+// A guild's quotes with its blocked words filtered out, kept until the words or the cache change.
+struct BlockedView {
+    generation: u64,
+    words: HashSet<String>,
+    quotes: OrderMap<String, QuoteYear>,
+    tags: TagIndex,
+    index: QuoteIndex,
+    removed: usize,
+}
+
+struct BlockedViews(HashMap<GuildId, BlockedView>);
+
+impl typemap::Key for BlockedViews {
+    type Value = BlockedViews;
+}
+
+// Rebuilds `guild`'s view if its blocklist or the cache moved on since it was built, and drops it once the
+// blocklist is empty.
+fn refresh_blocked_view(data: &mut ShareMap, guild: Option<GuildId>) {
+    let guild = match guild {
+        Option::Some(guild) => guild,
+        Option::None => return,
+    };
+    let words = data.get::<GuildSettings>().unwrap().blocked.get(&guild).cloned().unwrap_or_else(HashSet::new);
+    let view = {
+        let cache = data.get::<QuoteCacheKey>().unwrap();
+        let current = data.get::<BlockedViews>().unwrap().0.get(&guild)
+            .map_or(false, |view| view.generation == cache.generation && view.words == words);
+        if current {
+            return;
+        }
+        match cache.cache {
+            Option::Some(ref quotes) if !words.is_empty() => {
+                let (quotes, removed) = without_blocked(quotes, &words);
+                let tags = TagIndex::build(&quotes);
+                let index = QuoteIndex::build(&quotes);
+                Option::Some(BlockedView { generation: cache.generation, words, quotes, tags, index, removed })
+            },
+            _ => Option::None,
+        }
+    };
+    let views = data.get_mut::<BlockedViews>().unwrap();
+    match view {
+        Option::Some(view) => views.0.insert(guild, view),
+        Option::None => views.0.remove(&guild),
+    };
+}
+
+fn do_block(data: &mut ShareMap, msg: &Message, args: &Option<String>, block: bool) {
+    let config = data.get::<Config>().unwrap().clone();
+    if !is_guild_admin(&config, msg) {
+        send_text(msg, "Only server admins can change the blocklist.");
+        return;
+    }
+    let guild = match msg.guild_id() {
+        Option::Some(guild) => guild,
+        Option::None => {
+            send_text(msg, "Blocklists are kept per server; use this in a server channel.");
+            return;
+        },
+    };
+    let settings = data.get_mut::<GuildSettings>().unwrap();
+    let words = tokenize(args.as_ref().map_or("", |args| args.as_str()));
+    let word = match (words.first(), words.len()) {
+        (Option::Some(word), 1) => word.clone(),
+        (Option::None, _) if block => {
+            let mut blocked: Vec<String> = settings.blocked.get(&guild).map_or_else(Vec::new, |words| words.iter()
+                .map(|word| format!("`{}`", word))
+                .collect());
+            blocked.sort();
+            send_text(msg, &format!("Blocked words here: {}.", list_or_none(&blocked)));
+            return;
+        },
+        _ => {
+            send_error(msg, if block { "Usage: `block [word]`, one word at a time" } else {
+                "Usage: `unblock <word>`"
+            });
+            return;
+        },
+    };
+    {
+        let blocked = settings.blocked.entry(guild).or_insert_with(HashSet::new);
+        if block {
+            blocked.insert(word.clone());
+        } else if !blocked.remove(&word) {
+            send_text(msg, &format!("`{}` isn't blocked here.", word));
+            return;
+        }
+    }
+    settings.mark_dirty();
+    log_event!(Info, "blocklist_changed", format!("{} {} `{}` in {}", msg.author.id,
+                                                  if block { "blocked" } else { "unblocked" }, word, guild),
+               guild_id = guild.0, word = word.as_str(), blocked = block);
+    send_text(msg, &if block {
+        format!("Quotes containing `{}` won't be posted here any more.", word)
+    } else {
+        format!("Quotes containing `{}` can be posted here again.", word)
+    });
+}
+
+fn is_moderator(config: &Config, msg: &Message) -> bool {
+    if config.is_admin(msg.author.id) {
+        return true;
+    }
+    let member = match msg.guild_id().map(|guild| guild.member(msg.author.id)) {
+        Option::Some(Result::Ok(member)) => member,
+        _ => return false,
+    };
+    member.roles.iter().any(|role| config.mod_roles.contains(role))
+        || member.permissions().map_or(false, |permissions| permissions.manage_messages())
+}
+
+// Why `says pin` can't be used here, if it can't: only moderators may pin, and only where the bot can manage
+// messages. Permissions the cache doesn't know yet are left for the pin itself to find out.
+fn pin_refusal(config: &Config, msg: &Message) -> Option<String> {
+    if msg.guild_id().is_none() {
+        return Option::Some("Quotes can only be pinned in a server channel.".to_string());
+    }
+    if !is_moderator(config, msg) {
+        return Option::Some("Only moderators can pin quotes.".to_string());
+    }
+    let can_manage = {
+        let cache = serenity::CACHE.read();
+        cache.guild_channel(msg.channel_id)
+            .and_then(|channel| channel.read().permissions_for(cache.user.id).ok())
+            .map_or(true, |permissions| permissions.manage_messages())
+    };
+    if !can_manage {
+        return Option::Some("I need the Manage Messages permission in this channel to pin quotes.".to_string());
+    }
+    Option::None
+}
+
+// Pins the quote `says pin` just posted. A pin of a message that exists and that the bot may manage is only
+// refused as a bad request once the channel is at Discord's pin limit.
+fn pin_quote(msg: &Message, sent: &Message) {
+    match sent.pin() {
+        Result::Ok(()) => log_event!(Info, "quote_pinned", format!("{} pinned message {} in {}", msg.author.id,
+                                                                   sent.id, msg.channel_id),
+                                     channel_id = msg.channel_id.0, user_id = msg.author.id.0),
+        Result::Err(ref err) if http_status(err) == Option::Some(400) => {
+            send_error(msg, &format!("This channel already has {} pinned messages, the most Discord allows; \
+                                      unpin one and try again.", MAX_PINS));
+        },
+        Result::Err(ref err) if is_permission_error(err) => {
+            send_error(msg, "I need the Manage Messages permission in this channel to pin quotes.");
+        },
+        Result::Err(err) => {
+            log_event!(Warn, "pin_failed", format!("Could not pin message {}: {}", sent.id, err),
+                       channel_id = msg.channel_id.0, error = err.to_string());
+            send_error(msg, "I posted the quote but couldn't pin it; try again in a moment.");
+        },
+    }
+}
+
+// Returns how much longer the author has to wait, recording this use if they don't.
+fn check_cooldown(data: &mut ShareMap, config: &Config, msg: &Message) -> Option<Duration> {
+    let cooldown = data.get::<GuildSettings>().unwrap().cooldown(config, msg.guild_id());
+    if cooldown == Duration::from_secs(0) || is_moderator(config, msg) {
+        return Option::None;
+    }
+    let cooldowns = &mut data.get_mut::<Cooldowns>().unwrap().0;
+    if let Option::Some(last_used) = cooldowns.get(&msg.author.id) {
+        let elapsed = last_used.elapsed();
+        if elapsed < cooldown {
+            return Option::Some(cooldown - elapsed);
+        }
+    }
+    if cooldowns.len() >= MAX_TRACKED_COOLDOWNS {
+        cooldowns.retain(|_, last_used| last_used.elapsed() < cooldown);
+    }
+    cooldowns.insert(msg.author.id, Instant::now());
+    Option::None
+}
+
+fn do_cooldown(data: &mut ShareMap, config: &Config, msg: &Message, args: &Option<String>) {
+    if !config.is_admin(msg.author.id) {
+        send_text(msg, "Only bot admins can change the cooldown.");
+        return;
+    }
+    let guild = match msg.guild_id() {
+        Option::Some(guild) => guild,
+        Option::None => {
+            send_text(msg, "Cooldowns are set per server; use this in a server channel.");
+            return;
+        },
+    };
+    let settings = data.get_mut::<GuildSettings>().unwrap();
+    match args.as_ref().map(|args| args.trim()).filter(|args| !args.is_empty()) {
+        Option::None => {
+            let current = settings.cooldown(config, Option::Some(guild));
+            send_text(msg, &format!("The cooldown here is {}s.", current.as_secs()));
+        },
+        Option::Some(arg) if arg.eq_ignore_ascii_case("reset") => {
+            settings.cooldowns.remove(&guild);
+            settings.mark_dirty();
+            send_text(msg, &format!("Cooldown reset to the default of {}s.", config.cooldown.as_secs()));
+        },
+        Option::Some(arg) => match arg.parse::<u64>() {
+            Result::Ok(seconds) => {
+                settings.cooldowns.insert(guild, seconds);
+                settings.mark_dirty();
+                log_event!(Info, "cooldown_set",
+                           format!("{} set the cooldown in {} to {}s", msg.author.id, guild, seconds),
+                           guild_id = guild.0, seconds = seconds);
+                send_text(msg, &format!("Cooldown set to {}s.", seconds));
+            },
+            Result::Err(_) => {
+                send_error(msg, "Usage: `cooldown [seconds|reset]`");
+            },
+        },
+    }
+}
+
+// Reads `<command> on|off` as the lowercased command and whether to turn it on.
+fn parse_toggle(args: &str) -> Option<(String, bool)> {
+    let mut parts = args.split_whitespace();
+    match (parts.next().map(|command| command.to_lowercase()), parts.next()) {
+        (Option::Some(command), Option::Some(state)) if state.eq_ignore_ascii_case("on") =>
+            Option::Some((command, true)),
+        (Option::Some(command), Option::Some(state)) if state.eq_ignore_ascii_case("off") =>
+            Option::Some((command, false)),
+        _ => Option::None,
+    }
+}
+
+fn do_toggle(data: &mut ShareMap, config: &Config, msg: &Message, args: &Option<String>) {
+    if !config.is_admin(msg.author.id) {
+        send_text(msg, "Only bot admins can toggle commands.");
+        return;
+    }
+    let guild = match msg.guild_id() {
+        Option::Some(guild) => guild,
+        Option::None => {
+            send_text(msg, "Commands are toggled per server; use this in a server channel.");
+            return;
+        },
+    };
+    let (command, enable) = match parse_toggle(args.as_ref().map_or("", |args| args.as_str())) {
+        Option::Some(toggle) => toggle,
+        Option::None => {
+            send_error(msg, &format!("Usage: `toggle <command> on|off`, where command is one of {}",
+                                     TOGGLEABLE.join(", ")));
+            return;
+        },
+    };
+    if let Result::Err(err) = data.get_mut::<GuildSettings>().unwrap().toggle(guild, &command, enable) {
+        send_error(msg, &err);
+        return;
+    }
+    log_event!(Info, "command_toggled", format!("{} turned {} {} in {}", msg.author.id, command,
+                                                if enable { "on" } else { "off" }, guild),
+               guild_id = guild.0, command = command.as_str(), enabled = enable);
+    send_text(msg, &format!("`{}` is now {} here.", command, if enable { "enabled" } else { "disabled" }));
+}
+
+fn do_maintenance(data: &mut ShareMap, config: &Config, msg: &Message, args: &Option<String>) {
+    if !config.is_admin(msg.author.id) {
+        send_text(msg, "Only bot admins can change maintenance mode.");
+        return;
+    }
+    let settings = data.get_mut::<GuildSettings>().unwrap();
+    let enable = match args.as_ref().map_or("", |args| args.trim()) {
+        "" => {
+            send_text(msg, &format!("Maintenance mode is {}.", if settings.maintenance { "on" } else { "off" }));
+            return;
+        },
+        state if state.eq_ignore_ascii_case("on") => true,
+        state if state.eq_ignore_ascii_case("off") => false,
+        _ => {
+            send_error(msg, "Usage: `maintenance on|off`");
+            return;
+        },
+    };
+    if settings.maintenance != enable {
+        settings.maintenance = enable;
+        settings.mark_dirty();
+        log_event!(Warn, if enable { "maintenance_entered" } else { "maintenance_exited" },
+                   format!("{} turned maintenance mode {}", msg.author.id, if enable { "on" } else { "off" }),
+                   user_id = msg.author.id.0, enabled = enable);
+    }
+    send_text(msg, &format!("Maintenance mode is now {}.", if enable { "on" } else { "off" }));
+}
+
+// An admin command held back until whoever ran it confirms.
+#[derive(Clone, Copy)]
+enum PendingAction {
+    Reload,
+    Maintenance(bool),
+}
+
+impl PendingAction {
+    fn describe(&self) -> &'static str {
+        match *self {
+            PendingAction::Reload => "reload the configuration",
+            PendingAction::Maintenance(true) => "turn maintenance mode on",
+            PendingAction::Maintenance(false) => "turn maintenance mode off",
+        }
+    }
+    fn run(&self, data: &mut ShareMap, config: &Config, msg: &Message) {
+        match *self {
+            PendingAction::Reload => do_reload(data, msg),
+            PendingAction::Maintenance(enable) =>
+                do_maintenance(data, config, msg, &Option::Some((if enable { "on" } else { "off" }).to_string())),
         }
-        return (years, quote_count);
     }
-    panic!("Parsing error!");
 }
 
-#[derive(Debug)]
-struct CacheError;
+// A posted prompt, with the command message it's for so the action can run as if just invoked.
+struct Confirmation {
+    action: PendingAction,
+    msg: Message,
+    created: Instant,
+}
 
-impl Error for CacheError {
-    fn description(&self) -> &str {
-        "Cache miss!"
-    }
+struct Confirmations(HashMap<MessageId, Confirmation>);
+
+impl typemap::Key for Confirmations {
+    type Value = Confirmations;
 }
 
-impl fmt::Display for CacheError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Cache miss!")
+// Posts a prompt to react with `CONFIRM_YES` or `CONFIRM_NO` before `action` runs. Only admins get one; anyone
+// else goes straight to the command's own refusal.
+fn request_confirmation(data: &mut ShareMap, config: &Config, msg: &Message, action: PendingAction) {
+    if !config.is_admin(msg.author.id) {
+        action.run(data, config, msg);
+        return;
+    }
+    expire_confirmations(data);
+    let prompt = match send_text(msg, &format!("React {} to {}, or {} to cancel. This expires in {}s.", CONFIRM_YES,
+                                               action.describe(), CONFIRM_NO, CONFIRM_TIMEOUT)) {
+        Option::Some(prompt) => prompt,
+        Option::None => return,
+    };
+    for &reaction in &[CONFIRM_YES, CONFIRM_NO] {
+        if let Result::Err(err) = prompt.react(reaction) {
+            log_event!(Warn, "confirmation_react_failed", format!("Could not add confirmation reactions: {}", err),
+                       channel_id = msg.channel_id.0, error = err.to_string());
+        }
     }
+    data.get_mut::<Confirmations>().unwrap().0.insert(prompt.id, Confirmation {
+        action,
+        msg: msg.clone(),
+        created: Instant::now(),
+    });
 }
 
-#[derive(Debug)]
-struct CacheRetrievalError(String);
-
-impl Error for CacheRetrievalError {
-    fn description(&self) -> &str {
-        &self.0
+// Rewrites a prompt to say how it ended and clears its reactions so it can't be answered again.
+fn close_prompt(channel: ChannelId, prompt: MessageId, outcome: &str) {
+    if let Result::Err(err) = serenity::http::delete_message_reactions(channel.0, prompt.0) {
+        log_event!(Debug, "confirmation_cleanup_failed", format!("Could not clear reactions on {}: {}", prompt, err),
+                   message_id = prompt.0);
+    }
+    if let Result::Err(err) = channel.edit_message(prompt, |m| m.content(outcome)) {
+        log_event!(Debug, "confirmation_cleanup_failed", format!("Could not update prompt {}: {}", prompt, err),
+                   message_id = prompt.0);
     }
 }
 
-impl fmt::Display for CacheRetrievalError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Cache retrieval failed: {}", &self.0)
+fn expire_confirmations(data: &mut ShareMap) {
+    let timeout = Duration::from_secs(CONFIRM_TIMEOUT);
+    let confirmations = data.get_mut::<Confirmations>().unwrap();
+    let expired: Vec<MessageId> = confirmations.0.iter()
+        .filter(|&(_, confirmation)| confirmation.created.elapsed() >= timeout)
+        .map(|(&id, _)| id)
+        .collect();
+    for id in expired {
+        if let Option::Some(confirmation) = confirmations.0.remove(&id) {
+            close_prompt(confirmation.msg.channel_id, id,
+                         &format!("Timed out; didn't {}.", confirmation.action.describe()));
+        }
     }
 }
 
-impl From<reqwest::Error> for CacheRetrievalError {
-    fn from(err: reqwest::Error) -> Self {
-        CacheRetrievalError(err.description().to_string())
+// Runs or cancels the pending action a reaction answers, if it came from the admin who asked for it.
+fn confirm_action(ctx: &Context, reaction: &Reaction) {
+    let confirmed = match reaction.emoji {
+        ReactionType::Unicode(ref name) => match name.trim_right_matches('\u{FE0F}').chars().next() {
+            Option::Some(CONFIRM_YES) => true,
+            Option::Some(CONFIRM_NO) => false,
+            _ => return,
+        },
+        _ => return,
+    };
+    let mut data = ctx.data.lock();
+    expire_confirmations(&mut data);
+    let confirmation = {
+        let confirmations = data.get_mut::<Confirmations>().unwrap();
+        match confirmations.0.get(&reaction.message_id) {
+            Option::Some(confirmation) if confirmation.msg.author.id == reaction.user_id => {},
+            _ => return,
+        }
+        confirmations.0.remove(&reaction.message_id).unwrap()
+    };
+    let action = confirmation.action;
+    close_prompt(reaction.channel_id, reaction.message_id,
+                 &if confirmed {
+                     format!("Confirmed: going ahead to {}.", action.describe())
+                 } else {
+                     format!("Cancelled; didn't {}.", action.describe())
+                 });
+    if confirmed {
+        let config = data.get::<Config>().unwrap().clone();
+        action.run(&mut data, &config, &confirmation.msg);
     }
 }
 
-impl From<serde_json::Error> for CacheRetrievalError {
-    fn from(err: serde_json::Error) -> Self {
-        CacheRetrievalError(err.description().to_string())
+// DMs the help to someone using the bot in a server for the first time. Anyone with DMs closed is skipped
+// quietly, and still counts as welcomed so they aren't retried on every command.
+fn welcome(data: &mut ShareMap, config: &Config, msg: &Message, disabled: &HashSet<String>) {
+    if !config.welcome_dm || msg.is_private() {
+        return;
+    }
+    {
+        let settings = data.get_mut::<GuildSettings>().unwrap();
+        if !settings.welcomed.insert(msg.author.id) {
+            return;
+        }
+        settings.mark_dirty();
+    }
+    let help = help_text(config, msg, disabled);
+    let sent = msg.author.create_dm_channel().and_then(|dm| send_message(dm.id, |m| m
+        .embed(|e| e
+            .title("Welcome to Arrayong")
+            .description(&help)
+            .colour(config.colour)
+        )
+    ));
+    if let Result::Err(err) = sent {
+        log_event!(Debug, "welcome_skipped", format!("Could not DM {}: {}", msg.author.id, err),
+                   user_id = msg.author.id.0);
     }
 }
 
-struct Prefix(Regex);
+// Other bots and webhooks are ignored unless `allow_bots` is set. The bot's own messages are always ignored,
+// whatever the flag, so that a reply which happens to look like a command can never trigger another reply.
+fn should_ignore(msg: &Message, own_id: UserId, allow_bots: bool) -> bool {
+    msg.author.id == own_id || (!allow_bots && (msg.author.bot || msg.webhook_id.is_some()))
+}
 
-impl typemap::Key for Prefix {
-    type Value = Prefix;
+// A message that arrived before `ready` inserted the quote cache and command prefix, e.g. during a reconnect.
+struct NotReady;
+
+// The command `content` invokes and its arguments, or `None` if it doesn't invoke one.
+fn read_invocation(data: &ShareMap, content: &str) -> Result<Option<(String, Option<String>)>, NotReady> {
+    if data.get::<QuoteCacheKey>().is_none() {
+        return Result::Err(NotReady);
+    }
+    let prefix = data.get::<Prefix>().ok_or(NotReady)?;
+    Result::Ok(prefix.regex.captures(content).map(|groups| (
+        groups.get(1).map_or("says".to_string(), |m| m.as_str().to_lowercase()),
+        groups.get(2).map(|m| m.as_str().to_string()),
+    )))
 }
 
-struct QuoteCache {
-    last_request_time: SystemTime,
-    cache: Option<OrderMap<String, QuoteYear>>,
-    cache_size: usize,
-    request_url: String,
-    delay: Duration,
+// The answer to a message that isn't a command the bot handles: the help text in a DM, unless `dm_help` is off,
+// and nothing anywhere else.
+fn unmatched_response(config: &Config, private: bool) -> Option<CommandResponse<'static>> {
+    if private && config.dm_help { Option::Some(CommandResponse::Help) } else { Option::None }
+}
+
+fn update_presence(ctx: &Context, config: &Config, count: usize) {
+    if !config.presence.is_empty() {
+        ctx.set_game(Game::playing(&config.presence.replace("{count}", &config.count(count))));
+    }
 }
 
-impl QuoteCache {
-    fn perform_request(&mut self, request_url: reqwest::Url) -> Result<Json, CacheRetrievalError> {
-        let json: Json = serde_json::from_str(&reqwest::get(request_url)?.text()?)?;
-        Result::Ok(json)
-    }
-    fn get_quotes(&mut self) -> Result<&OrderMap<String, QuoteYear>, CacheError> {
-        let now = SystemTime::now();
-        if let Result::Ok(dur) = now.duration_since(self.last_request_time) {
-            if &dur >= &self.delay {
-                println!("Cache expired! Retrieving...");
-                self.last_request_time = now;
-                let request_url = reqwest::Url::parse(&self.request_url)
-                    .expect("Could not parse request URL!");
-                match self.perform_request(request_url) {
-                    Result::Ok(json) => {
-                        let (cache, cache_size) = parse_quotes(json);
-                        self.cache = Option::Some(cache);
-                        self.cache_size = cache_size
-                    },
-                    Result::Err(err) => eprintln!("Cache retrieval failed: {}", err),
+fn handle_message(ctx: &Context, msg: &Message) {
+    let own_id = serenity::CACHE.read().user.id;
+    let allow_bots = ctx.data.lock().get::<Config>().map_or(false, |config| config.allow_bots);
+    if !should_ignore(msg, own_id, allow_bots) {
+        let mut data = ctx.data.lock();
+        let invocation = match read_invocation(&data, &msg.content) {
+            Result::Ok(invocation) => invocation,
+            Result::Err(NotReady) => {
+                log_event!(Debug, "not_ready", format!("Ignoring message {} received before initialization", msg.id));
+                return;
+            },
+        };
+        let count_before = data.get::<QuoteCacheKey>().unwrap().cache_size;
+        let mut config = data.get::<GuildSettings>().unwrap().themed(data.get::<Config>().unwrap(), msg.guild_id());
+        if data.get::<Prefix>().map_or(false, |prefix| prefix.is_bare_mention(&msg.content)) {
+            config.empty_query = config.empty_mention;
+        }
+        let invocation = invocation.map(|(command, args)| match expand_alias(&config.aliases, command.clone(),
+                                                                               args.clone()) {
+            Option::Some((command, args)) => (command, args),
+            Option::None => {
+                log_event!(Warn, "alias_loop", format!("Alias {} didn't resolve to a command", command));
+                (command, args)
+            },
+        });
+        let invocation = invocation.map(|(command, args)| (correct_command(&config, command), args));
+        let links = data.get::<PostedLinks>().unwrap().clone();
+        let rng = selection_rng(&mut data, msg.guild_id());
+        if let Option::Some(guild) = msg.guild_id() {
+            if !config.allows_guild(guild) {
+                if invocation.is_some() {
+                    log_event!(Info, "guild_ignored", format!("Ignoring command from unapproved guild {}", guild),
+                               guild_id = guild.0);
                 }
+                return;
             }
         }
-        if let Option::Some(ref contents) = self.cache {
-            Result::Ok(contents)
-        } else {
-            Result::Err(CacheError)
+        if let Option::Some((ref command, ref args)) = invocation {
+            begin_request();
+            log_event!(Info, "command", format!("{} invoked {} in {}", msg.author.id, command, msg.channel_id),
+                       channel_id = msg.channel_id.0, user_id = msg.author.id.0, command = command.as_str(),
+                       query = args.as_ref().map_or("", |args| args.trim()));
+        }
+        if invocation.is_some() {
+            if let Option::Some(remaining) = check_cooldown(&mut data, &config, msg) {
+                log_event!(Debug, "cooldown", format!("{} is on cooldown for another {}ms", msg.author.id,
+                                                      as_millis(remaining)),
+                           user_id = msg.author.id.0, remaining_ms = as_millis(remaining));
+                if let Result::Err(err) = msg.react(COOLDOWN_REACTION) {
+                    log_event!(Debug, "cooldown_react_failed", format!("Could not react to {}: {}", msg.id, err));
+                }
+                end_request();
+                return;
+            }
         }
+        if invocation.is_some() && data.get::<GuildSettings>().unwrap().maintenance && !config.is_admin(msg.author.id) {
+            send_text(msg, &config.maintenance_text);
+            end_request();
+            return;
+        }
+        let disabled = data.get::<GuildSettings>().unwrap().disabled_in(msg.guild_id());
+        if let Option::Some((ref command, _)) = invocation {
+            if disabled.contains(command) {
+                send_text(msg, &format!("`{}` is disabled here.", command));
+                end_request();
+                return;
+            }
+        }
+        if invocation.is_some() {
+            welcome(&mut data, &config, msg, &disabled);
+        }
+        let started = Instant::now();
+        match invocation {
+            Option::Some((ref command, ref args)) if command == "says" => {
+                let (pin, args) = take_modifier(args, "pin");
+                let (silent, args) = take_modifier(&args, "silent");
+                let refusal = if pin { pin_refusal(&config, msg) } else { Option::None };
+                let args = with_category(&config, data.get::<GuildSettings>().unwrap().categories.get(&msg.channel_id),
+                                         args);
+                let ready = data.get_mut::<QuoteCacheKey>().unwrap().get_quotes().is_ok();
+                refresh_blocked_view(&mut data, msg.guild_id());
+                // Paged listings need the share map back, so they're sent once the cache borrow ends.
+                let pages = {
+                    let cache = data.get::<QuoteCacheKey>().unwrap();
+                    let view = msg.guild_id().and_then(|guild| data.get::<BlockedViews>().unwrap().0.get(&guild));
+                    let response = match refusal {
+                        Option::Some(reason) => CommandResponse::Error(reason),
+                        Option::None => do_command(cache, view, ready, &config, &links, &mut rng.lock().unwrap(),
+                                                   msg.channel_id, msg.author.id, &disabled, &args),
+                    };
+                    let selected = Instant::now();
+                    let pages = match response {
+                        CommandResponse::Pages(title, pages) => Option::Some((title, pages)),
+                        response => {
+                            // Only a post of quotes is worth pinning, not an error or a note about the query.
+                            let pinnable = match response {
+                                CommandResponse::Quote(..) | CommandResponse::Batch(..) | CommandResponse::Listing(..)
+                                    | CommandResponse::Transformed(..) => true,
+                                _ => false,
+                            };
+                            let sent = respond(msg, &config, &links, cache.cache.as_ref(), &disabled,
+                                               args.as_ref().map_or("", |args| args.trim()), config.silent || silent,
+                                               response);
+                            if let Option::Some(ref sent) = sent.filter(|_| pin && pinnable) {
+                                pin_quote(msg, sent);
+                            }
+                            Option::None
+                        },
+                    };
+                    log_event!(Debug, "command_timing", format!("Selection took {}ms, sending took {}ms",
+                                                                as_millis(selected - started),
+                                                                as_millis(selected.elapsed())),
+                               select_ms = as_millis(selected - started), send_ms = as_millis(selected.elapsed()));
+                    pages
+                };
+                if let Option::Some((title, pages)) = pages {
+                    send_paginated(&mut data, msg, &config, "says", &title, pages);
+                }
+            },
+            Option::Some((ref command, ref args)) if command == "similar" =>
+                do_similar(data.get_mut::<QuoteCacheKey>().unwrap(), &config, &links, msg, args),
+            Option::Some((ref command, ref args)) if command == "next" || command == "prev" =>
+                do_adjacent(data.get_mut::<QuoteCacheKey>().unwrap(), &config, &links, msg, args, command == "next"),
+            Option::Some((ref command, _)) if command == "stats" => do_stats(&mut data, &config, msg),
+            Option::Some((ref command, _)) if command == "diag" => {
+                refresh_blocked_view(&mut data, msg.guild_id());
+                do_diag(&mut data, &config, msg)
+            },
+            Option::Some((ref command, _)) if command == "config" => do_config(&mut data, &config, msg),
+            Option::Some((ref command, _)) if command == "years" => do_years(&mut data, &config, msg),
+            Option::Some((ref command, _)) if command == "tags" => do_tags(&mut data, &config, msg),
+            Option::Some((ref command, _)) if command == "histogram" =>
+                do_histogram(data.get_mut::<QuoteCacheKey>().unwrap(), &config, msg),
+            Option::Some((ref command, ref args)) if command == "words" =>
+                do_words(data.get_mut::<QuoteCacheKey>().unwrap(), &config, msg, args),
+            Option::Some((ref command, _)) if command == "digest" => do_digest(&mut data, &config, &links, msg),
+            Option::Some((ref command, _)) if command == "reload" => if config.confirm_admin {
+                request_confirmation(&mut data, &config, msg, PendingAction::Reload)
+            } else {
+                do_reload(&mut data, msg)
+            },
+            Option::Some((ref command, ref args)) if command == "maintenance" => {
+                match args.as_ref().map(|args| args.trim().to_lowercase()) {
+                    Option::Some(ref state) if config.confirm_admin && (state == "on" || state == "off") =>
+                        request_confirmation(&mut data, &config, msg, PendingAction::Maintenance(state == "on")),
+                    _ => do_maintenance(&mut data, &config, msg, args),
+                }
+            },
+            Option::Some((ref command, ref args)) if command == "toggle" =>
+                do_toggle(&mut data, &config, msg, args),
+            Option::Some((ref command, ref args)) if command == "cooldown" =>
+                do_cooldown(&mut data, &config, msg, args),
+            Option::Some((ref command, ref args)) if command == "setcolour" || command == "setcolor" =>
+                do_setcolour(&mut data, msg, args),
+            Option::Some((ref command, ref args)) if command == "setcategory" => do_setcategory(&mut data, msg, args),
+            Option::Some((ref command, ref args)) if command == "setseed" => do_setseed(&mut data, msg, args),
+            Option::Some((ref command, ref args)) if command == "block" => do_block(&mut data, msg, args, true),
+            Option::Some((ref command, ref args)) if command == "unblock" => do_block(&mut data, msg, args, false),
+            Option::Some((ref command, _)) if command == "export" =>
+                do_export(data.get_mut::<QuoteCacheKey>().unwrap(), &config, msg),
+            Option::Some((ref command, _)) if command == "help" => {
+                respond(msg, &config, &links, Option::None, &disabled, "", config.silent, CommandResponse::Help);
+            },
+            _ => if let Option::Some(response) = unmatched_response(&config, msg.is_private()) {
+                respond(msg, &config, &links, Option::None, &disabled, "", config.silent, response);
+            },
+        }
+        if invocation.is_some() {
+            let elapsed = as_millis(started.elapsed());
+            data.get_mut::<Metrics>().unwrap().commands.record(elapsed);
+            log_event!(Info, "command_done", format!("Handled command in {}ms", elapsed), latency_ms = elapsed);
+        }
+        let count_after = data.get::<QuoteCacheKey>().unwrap().cache_size;
+        if count_after != count_before {
+            update_presence(ctx, &config, count_after);
+        }
+        end_request();
     }
 }
 
-impl typemap::Key for QuoteCache {
-    type Value = QuoteCache;
+// Polls the cache so it refreshes on schedule even when nobody is running commands. The fetch and index build
+// run without the shared data lock, so commands keep answering from the old cache until the new one is swapped
+// in. A panic inside a single refresh is caught and logged rather than ending the loop.
+fn refresh_loop(data: Arc<DataMutex<ShareMap>>) {
+    loop {
+        thread::sleep(Duration::from_secs(REFRESH_TICK));
+        let refresh = match data.lock().get_mut::<QuoteCacheKey>().and_then(|cache| cache.begin_refresh()) {
+            Option::Some(refresh) => refresh,
+            Option::None => continue,
+        };
+        match panic::catch_unwind(AssertUnwindSafe(move || refresh.run())) {
+            Result::Ok(result) => if let Option::Some(cache) = data.lock().get_mut::<QuoteCacheKey>() {
+                cache.finish_refresh(result);
+            },
+            Result::Err(_) => {
+                log_event!(Error, "refresh_panicked", "A background refresh panicked; keeping the old cache");
+                if let Option::Some(cache) = data.lock().get_mut::<QuoteCacheKey>() {
+                    cache.abandon_refresh();
+                }
+            },
+        }
+    }
 }
 
-fn send_quote(msg: &Message, quote: &Quote) {
-    let month = MONTHS[quote.month.parse::<usize>().unwrap() - 1];
-    if let Result::Err(err) = msg.channel_id.send_message(|m| m
-        .embed(|e| e
-            .description(&quote.text)
-            .colour(0x2196F3)
-            .footer(|f| f
-                .text(format!("Arraying, {} {}", month, quote.year))
-                .icon_url("https://avatars1.githubusercontent.com/u/16021050?s=460&v=4")
-            )
-        )
-    ) {
-        eprintln!("Failed to send message: {}", err);
+// Keeps fetching after a failed first load until some quotes arrive, whether or not refreshes otherwise run in
+// the background. Past `grace` it logs an error, once, and goes on trying.
+fn startup_loop(data: Arc<DataMutex<ShareMap>>, grace: Duration) {
+    let mut warned = false;
+    loop {
+        thread::sleep(Duration::from_secs(STARTUP_RETRY_TICK));
+        let refresh = {
+            let mut data = data.lock();
+            let loaded = data.get::<QuoteCacheKey>().map_or(false, |cache| cache.cache.is_some());
+            if loaded {
+                let startup = data.get_mut::<Startup>().unwrap();
+                startup.loaded();
+                log_event!(Info, "startup_recovered", format!("Quotes loaded {}ms after starting",
+                                                              as_millis(startup.loaded_after.unwrap())));
+                break;
+            }
+            let began = data.get::<Startup>().unwrap().began;
+            if !warned && began.elapsed() >= grace {
+                log_event!(Error, "startup_grace_exceeded", format!(
+                    "ERROR: no quotes have loaded in the {}ms since starting; still retrying. Check the source is up \
+                     and BOT_URL is right.", as_millis(began.elapsed())), grace_ms = as_millis(grace));
+                warned = true;
+            }
+            data.get_mut::<QuoteCacheKey>().and_then(|cache| cache.begin_refresh())
+        };
+        if let Option::Some(refresh) = refresh {
+            let result = refresh.run();
+            if let Option::Some(cache) = data.lock().get_mut::<QuoteCacheKey>() {
+                cache.finish_refresh(result);
+            }
+        }
     }
+    STARTUP_RETRYING.store(false, Ordering::SeqCst);
 }
 
-fn choose_map_entry<V>(map: &OrderMap<String, V>) -> &V {
-    map.get_index(rand::thread_rng().gen_range::<usize>(0, map.len())).unwrap().1
+// Respawns the refresh thread whenever it dies, so the cache never silently stops updating.
+fn supervise_refresh(data: Arc<DataMutex<ShareMap>>) {
+    if REFRESH_STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    thread::spawn(move || loop {
+        let worker_data = data.clone();
+        let worker = thread::Builder::new()
+            .name("refresh".to_string())
+            .spawn(move || refresh_loop(worker_data));
+        match worker.map(|handle| handle.join()) {
+            Result::Ok(Result::Ok(())) => return,
+            Result::Ok(Result::Err(_)) => {
+                let restarts = REFRESH_RESTARTS.fetch_add(1, Ordering::Relaxed) + 1;
+                log_event!(Error, "refresh_restarted",
+                           format!("Refresh thread died; restarting (restart #{})", restarts), restarts = restarts);
+            },
+            Result::Err(err) => log_event!(Error, "refresh_spawn_failed",
+                                           format!("Could not spawn refresh thread: {}", err), error = err.to_string()),
+        }
+        thread::sleep(Duration::from_secs(REFRESH_RESTART_BACKOFF));
+    });
 }
 
-fn flatten<'a>(quotes: &'a OrderMap<String, QuoteYear>, quotes_flat: &mut Vec<Box<&'a Quote>>) {
-    for (_, year) in quotes {
-        for (_, month) in &year.months {
-            for quote in &month.quotes {
-                &quotes_flat.push(Box::new(quote));
-            }
+// Which UTC day it is and how many minutes into it, as `(day, minute)`.
+fn utc_now() -> (u64, u64) {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    (seconds / 86400, seconds % 86400 / 60)
+}
+
+// The current calendar year in UTC, from the days-since-epoch count in `utc_now`.
+fn current_year() -> u32 {
+    // Shifts the epoch to 1 March 0000 so leap days fall at the end of each 400-year era.
+    let days = utc_now().0 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_month = (5 * day_of_year + 2) / 153;
+    (era * 400 + year_of_era) as u32 + if march_month >= 10 { 1 } else { 0 }
+}
+
+// Reacts to a posted quote with the emoji for its age, if age reactions are configured and the quote has a year.
+// A failed reaction is only logged; the quote itself was already sent.
+fn react_age(config: &Config, quote: &Quote, sent: &Message) {
+    let year = match quote.year.parse::<u32>() {
+        Result::Ok(year) if !config.age_reactions.is_empty() => year,
+        _ => return,
+    };
+    let age = current_year().saturating_sub(year);
+    if let Option::Some(&(_, ref emoji)) = config.age_reactions.iter().find(|&&(years, _)| age >= years) {
+        if let Result::Err(err) = sent.react(emoji.clone()) {
+            log_event!(Debug, "age_react_failed", format!("Could not react to {}: {}", sent.id, err));
         }
     }
 }
 
-fn do_command(cache: &mut QuoteCache, msg: &Message, args: &Option<String>) {
-    let cache_size = cache.cache_size;
-    if let Result::Ok(quotes) = cache.get_quotes() {
-        if let &Option::Some(ref query) = args {
-            if !query.is_empty() {
-                // TODO Implement date-based search
-                let mut best: Option<&Quote> = Option::None;
-                let mut best_score: isize = 0;
-                for (_, year) in quotes {
-                    for (_, month) in &year.months {
-                        for quote in &month.quotes {
-                            if let Option::Some(f_match) = best_match(
-                                &query.to_lowercase(),
-                                &quote.text.to_lowercase()
-                            ) {
-                                if f_match.score() != 0
-                                    && (!best.is_some() || f_match.score() > best_score) {
-                                    best = Option::Some(quote);
-                                    best_score = f_match.score();
-                                }
-                            }
-                        }
-                    }
-                }
-                if let Option::Some(quote) = best {
-                    send_quote(&msg, quote);
-                } else {
-                    if let Result::Err(err) = msg.channel_id.send_message(|m| m
-                        .content(format!("No results found for query `{}`.", query))) {
-                        eprintln!("Failed to send message: {}", err);
-                    }
-                }
-                return;
+// Posts one quote to `schedule.channel`, drawn from its filter if it has one.
+fn post_qotd(shared: &mut ShareMap, config: &Config, schedule: &QotdSchedule) -> bool {
+    let guild = serenity::CACHE.read().guild_channel(schedule.channel).map(|channel| channel.read().guild_id);
+    let config = &shared.get::<GuildSettings>().unwrap().themed(config, guild);
+    let links = shared.get::<PostedLinks>().unwrap().clone();
+    let rng = selection_rng(shared, guild);
+    let cache = match shared.get_mut::<QuoteCacheKey>() {
+        Option::Some(cache) => cache,
+        Option::None => return false,
+    };
+    if cache.get_quotes().is_err() {
+        return false;
+    }
+    let quotes = cache.cache.as_ref().unwrap();
+    let pool: Vec<&Quote> = match schedule.filter {
+        Option::Some(QotdFilter::Year(ref year)) => year_quotes(quotes, year),
+        Option::Some(QotdFilter::Source(ref label)) => quotes.values()
+            .flat_map(|year| year.months.values())
+            .flat_map(|month| month.quotes.iter())
+            .filter(|quote| quote.source.as_ref() == Option::Some(label))
+            .collect(),
+        Option::None => quotes.values()
+            .flat_map(|year| year.months.values())
+            .flat_map(|month| month.quotes.iter())
+            .collect(),
+    };
+    let recent: Vec<&Quote> = recent_positions(&cache.index, &links, schedule.channel).into_iter()
+        .filter_map(|position| cache.index.get(quotes, position))
+        .collect();
+    let quote = match select_weighted(&pool, &config.source_weights(), &mut *rng.lock().unwrap(), &recent) {
+        Option::Some(quote) => quote,
+        Option::None => {
+            log_event!(Warn, "qotd_empty", format!("No quotes match the QOTD filter for {}", schedule.channel),
+                       channel_id = schedule.channel.0);
+            return false;
+        },
+    };
+    match send_quote(schedule.channel, quote, Option::Some(quotes), config, &links, "says",
+                     Option::Some("Quote of the day"), Option::None, config.silent) {
+        Result::Ok(_) => true,
+        Result::Err(err) => {
+            log_event!(Error, "qotd_failed", format!("Failed to post QOTD to {}: {}", schedule.channel, err),
+                       channel_id = schedule.channel.0, error = err.to_string());
+            false
+        },
+    }
+}
+
+// Checks every QOTD schedule each tick and posts to any channel whose time has come and that hasn't had
+// today's quote yet. Each channel fires independently of the others.
+fn qotd_loop(data: Arc<DataMutex<ShareMap>>) {
+    loop {
+        thread::sleep(Duration::from_secs(QOTD_TICK));
+        let mut shared = data.lock();
+        let config = shared.get::<Config>().unwrap().clone();
+        let (today, minute) = utc_now();
+        for schedule in &config.qotd {
+            let due = minute >= schedule.minute && shared.get::<GuildSettings>().unwrap()
+                .qotd_posted.get(&schedule.channel).map_or(true, |&day| day < today);
+            if due && post_qotd(&mut shared, &config, schedule) {
+                log_event!(Info, "qotd_posted", format!("Posted the quote of the day to {}", schedule.channel),
+                           channel_id = schedule.channel.0);
+                let settings = shared.get_mut::<GuildSettings>().unwrap();
+                settings.qotd_posted.insert(schedule.channel, today);
+                // Flushed straight away: losing this would repost today's quote after a restart.
+                settings.mark_dirty();
+                settings.flush();
             }
         }
-        let mut quotes_flat: Vec<Box<&Quote>> = Vec::with_capacity(cache_size);
-        flatten(quotes, &mut quotes_flat);
-        let quote = rand::thread_rng().choose(&quotes_flat);
-        send_quote(&msg, &quote.unwrap());
-    } else {
-        panic!("Cache was null at command!");
     }
 }
 
-struct Handler(Config);
+// Writes dirty state to disk periodically, so a crash loses at most one interval of changes.
+fn flush_loop(data: Arc<DataMutex<ShareMap>>) {
+    loop {
+        thread::sleep(Duration::from_secs(STATE_FLUSH_INTERVAL));
+        let mut data = data.lock();
+        data.get_mut::<GuildSettings>().unwrap().flush();
+        // Also the closest thing to a timer the confirmations have, so a prompt nobody answers still closes.
+        expire_confirmations(&mut data);
+    }
+}
+
+struct Handler;
 
 impl EventHandler for Handler {
     fn message(&self, ctx: Context, msg: Message) {
-        if !msg.author.bot {
+        if !ctx.data.lock().get_mut::<SeenMessages>().unwrap().record(msg.id) {
+            log_event!(Debug, "duplicate_message", format!("Skipping redelivered message {}", msg.id),
+                       message_id = msg.id.0);
+            return;
+        }
+        handle_message(&ctx, &msg);
+    }
+    fn message_update(&self, ctx: Context, update: MessageUpdateEvent) {
+        let content = match update.content {
+            Option::Some(ref content) => content,
+            Option::None => return,
+        };
+        {
             let mut data = ctx.data.lock();
-            if let Option::Some(groups) = data.get::<Prefix>().unwrap().0.captures(&msg.content) {
-                do_command(data.get_mut::<QuoteCache>().unwrap(), &msg,
-                           &groups.get(1).map(|m| m.as_str().to_string()));
-            } else if msg.is_private() {
-                if let Result::Err(err) = msg.channel_id.send_message(|m| m.content(HELP_TEXT)) {
-                    eprintln!("Failed to send message: {}", err);
-                }
+            if !data.get::<Config>().unwrap().handle_edits
+                || !data.get::<Prefix>().map_or(false, |prefix| prefix.regex.is_match(content))
+                || !data.get_mut::<HandledEdits>().unwrap().record(update.id, content) {
+                return;
             }
         }
+        match update.channel_id.message(update.id) {
+            Result::Ok(msg) => handle_message(&ctx, &msg),
+            Result::Err(err) => log_event!(Error, "edit_fetch_failed",
+                                           format!("Failed to fetch edited message {}: {}", update.id, err),
+                                           message_id = update.id.0, error = err.to_string()),
+        }
+    }
+    fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        if reaction.user_id != serenity::CACHE.read().user.id {
+            turn_page(&ctx, &reaction);
+            confirm_action(&ctx, &reaction);
+        }
+    }
+    fn guild_create(&self, ctx: Context, guild: Guild, _is_new: bool) {
+        let config = ctx.data.lock().get::<Config>().unwrap().clone();
+        if config.allows_guild(guild.id) || !config.leave_unapproved {
+            return;
+        }
+        match guild.id.leave() {
+            Result::Ok(()) => log_event!(Warn, "guild_left", format!("Left unapproved guild {} ({})", guild.name, guild.id),
+                                         guild_id = guild.id.0),
+            Result::Err(err) => log_event!(Error, "guild_leave_failed",
+                                           format!("Failed to leave unapproved guild {}: {}", guild.id, err),
+                                           guild_id = guild.id.0, error = err.to_string()),
+        }
     }
     fn ready(&self, ctx: Context, ready: Ready) {
-        println!("Authenticated successfully!");
+        log_event!(Info, "ready", "Authenticated successfully!");
 
-        println!("Building prefix pattern...");
-        let prefix_pattern = format!(r"(?:\[]says|<@!?{}>)\s*(?:(.*)\s*)?", ready.user.id);
-        println!("Pattern built: {}", prefix_pattern);
         let mut data = ctx.data.lock();
-        data.insert::<Prefix>(Prefix(Regex::new(&prefix_pattern).unwrap()));
+        let config = data.get::<Config>().unwrap().clone();
 
-        println!("Preparing quote cache...");
-        let mut cache = QuoteCache {
-            last_request_time: UNIX_EPOCH,
-            cache: Option::None,
-            cache_size: 0,
-            request_url: self.0.url.clone(),
-            delay: self.0.delay,
-        };
-        if cache.get_quotes().is_err() {
-            panic!("Initial cache population failed!");
+        refresh_prefix(&mut data, &config.prefix, ready.user.id);
+
+        log_event!(Info, "startup", "Preparing quote cache...");
+        let mut cache = QuoteCache::new(config.sources.clone(), config.request.clone(), config.format, config.dedup,
+                                        config.delay, config.jitter)
+            .expect("Could not create HTTP client");
+        cache.stopwords = config.stopwords.clone();
+        let loaded = cache.get_quotes().is_ok();
+        if loaded {
+            data.get_mut::<Startup>().unwrap().loaded();
+        } else {
+            log_event!(Warn, "startup_empty", "Initial cache population failed; retrying in the background \
+                                              and answering that quotes aren't ready for now",
+                       grace_ms = as_millis(config.startup_grace));
+        }
+        update_presence(&ctx, &config, cache.cache_size);
+        cache.background = config.background_refresh;
+        cache.max_in_flight = config.max_fetches;
+        cache.breaker = CircuitBreaker::new(config.breaker_threshold, config.breaker_cooldown);
+        data.insert::<QuoteCacheKey>(cache);
+        if config.background_refresh {
+            supervise_refresh(ctx.data.clone());
+        }
+        if !loaded && !STARTUP_RETRYING.swap(true, Ordering::SeqCst) {
+            let startup_data = ctx.data.clone();
+            let grace = config.startup_grace;
+            thread::spawn(move || startup_loop(startup_data, grace));
+        }
+        for schedule in &config.qotd {
+            if let Result::Err(err) = schedule.channel.get() {
+                log_event!(Warn, "qotd_channel_unavailable",
+                           format!("QOTD channel {} isn't reachable: {}", schedule.channel, err),
+                           channel_id = schedule.channel.0, error = err.to_string());
+            }
+        }
+        if !config.qotd.is_empty() && !QOTD_STARTED.swap(true, Ordering::SeqCst) {
+            let qotd_data = ctx.data.clone();
+            thread::spawn(move || qotd_loop(qotd_data));
         }
-        data.insert::<QuoteCache>(cache);
 
-        println!("Bot initialization completed!");
+        log_event!(Info, "startup", "Bot initialization completed!");
     }
 }
 
-fn main() {
-    println!("Loading configuration...");
-    let config = Config {
-        url: env::var("BOT_URL").expect("config->url"),
-        token: env::var("BOT_TOKEN").expect("config->token"),
-        delay: Duration::from_millis(
-            if let std::result::Result::Ok(res) = env::var("BOT_REQ_DELAY") {
-                res.parse::<u64>().unwrap_or(DEFAULT_REQ_DELAY)
-            } else { DEFAULT_REQ_DELAY }
-        )
+fn dry_run(config: &Config, sample: bool) {
+    println!("Dry run: fetching quotes from {}...", config.url);
+    let client = http_client().expect("Could not create HTTP client");
+    let (quotes, quote_count) = match fetch_quotes(&client, &config.sources, &config.request,
+                                                   config.format, config.dedup) {
+        Result::Ok(parsed) => {
+            if parsed.skipped > 0 {
+                println!("Skipped {} malformed entries", parsed.skipped);
+            }
+            (parsed.quotes, parsed.quote_count)
+        },
+        Result::Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        },
     };
-    println!("url: {}, token: {}, delay: {}", config.url, config.token, config.delay.as_secs());
+    for (year_key, year) in &quotes {
+        println!("{}", year_key);
+        for (month_key, month) in &year.months {
+            println!("  {}: {} quotes", month_key, month.quotes.len());
+        }
+    }
+    println!("Total: {} quotes in {} years", quote_count, quotes.len());
+    if sample {
+        if let Option::Some(quote) = select_quote(&quotes, Option::None, config.fuzzy_distance,
+                                                    &mut QuoteRng::new(config.random_seed)) {
+            println!("Sample ({}-{}): {}", quote.year, quote.month, quote.text);
+        } else {
+            println!("No quotes to sample!");
+        }
+    }
+}
+
+fn main() {
+    let dry = env::args().any(|arg| arg == "--dry-run")
+        || env_flag("BOT_DRY_RUN");
+
+    let config = Config::load(!dry).expect("Could not load configuration");
+    logging::configure(config.log_format, config.log_level);
+    // The token is left out whichever way it was given.
+    log_event!(Info, "config", format!("url: {}, delay: {}, request: {:?}",
+                                       config.url, config.delay.as_secs(), config.request),
+               url = config.url.as_str(), delay_secs = config.delay.as_secs(),
+               headers = config.request.headers.iter().map(|&(ref name, _)| name.clone()).collect::<Vec<String>>());
+
+    if dry {
+        dry_run(&config, env::args().any(|arg| arg == "--sample"));
+        return;
+    }
+
+    log_event!(Info, "startup", "Initializing client...");
+    let mut bot = Client::new(&config.token.clone(), Handler).expect("Could not create client");
+    {
+        let mut data = bot.data.lock();
+        data.insert::<PostedLinks>(Arc::new(Mutex::new(PostedLinks::new(config.link_capacity, config.recent_size))));
+        data.insert::<SelectionRng>(Arc::new(Mutex::new(QuoteRng::new(config.random_seed))));
+        data.insert::<GuildSettings>(GuildSettings::load(&config.state_file));
+        data.insert::<Config>(Arc::new(config));
+        data.insert::<HandledEdits>(HandledEdits { order: VecDeque::new(), contents: HashMap::new() });
+        data.insert::<SeenMessages>(SeenMessages { order: VecDeque::new(), ids: HashSet::new() });
+        data.insert::<Metrics>(Metrics { commands: LatencyWindow::new() });
+        data.insert::<Paginations>(Paginations(HashMap::new()));
+        data.insert::<Cooldowns>(Cooldowns(HashMap::new()));
+        data.insert::<BlockedViews>(BlockedViews(HashMap::new()));
+        data.insert::<Confirmations>(Confirmations(HashMap::new()));
+        data.insert::<GuildRngs>(GuildRngs(HashMap::new()));
+        data.insert::<Startup>(Startup { began: Instant::now(), loaded_after: Option::None });
+    }
+    let flush_data = bot.data.clone();
+    thread::spawn(move || flush_loop(flush_data));
+    let shutdown_data = bot.data.clone();
+    let handler = ctrlc::set_handler(move || {
+        log_event!(Info, "shutdown", "Interrupted; saving state before exiting");
+        shutdown_data.lock().get_mut::<GuildSettings>().unwrap().flush();
+        process::exit(0);
+    });
+    if let Result::Err(err) = handler {
+        log_event!(Warn, "shutdown_handler_failed", format!("Could not install the Ctrl-C handler: {}", err),
+                   error = err.to_string());
+    }
+    let mut backoff = Duration::from_millis(START_BACKOFF);
+    let mut attempt = 1;
+    loop {
+        match bot.start() {
+            Result::Ok(()) => break,
+            Result::Err(ref err) if attempt < START_ATTEMPTS && is_retryable_start_error(err) => {
+                log_event!(Warn, "start_retry", format!(
+                    "Could not connect to Discord (attempt {}/{}), retrying in {}ms: {}",
+                    attempt, START_ATTEMPTS, as_millis(backoff), err),
+                           attempt = attempt, wait_ms = as_millis(backoff), error = err.to_string());
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            },
+            Result::Err(err) => {
+                log_event!(Error, "start_failed", format!("Could not connect to Discord: {}", err),
+                           attempt = attempt, error = err.to_string());
+                panic!(err);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWN_ID: u64 = 1;
+
+    // A guild message as the gateway would deliver it.
+    fn message(author: u64, bot: bool, webhook: Option<u64>, content: &str) -> Message {
+        serde_json::from_str(&format!(r#"{{
+            "id": "100", "channel_id": "200", "content": {}, "type": 0, "tts": false, "pinned": false,
+            "mention_everyone": false, "mention_roles": [], "mentions": [], "attachments": [], "embeds": [],
+            "timestamp": "2018-03-01T12:00:00+00:00", "edited_timestamp": null, "webhook_id": {},
+            "author": {{ "id": "{}", "username": "someone", "discriminator": "0001", "avatar": null,
+                         "bot": {} }}
+        }}"#, Json::from(content), webhook.map_or(Json::Null, |id| Json::from(id.to_string())), author, bot)).unwrap()
+    }
+
+    #[test]
+    fn webhook_messages_are_ignored() {
+        let msg = message(2, false, Option::Some(300), "[]says hello");
+        assert!(should_ignore(&msg, UserId(OWN_ID), false));
+        assert!(!should_ignore(&message(2, false, Option::None, "[]says hello"), UserId(OWN_ID), false));
+    }
+
+    #[test]
+    fn own_messages_are_ignored_even_with_allow_bots() {
+        let own = message(OWN_ID, true, Option::None, "[]says hello");
+        assert!(should_ignore(&own, UserId(OWN_ID), false));
+        assert!(should_ignore(&own, UserId(OWN_ID), true));
+    }
+
+    #[test]
+    fn allow_bots_lets_other_bots_and_webhooks_through() {
+        let bot = message(2, true, Option::None, "[]says hello");
+        let webhook = message(3, false, Option::Some(300), "[]says hello");
+        assert!(should_ignore(&bot, UserId(OWN_ID), false));
+        assert!(should_ignore(&webhook, UserId(OWN_ID), false));
+        assert!(!should_ignore(&bot, UserId(OWN_ID), true));
+        assert!(!should_ignore(&webhook, UserId(OWN_ID), true));
+    }
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/quotes.json");
+
+    fn empty_cache() -> QuoteCache {
+        QuoteCache::new(Vec::new(), SourceRequest::default(), SourceFormat::Auto, false, Duration::from_secs(60), 0)
+            .unwrap()
+    }
+
+    // A cache loaded from the fixture corpus.
+    fn fixture_cache() -> QuoteCache {
+        let url = reqwest::Url::from_file_path(FIXTURE).unwrap();
+        let source = Source { label: Option::None, url, weight: Option::None, fallback: Option::None };
+        let mut cache = QuoteCache::new(vec![source], SourceRequest::default(), SourceFormat::Auto, false,
+                                        Duration::from_secs(60), 0).unwrap();
+        cache.get_quotes().ok().expect("the fixture should load");
+        cache
+    }
+
+    // The configuration with nothing set beyond the required source URL.
+    fn default_config() -> Config {
+        env::set_var("BOT_URL", "https://example.com/quotes.json");
+        Config::load(false).ok().expect("the default configuration should load")
+    }
+
+    fn says<'a>(cache: &'a QuoteCache, config: &Config, disabled: &HashSet<String>, query: Option<&str>)
+        -> CommandResponse<'a> {
+        let links = Mutex::new(PostedLinks::new(config.link_capacity, config.recent_size));
+        do_command(cache, Option::None, true, config, &links, &mut QuoteRng::new(Option::Some(7)), ChannelId(200),
+                   UserId(2), disabled, &query.map(str::to_string))
+    }
+
+    #[test]
+    fn command_responses() {
+        let cache = fixture_cache();
+        let config = default_config();
+        let enabled = HashSet::new();
+        match says(&cache, &config, &enabled, Option::None) {
+            CommandResponse::Quote(..) => {},
+            _ => panic!("an empty query should pick a quote"),
+        }
+        match says(&cache, &config, &enabled, Option::Some("2018")) {
+            CommandResponse::Quote(quote, _) => assert_eq!(quote.year, "2018"),
+            _ => panic!("a year should pick a quote from it"),
+        }
+        match says(&cache, &config, &enabled, Option::Some("hemlock")) {
+            CommandResponse::Quote(quote, _) => assert_eq!(quote.text, "Socrates drank the hemlock"),
+            _ => panic!("free text should find the quote containing it"),
+        }
+        match says(&cache, &config, &enabled, Option::Some("zzzzzz")) {
+            CommandResponse::NoMatch(query) => assert_eq!(query, "zzzzzz"),
+            _ => panic!("text in no quote should be a miss"),
+        }
+        let disabled: HashSet<String> = vec!["batch".to_string()].into_iter().collect();
+        match says(&cache, &config, &disabled, Option::Some("batch 2")) {
+            CommandResponse::Text(text) => assert_eq!(text, "`says batch` is disabled here."),
+            _ => panic!("a disabled form should be refused"),
+        }
+    }
+
+    // A cache loaded from `json`, written to a temporary file named after `name`.
+    fn corpus_cache(name: &str, json: &str) -> QuoteCache {
+        let path = env::temp_dir().join(format!("arrayong-{}-{}.json", process::id(), name));
+        fs::write(&path, json).unwrap();
+        let url = reqwest::Url::from_file_path(&path).unwrap();
+        let source = Source { label: Option::None, url, weight: Option::None, fallback: Option::None };
+        let mut cache = QuoteCache::new(vec![source], SourceRequest::default(), SourceFormat::Auto, false,
+                                        Duration::from_secs(60), 0).unwrap();
+        cache.get_quotes().ok().expect("the corpus should load");
+        cache
+    }
+
+    #[test]
+    fn searches_that_match_are_not_corrected() {
+        let cache = corpus_cache("collisions", r#"{ "2018": { "3": ["Marc spilled the coffee"],
+            "5": ["Mary had a little lamb", "I lost keys at the fair", "The ransom note was unsigned"] } }"#);
+        let config = default_config();
+        let enabled = HashSet::new();
+        for &(query, text) in &[("mary", "Mary had a little lamb"), ("marc", "Marc spilled the coffee"),
+                                ("lost keys", "I lost keys at the fair"),
+                                ("ransom note", "The ransom note was unsigned")] {
+            match says(&cache, &config, &enabled, Option::Some(query)) {
+                CommandResponse::Quote(quote, note) => {
+                    assert_eq!(quote.text, text);
+                    assert!(!note.unwrap_or_default().contains("interpreting"), "{}", query);
+                },
+                _ => panic!("`{}` should find its quote", query),
+            }
+        }
+        match says(&cache, &config, &enabled, Option::Some("Mayy 2018")) {
+            CommandResponse::Quote(quote, note) => {
+                assert_eq!(quote.month, "5");
+                assert!(note.unwrap_or_default().contains("interpreting 'Mayy' as May"));
+            },
+            _ => panic!("a misspelt month that finds nothing should be corrected"),
+        }
+    }
+
+    #[test]
+    fn numbers_that_dont_parse_are_config_errors() {
+        env::remove_var("BOT_TEST_NUMBER");
+        assert_eq!(env_number("BOT_TEST_NUMBER", "test_number", 5usize).ok(), Option::Some(5));
+        env::set_var("BOT_TEST_NUMBER", " 12 ");
+        assert_eq!(env_number("BOT_TEST_NUMBER", "test_number", 5usize).ok(), Option::Some(12));
+        for res in &["twelve", "-1", ""] {
+            env::set_var("BOT_TEST_NUMBER", res);
+            match env_number("BOT_TEST_NUMBER", "test_number", 5usize) {
+                Result::Err(ConfigError(err)) => assert_eq!(err, format!("config->test_number: {}", res)),
+                Result::Ok(number) => panic!("`{}` shouldn't read as {}", res, number),
+            }
+        }
+        env::remove_var("BOT_TEST_NUMBER");
+    }
+
+    #[test]
+    fn seen_messages_answer_once() {
+        let mut seen = SeenMessages { order: VecDeque::new(), ids: HashSet::new() };
+        assert!(seen.record(MessageId(1)));
+        assert!(!seen.record(MessageId(1)));
+        assert!(seen.record(MessageId(2)));
+    }
+
+    #[test]
+    fn seen_messages_evict_the_oldest_at_capacity() {
+        let mut seen = SeenMessages { order: VecDeque::new(), ids: HashSet::new() };
+        for id in 0..MAX_TRACKED_MESSAGES as u64 {
+            assert!(seen.record(MessageId(id)));
+        }
+        assert_eq!(seen.order.len(), MAX_TRACKED_MESSAGES);
+        assert!(!seen.record(MessageId(0)));
+        assert!(seen.record(MessageId(MAX_TRACKED_MESSAGES as u64)));
+        assert_eq!(seen.order.len(), MAX_TRACKED_MESSAGES);
+        // The oldest id made room for the new one, so it counts as unseen again.
+        assert!(seen.record(MessageId(0)));
+        assert!(!seen.record(MessageId(MAX_TRACKED_MESSAGES as u64 - 1)));
+    }
+
+    // Settings that start empty, since nothing exists at the path.
+    fn fresh_settings() -> GuildSettings {
+        GuildSettings::load("/nonexistent/arrayong-state.json")
+    }
+
+    #[test]
+    fn flushed_settings_load_back() {
+        let path = env::temp_dir().join(format!("arrayong-{}-state.json", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let mut settings = GuildSettings::load(path);
+        settings.cooldowns.insert(GuildId(10), 30);
+        settings.disabled.insert(GuildId(10), vec!["regex".to_string(), "batch".to_string()].into_iter().collect());
+        settings.qotd_posted.insert(ChannelId(20), 17_000);
+        settings.maintenance = true;
+        settings.welcomed.insert(UserId(2));
+        settings.colours.insert(GuildId(10), 0x12_34_56);
+        settings.categories.insert(ChannelId(20), "philosophy".to_string());
+        settings.blocked.insert(GuildId(11), vec!["hemlock".to_string()].into_iter().collect());
+        settings.seeds.insert(GuildId(11), 42);
+        settings.mark_dirty();
+        settings.flush();
+        assert!(!settings.dirty);
+        let loaded = GuildSettings::load(path);
+        assert_eq!(loaded.to_json(), settings.to_json());
+        assert_eq!(loaded.cooldowns, settings.cooldowns);
+        assert_eq!(loaded.disabled, settings.disabled);
+        assert_eq!(loaded.qotd_posted, settings.qotd_posted);
+        assert!(loaded.maintenance);
+        assert_eq!(loaded.welcomed, settings.welcomed);
+        assert_eq!(loaded.colours, settings.colours);
+        assert_eq!(loaded.categories, settings.categories);
+        assert_eq!(loaded.blocked, settings.blocked);
+        assert_eq!(loaded.seeds, settings.seeds);
+        assert!(fs::metadata(format!("{}.tmp", path)).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn toggle_arguments() {
+        assert_eq!(parse_toggle("Regex OFF"), Option::Some(("regex".to_string(), false)));
+        assert_eq!(parse_toggle(" regex  on "), Option::Some(("regex".to_string(), true)));
+        assert_eq!(parse_toggle("regex"), Option::None);
+        assert_eq!(parse_toggle("regex maybe"), Option::None);
+        assert_eq!(parse_toggle(""), Option::None);
+    }
+
+    #[test]
+    fn toggles_are_per_guild() {
+        let mut settings = fresh_settings();
+        let (guild, other) = (GuildId(10), GuildId(11));
+        assert!(settings.disabled_in(Option::Some(guild)).is_empty());
+        settings.toggle(guild, "regex", false).unwrap();
+        settings.toggle(guild, "batch", false).unwrap();
+        assert!(settings.dirty);
+        assert!(settings.disabled_in(Option::Some(guild)).contains("regex"));
+        assert!(settings.disabled_in(Option::Some(other)).is_empty());
+        assert!(settings.disabled_in(Option::None).is_empty());
+        settings.toggle(guild, "regex", true).unwrap();
+        assert_eq!(settings.disabled_in(Option::Some(guild)), vec!["batch".to_string()].into_iter().collect());
+        // Turning on what was never off is harmless.
+        settings.toggle(other, "regex", true).unwrap();
+        assert!(settings.disabled_in(Option::Some(other)).is_empty());
+    }
+
+    #[test]
+    fn plain_says_cant_be_toggled() {
+        let mut settings = fresh_settings();
+        assert!(settings.toggle(GuildId(10), "says", false).is_err());
+        assert!(settings.toggle(GuildId(10), "nonsense", false).is_err());
+        assert!(!settings.dirty);
+        assert!(settings.disabled_in(Option::Some(GuildId(10))).is_empty());
+    }
 
-    println!("Initializing client...");
-    let mut bot = Client::new(&config.token.clone(), Handler(config)).expect("Could not create client");
-    if let Result::Err(err) = bot.start() {
-        panic!(err);
+    #[test]
+    fn batches_clamp_to_max_batch() {
+        let cache = fixture_cache();
+        let mut config = default_config();
+        config.max_batch = 2;
+        let enabled = HashSet::new();
+        for &(query, requested, shown) in &[("batch 1", 1, 1), ("batch 2", 2, 2), ("batch 3", 3, 2),
+                                            ("batch 9999", 9999, 2)] {
+            match says(&cache, &config, &enabled, Option::Some(query)) {
+                CommandResponse::Batch(quotes, asked, _) => {
+                    assert_eq!(asked, requested);
+                    assert_eq!(quotes.len(), shown, "{}", query);
+                },
+                _ => panic!("`{}` should be a batch", query),
+            }
+        }
+    }
+
+    #[test]
+    fn batches_pick_distinct_matches() {
+        let cache = fixture_cache();
+        let config = default_config();
+        let enabled = HashSet::new();
+        match says(&cache, &config, &enabled, Option::Some("4 quote")) {
+            CommandResponse::Batch(quotes, 4, _) => {
+                let mut ids: Vec<String> = quotes.iter().map(|quote| quote.id()).collect();
+                assert!(quotes.iter().all(|quote| quote.text.to_lowercase().contains("quote")));
+                ids.sort();
+                ids.dedup();
+                assert_eq!(ids.len(), 4);
+            },
+            _ => panic!("a count and a search should be a batch"),
+        }
+        // Only two quotes mention Socrates, so asking for five brings back both.
+        match says(&cache, &config, &enabled, Option::Some("5 socrates")) {
+            CommandResponse::Batch(quotes, 5, _) => {
+                let mut ids: Vec<String> = quotes.iter().map(|quote| quote.id()).collect();
+                ids.sort();
+                assert_eq!(ids, vec!["2017-1-1", "2018-3-1"]);
+            },
+            _ => panic!("a count past the matches should still be a batch"),
+        }
+    }
+
+    #[test]
+    fn counts_that_find_nothing_are_searched_as_text() {
+        // A batch only looks for its terms as written, so it takes the fuzzy search to find the misspelt quote.
+        let cache = corpus_cache("counts", r#"{ "2018": { "3": ["All 12 angri men",
+                                                               "Another 0 days since we met"] } }"#);
+        let config = default_config();
+        let enabled = HashSet::new();
+        for &(query, text) in &[("12 angry men", "All 12 angri men"),
+                                ("0 days", "Another 0 days since we met")] {
+            match says(&cache, &config, &enabled, Option::Some(query)) {
+                CommandResponse::Quote(quote, _) => assert_eq!(quote.text, text),
+                _ => panic!("`{}` should be searched for", query),
+            }
+        }
+        match says(&cache, &config, &enabled, Option::Some("batch 12 angry men")) {
+            CommandResponse::NoMatch(terms) => assert_eq!(terms, "angry men"),
+            _ => panic!("a batch asked for by name shouldn't turn into a search"),
+        }
+    }
+
+    #[test]
+    fn packed_quotes_clamp_to_the_character_limit() {
+        let cache = fixture_cache();
+        let quotes = cache.cache.as_ref().unwrap();
+        let first = find_quote(quotes, "2018-3-1").unwrap();
+        let second = find_quote(quotes, "2018-3-2").unwrap();
+        let both = format!("{}\n— March 2018 · `{}`\n\n{}\n— March 2018 · `{}`", first.text, first.id(), second.text,
+                           second.id());
+        let limit = both.chars().count();
+        let (description, shown) = pack_quotes(&[first, second], limit, false);
+        assert_eq!((description, shown.len()), (both, 2));
+        let (description, shown) = pack_quotes(&[first, second], limit - 1, false);
+        assert_eq!(shown.len(), 1);
+        assert_eq!(description, format!("{}\n— March 2018 · `{}`", first.text, first.id()));
+        // A first quote longer than the whole limit is cut short rather than dropped.
+        let (description, shown) = pack_quotes(&[first, second], 10, false);
+        assert_eq!(shown.len(), 1);
+        assert_eq!(description.chars().count(), 10);
+        assert!(description.ends_with('…'));
+    }
+
+    #[test]
+    fn messages_before_ready_are_not_handled() {
+        let mut data = ShareMap::custom();
+        assert!(read_invocation(&data, "[]says hello").is_err());
+        data.insert::<Prefix>(Prefix::build("[]", UserId(OWN_ID)));
+        assert!(read_invocation(&data, "[]says hello").is_err());
+        data.insert::<QuoteCacheKey>(empty_cache());
+        assert_eq!(read_invocation(&data, "[]says hello").ok(),
+                   Option::Some(Option::Some(("says".to_string(), Option::Some("hello".to_string())))));
+    }
+
+    #[test]
+    fn query_strings_are_not_read_as_labels() {
+        let sources = parse_sources("https://host/q.json?a=b", true).ok().unwrap();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].label, Option::None);
+        assert_eq!(sources[0].url.as_str(), "https://host/q.json?a=b");
+
+        let sources = parse_sources("main=https://host/q.json?a=b=3, local=quotes.json", true).ok().unwrap();
+        assert_eq!(sources[0].label, Option::Some("main".to_string()));
+        assert_eq!(sources[0].url.as_str(), "https://host/q.json?a=b");
+        assert_eq!(sources[0].weight, Option::Some(3));
+        assert_eq!(sources[1].label, Option::Some("local".to_string()));
+        assert_eq!(sources[1].url.scheme(), "file");
+    }
+
+    #[test]
+    fn unchanged_ready_keeps_the_prefix() {
+        let mut data = ShareMap::custom();
+        assert!(refresh_prefix(&mut data, "[]", UserId(OWN_ID)));
+        assert!(!refresh_prefix(&mut data, "[]", UserId(OWN_ID)));
+        assert!(refresh_prefix(&mut data, "!", UserId(OWN_ID)));
+        assert!(refresh_prefix(&mut data, "!", UserId(OWN_ID + 1)));
+        assert!(!refresh_prefix(&mut data, "!", UserId(OWN_ID + 1)));
+        assert!(data.get::<Prefix>().unwrap().regex.is_match("!says"));
+    }
+
+    // The command and non-blank query the prefix pattern reads from `content`.
+    fn invoked(prefix: &Prefix, content: &str) -> Option<(Option<String>, Option<String>)> {
+        prefix.regex.captures(content).map(|groups| (
+            groups.get(1).map(|m| m.as_str().to_string()),
+            groups.get(2).map(|m| m.as_str().trim().to_string()).filter(|query| !query.is_empty()),
+        ))
+    }
+
+    #[test]
+    fn prefix_pattern_forms() {
+        let prefix = Prefix::build("[]", UserId(OWN_ID));
+        assert_eq!(invoked(&prefix, "<@1>"), Option::Some((Option::None, Option::None)));
+        assert!(prefix.is_bare_mention("<@1>"));
+        assert!(prefix.is_bare_mention("<@!1>  "));
+        assert_eq!(invoked(&prefix, "[]says"), Option::Some((Option::Some("says".to_string()), Option::None)));
+        assert!(!prefix.is_bare_mention("[]says"));
+        assert_eq!(invoked(&prefix, "<@!1> socrates hemlock"),
+                   Option::Some((Option::None, Option::Some("socrates hemlock".to_string()))));
+        assert!(!prefix.is_bare_mention("<@1> socrates"));
+        assert_eq!(invoked(&prefix, "[]says march 2018"),
+                   Option::Some((Option::Some("says".to_string()), Option::Some("march 2018".to_string()))));
+        assert_eq!(invoked(&prefix, "<@2> socrates"), Option::None);
+        assert_eq!(invoked(&prefix, "says socrates"), Option::None);
+    }
+
+    #[test]
+    fn prefix_is_matched_literally() {
+        let prefix = Prefix::build("q.", UserId(OWN_ID));
+        assert!(prefix.regex.is_match("q.says"));
+        assert!(!prefix.regex.is_match("qxsays"));
+    }
+
+    #[test]
+    fn digits_group_in_threes() {
+        assert_eq!(group_digits(0, ","), "0");
+        assert_eq!(group_digits(999, ","), "999");
+        assert_eq!(group_digits(1000, ","), "1,000");
+        assert_eq!(group_digits(1234567, ","), "1,234,567");
+        assert_eq!(group_digits(1234567, "\u{202F}"), "1\u{202F}234\u{202F}567");
+    }
+
+    #[test]
+    fn plain_cards_append_the_footer_as_a_line() {
+        let mut card = Card::new(Option::Some("Quote 2018-3-3"), "Linked quote from March", 0);
+        card.footer = Option::Some("Arraying, March 2018");
+        card.image = Option::Some("https://example.com/image.png");
+        card.context = Option::Some("https://example.com/post/1");
+        assert_eq!(card.plain(), "**Quote 2018-3-3**\nLinked quote from March\nhttps://example.com/image.png\n\
+                                  Context: <https://example.com/post/1>\n_Arraying, March 2018_");
+
+        let mut card = Card::new(Option::None, "Socrates drank the hemlock", 0);
+        card.footer = Option::Some("");
+        assert_eq!(card.plain(), "Socrates drank the hemlock");
+    }
+
+    #[test]
+    fn long_plain_text_splits_at_line_breaks() {
+        assert_eq!(split_content("one\ntwo\nthree", 8), vec!["one\ntwo", "three"]);
+        assert_eq!(split_content("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(split_content("", 4), vec![""]);
+    }
+
+    #[test]
+    fn missing_permissions_are_permission_errors() {
+        let err = serenity::Error::Model(ModelError::InvalidPermissions(Permissions::EMBED_LINKS));
+        assert!(is_permission_error(&err));
+        assert!(!is_permission_error(&serenity::Error::Other("boom")));
+    }
+
+    #[test]
+    fn digest_sections_keep_their_order_and_never_repeat() {
+        let cache = fixture_cache();
+        let quotes = cache.cache.as_ref().unwrap();
+        let sections = [DigestSection::Longest, DigestSection::Shortest, DigestSection::Random(2),
+                        DigestSection::Recent(2)];
+        let digest = digest_quotes(quotes, &sections, &mut QuoteRng::new(Option::Some(7)));
+        assert_eq!(digest.iter().map(|&(section, _)| section).collect::<Vec<DigestSection>>(), sections.to_vec());
+        assert_eq!(digest.iter().map(|&(_, ref picked)| picked.len()).collect::<Vec<usize>>(), vec![1, 1, 2, 2]);
+        assert_eq!(digest[0].1[0].id(), "2017-1-1");
+        assert_eq!(digest[1].1[0].text, "March madness");
+        let mut ids: Vec<String> = digest.iter().flat_map(|&(_, ref picked)| picked.iter().map(|quote| quote.id()))
+            .collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 6);
+    }
+
+    #[test]
+    fn digest_sections_come_up_short_on_a_small_corpus() {
+        let cache = fixture_cache();
+        let quotes = cache.cache.as_ref().unwrap();
+        let sections = [DigestSection::Random(5), DigestSection::Recent(5), DigestSection::Longest];
+        let digest = digest_quotes(quotes, &sections, &mut QuoteRng::new(Option::Some(7)));
+        assert_eq!(digest.iter().map(|&(_, ref picked)| picked.len()).collect::<Vec<usize>>(), vec![5, 3, 0]);
+    }
+
+    #[test]
+    fn unmatched_dms_are_ignored_without_dm_help() {
+        let mut data = ShareMap::custom();
+        refresh_prefix(&mut data, "[]", UserId(OWN_ID));
+        data.insert::<QuoteCacheKey>(empty_cache());
+        assert_eq!(read_invocation(&data, "hello there").ok(), Option::Some(Option::None));
+        let quiet = Config { dm_help: false, ..default_config() };
+        assert!(unmatched_response(&quiet, true).is_none());
+        assert!(unmatched_response(&quiet, false).is_none());
+        // An explicit `help` is still its own command.
+        let help = read_invocation(&data, "[]help").ok().and_then(|invocation| invocation);
+        assert_eq!(help.map(|(command, _)| command), Option::Some("help".to_string()));
+
+        let chatty = default_config();
+        assert!(chatty.dm_help);
+        match unmatched_response(&chatty, true) {
+            Option::Some(CommandResponse::Help) => {},
+            _ => panic!("a DM gets the help text by default"),
+        }
+        assert!(unmatched_response(&chatty, false).is_none());
+    }
+
+    #[test]
+    fn since_responses() {
+        let cache = fixture_cache();
+        let config = default_config();
+        let enabled = HashSet::new();
+        match says(&cache, &config, &enabled, Option::Some("since march 2018")) {
+            CommandResponse::Pages(title, pages) => {
+                assert_eq!(title, "Since March 2018 — 5 quotes");
+                assert_eq!(pages.len(), 1);
+            },
+            _ => panic!("quotes from a month on should be listed"),
+        }
+        match says(&cache, &config, &enabled, Option::Some("since 2020")) {
+            CommandResponse::Text(text) => assert_eq!(text, "There are no quotes from 2020 on."),
+            _ => panic!("a year past the last quote should say so"),
+        }
+    }
+
+    // Ten random picks from the fixture for `guild`.
+    fn picks(data: &mut ShareMap, quotes: &[&Quote], guild: GuildId) -> Vec<String> {
+        let rng = selection_rng(data, Option::Some(guild));
+        let mut rng = rng.lock().unwrap();
+        (0..10).map(|_| select_random(quotes, &mut *rng, &[]).unwrap().id()).collect()
+    }
+
+    #[test]
+    fn seeds_pick_per_guild() {
+        let cache = fixture_cache();
+        let quotes: Vec<&Quote> = cache.cache.as_ref().unwrap().values()
+            .flat_map(|year| year.months.values())
+            .flat_map(|month| month.quotes.iter())
+            .collect();
+        let mut data = ShareMap::custom();
+        data.insert::<GuildSettings>(fresh_settings());
+        data.insert::<GuildRngs>(GuildRngs(HashMap::new()));
+        data.insert::<SelectionRng>(Arc::new(Mutex::new(QuoteRng::new(Option::None))));
+        let (first, second, third) = (GuildId(10), GuildId(11), GuildId(12));
+        apply_seed(&mut data, first, Option::Some(1));
+        apply_seed(&mut data, second, Option::Some(2));
+        apply_seed(&mut data, third, Option::Some(1));
+        let run = picks(&mut data, &quotes, first);
+        assert_ne!(run, picks(&mut data, &quotes, second));
+        assert_eq!(run, picks(&mut data, &quotes, third));
+        // Setting the seed again starts its run over.
+        apply_seed(&mut data, first, Option::Some(1));
+        assert_eq!(run, picks(&mut data, &quotes, first));
+
+        apply_seed(&mut data, first, Option::None);
+        let shared = data.get::<SelectionRng>().unwrap().clone();
+        assert!(Arc::ptr_eq(&selection_rng(&mut data, Option::Some(first)), &shared));
+        assert!(Arc::ptr_eq(&selection_rng(&mut data, Option::None), &shared));
+    }
+
+    #[test]
+    fn no_match_text_names_the_query() {
+        assert_eq!(fill_query(NO_MATCH_TEXT, "  Plato*_ ".trim()), "No quotes found for `Plato*_`.");
+        assert_eq!(fill_query(NO_MATCH_TEXT, "a `tick`"), "No quotes found for `a 'tick'`.");
+        assert_eq!(fill_query("Nothing for **{query}**.", "a*b"), "Nothing for **a\\*b**.");
+        let cache = fixture_cache();
+        match says(&cache, &default_config(), &HashSet::new(), Option::Some("  Zzyzx ")) {
+            CommandResponse::NoMatch(query) => assert_eq!(query, "Zzyzx"),
+            _ => panic!("no fixture quote comes near Zzyzx"),
+        }
+    }
+
+    #[test]
+    fn stale_quotes_are_noted_in_every_response() {
+        let cache = fixture_cache();
+        let config = Config { max_stale: Option::Some(Duration::from_secs(0)), ..default_config() };
+        let enabled = HashSet::new();
+        let stale = |note: Option<String>| note.map_or(false, |note| note.ends_with(STALE_NOTE));
+        match says(&cache, &config, &enabled, Option::Some("hemlock")) {
+            CommandResponse::Quote(_, note) => assert!(stale(note)),
+            _ => panic!("free text should find a quote"),
+        }
+        match says(&cache, &config, &enabled, Option::Some("batch 2 quote")) {
+            CommandResponse::Batch(_, _, note) => assert!(stale(note.clone()), "{:?}", note),
+            _ => panic!("a batch should be a batch"),
+        }
+        match says(&cache, &config, &enabled, Option::Some("spread 2")) {
+            CommandResponse::Batch(_, _, note) => assert!(stale(note)),
+            _ => panic!("a spread should be a batch"),
+        }
+        match says(&cache, &config, &enabled, Option::Some("list socrates")) {
+            CommandResponse::Listing(_, note) => assert!(stale(note)),
+            _ => panic!("a list should be a listing"),
+        }
+        match says(&cache, &config, &enabled, Option::Some("since 2018")) {
+            CommandResponse::Pages(title, _) => assert!(title.ends_with(&format!(" · {}", STALE_NOTE)), "{}", title),
+            _ => panic!("since should page its quotes"),
+        }
+        match says(&cache, &config, &enabled, Option::Some("/hemlock/ -> poison")) {
+            CommandResponse::Transformed(_, text, note) => {
+                assert_eq!(text, "poison");
+                assert!(stale(note));
+            },
+            _ => panic!("a regex with a template should transform"),
+        }
+        match says(&cache, &default_config(), &enabled, Option::Some("list socrates")) {
+            CommandResponse::Listing(_, note) => assert_eq!(note, Option::None),
+            _ => panic!("a list should be a listing"),
+        }
     }
 }
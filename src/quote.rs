@@ -0,0 +1,564 @@
+use cache::CacheRetrievalError;
+use ordermap::OrderMap;
+use reqwest;
+use search::normalize;
+use serde_json::{ self, Value as Json };
+use std::collections::HashSet;
+use std::io::BufRead;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Layout of the JSON served by a source.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SourceFormat {
+    /// Nested when the root is an object, a flat array when it's an array.
+    Auto,
+    Nested,
+    /// One array of `{ year, month, text }` objects.
+    Array,
+    Ndjson,
+}
+
+
+/// A quote source URL with an optional display label.
+#[derive(Clone, PartialEq)]
+pub struct Source {
+    pub label: Option<String>,
+    pub url: reqwest::Url,
+    /// Relative share of random picks when several sources are merged; unset counts as 1.
+    pub weight: Option<u32>,
+    /// A standby copy, e.g. a mirror or CDN, fetched only when `url` fails.
+    pub fallback: Option<reqwest::Url>,
+}
+
+impl Source {
+    /// The label if there is one, otherwise the URL.
+    pub fn name(&self) -> String {
+        self.label.clone().unwrap_or_else(|| self.url.to_string())
+    }
+}
+
+
+/// One year's quotes, keyed by month.
+pub struct QuoteYear {
+    pub months: OrderMap<String, QuoteMonth>,
+}
+
+/// One month's quotes, in source order.
+pub struct QuoteMonth {
+    pub quotes: Vec<Quote>,
+}
+
+/// A single quote and where it sits in the archive.
+#[derive(Clone)]
+pub struct Quote {
+    pub year: String,
+    /// The year key as the source wrote it, before digit normalization.
+    pub year_display: String,
+    pub month: String,
+    pub index: usize,
+    pub text: String,
+    pub image: Option<String>,
+    /// Where the quote was originally said, e.g. a forum post or commit; only from the object form's `url`.
+    pub url: Option<String>,
+    /// Label of the source this came from; only set when several sources are merged.
+    pub source: Option<String>,
+    /// Lowercased tags from the object form's `tags` array; empty for plain-string quotes.
+    pub tags: Vec<String>,
+}
+
+impl Quote {
+    /// The stable `YYYY-MM-N` id shown in embed footers.
+    pub fn id(&self) -> String {
+        format!("{}-{}-{}", self.year, self.month, self.index + 1)
+    }
+}
+
+/// Zero code points of the Unicode decimal digit blocks mapped to ASCII in year/month keys.
+const DIGIT_ZEROS: [u32; 24] = [
+    0x0660, 0x06F0, 0x07C0, 0x0966, 0x09E6, 0x0A66, 0x0AE6, 0x0B66, 0x0BE6, 0x0C66, 0x0CE6, 0x0D66,
+    0x0DE6, 0x0E50, 0x0ED0, 0x0F20, 0x1040, 0x1090, 0x17E0, 0x1810, 0x1946, 0x19D0, 0xA9D0, 0xFF10,
+];
+
+/// Trims `key` and maps every decimal digit, ASCII or otherwise, to its ASCII form.
+///
+/// Returns `None` if the key is empty or anything other than a digit remains.
+pub fn normalize_key(key: &str) -> Option<String> {
+    let key = key.trim();
+    if key.is_empty() {
+        return Option::None;
+    }
+    key.chars().map(|c| {
+        if c.is_ascii_digit() {
+            return Option::Some(c);
+        }
+        let code = c as u32;
+        DIGIT_ZEROS.iter()
+            .find(|&&zero| code >= zero && code < zero + 10)
+            .and_then(|&zero| ::std::char::from_digit(code - zero, 10))
+    }).collect()
+}
+
+fn log_invalid_key(kind: &str, key: &str) {
+    log_event!(Warn, "invalid_key", format!("Skipped {} key {:?}: not numeric", kind, key),
+               kind = kind, key = key);
+}
+
+/// What a source parsed into.
+pub struct ParsedQuotes {
+    pub quotes: OrderMap<String, QuoteYear>,
+    pub quote_count: usize,
+    /// Entries left out because they couldn't be read as quotes; the rest of the source still loads.
+    pub skipped: usize,
+}
+
+fn log_skipped(skipped: usize, what: &str) {
+    if skipped > 0 {
+        log_event!(Warn, "parse_skipped", format!("Skipped {} malformed {}", skipped, what), skipped = skipped);
+    }
+}
+
+/// Parses a source in whichever layout its root suggests: an object is read by `parse_quotes_nested`, an array by
+/// `parse_quotes_array`.
+///
+/// Fails if the root is neither.
+pub fn parse_quotes(json: Json) -> Result<ParsedQuotes, CacheRetrievalError> {
+    if json.is_array() {
+        log_event!(Info, "format_detected", "Source root is an array; reading it as a flat list",
+                   format = "array");
+        parse_quotes_array(json)
+    } else if json.is_object() {
+        log_event!(Info, "format_detected", "Source root is an object; reading it as years and months",
+                   format = "nested");
+        parse_quotes_nested(json)
+    } else {
+        Result::Err(CacheRetrievalError("Source root is neither an object nor an array".to_string()))
+    }
+}
+
+/// Parses the nested `{ year: { month: [quotes] } }` layout, skipping entries that aren't a string or an object
+/// with `text`, and years and months that aren't objects and arrays.
+///
+/// Fails if the root isn't an object.
+pub fn parse_quotes_nested(years_dto: Json) -> Result<ParsedQuotes, CacheRetrievalError> {
+    if let Json::Object(years_map) = years_dto {
+        let mut years: OrderMap<String, QuoteYear> = OrderMap::new();
+        let mut quote_count: usize = 0;
+        let mut skipped: usize = 0;
+        for (year_display, months_dto) in years_map {
+            let year_key = match normalize_key(&year_display) {
+                Option::Some(key) => key,
+                Option::None => {
+                    log_invalid_key("year", &year_display);
+                    continue;
+                },
+            };
+            if let Json::Object(months_map) = months_dto {
+                let mut months: OrderMap<String, QuoteMonth> = OrderMap::new();
+                for (month_display, quotes_dto) in months_map {
+                    let month_key = match normalize_key(&month_display) {
+                        Option::Some(key) => key,
+                        Option::None => {
+                            log_invalid_key("month", &month_display);
+                            continue;
+                        },
+                    };
+                    if let Json::Array(quotes_vec) = quotes_dto {
+                        let mut quotes: Vec<Quote> = Vec::with_capacity(quotes_vec.len());
+                        for (index, quote_dto) in quotes_vec.into_iter().enumerate() {
+                            match quote_dto {
+                                Json::String(quote) => quotes.push(Quote {
+                                    year: year_key.clone(),
+                                    year_display: year_display.clone(),
+                                    month: month_key.clone(),
+                                    index,
+                                    text: quote,
+                                    image: Option::None,
+                                    url: Option::None,
+                                    source: Option::None,
+                                    tags: Vec::new(),
+                                }),
+                                Json::Object(mut quote_dto) => if let Option::Some(quote) = quote_from_object(
+                                    year_key.clone(), year_display.clone(), month_key.clone(), index,
+                                    &mut quote_dto) {
+                                    quotes.push(quote);
+                                } else {
+                                    skipped += 1;
+                                },
+                                _ => skipped += 1,
+                            }
+                        }
+                        quote_count += quotes.len();
+                        merge_month(&mut months, month_key, quotes);
+                    } else {
+                        skipped += 1;
+                    }
+                }
+                // Keys such as "2019" and "٢٠١٩" normalize to the same year, so merge rather than replace.
+                let year = years.entry(year_key).or_insert_with(|| QuoteYear { months: OrderMap::new() });
+                for (month_key, month) in months {
+                    merge_month(&mut year.months, month_key, month.quotes);
+                }
+            } else {
+                skipped += 1;
+            }
+        }
+        log_skipped(skipped, "entries");
+        return Result::Ok(ParsedQuotes { quotes: years, quote_count, skipped });
+    }
+    Result::Err(CacheRetrievalError("Source root isn't an object".to_string()))
+}
+
+/// Serializes quotes back into the nested layout `parse_quotes` reads.
+///
+/// Quotes with an image, url or tags become `{ text, image, url, tags }` objects; everything else stays a plain
+/// string.
+pub fn export_quotes(years: &OrderMap<String, QuoteYear>) -> Json {
+    let mut years_map = serde_json::Map::new();
+    for (year_key, year) in years {
+        let mut months_map = serde_json::Map::new();
+        for (month_key, month) in &year.months {
+            let quotes = month.quotes.iter()
+                .map(|quote| {
+                    if quote.image.is_none() && quote.url.is_none() && quote.tags.is_empty() {
+                        return Json::from(quote.text.as_str());
+                    }
+                    let mut quote_map = serde_json::Map::new();
+                    quote_map.insert("text".to_string(), Json::from(quote.text.as_str()));
+                    if let Option::Some(ref image) = quote.image {
+                        quote_map.insert("image".to_string(), Json::from(image.as_str()));
+                    }
+                    if let Option::Some(ref url) = quote.url {
+                        quote_map.insert("url".to_string(), Json::from(url.as_str()));
+                    }
+                    if !quote.tags.is_empty() {
+                        quote_map.insert("tags".to_string(), Json::from(quote.tags.clone()));
+                    }
+                    Json::Object(quote_map)
+                })
+                .collect();
+            months_map.insert(month_key.clone(), Json::Array(quotes));
+        }
+        years_map.insert(year_key.clone(), Json::Object(months_map));
+    }
+    Json::Object(years_map)
+}
+
+/// Drops quotes whose normalized text was already seen, returning how many were removed.
+pub fn dedup_quotes(years: &mut OrderMap<String, QuoteYear>) -> usize {
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut removed: usize = 0;
+    for (_, year) in years.iter_mut() {
+        for (_, month) in year.months.iter_mut() {
+            let before = month.quotes.len();
+            month.quotes.retain(|quote| seen.insert(normalize(&quote.text)));
+            removed += before - month.quotes.len();
+        }
+        year.months.retain(|_, month| !month.quotes.is_empty());
+    }
+    years.retain(|_, year| !year.months.is_empty());
+    removed
+}
+
+// An `http`/`https` URL from an object-form field, or `None` (with a warning naming `field`) for anything else.
+fn web_url(value: Option<&Json>, field: &str) -> Option<String> {
+    if let Option::Some(&Json::String(ref url)) = value {
+        match reqwest::Url::parse(url) {
+            Result::Ok(ref parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" =>
+                return Option::Some(url.clone()),
+            _ => log_event!(Warn, "invalid_media_url", format!("Ignoring invalid {} URL: {}", field, url),
+                            field = field, url = url.as_str()),
+        }
+    }
+    Option::None
+}
+
+/// Appends `quotes` to the month at `month_key`, shifting their indices past any already there.
+fn merge_month(months: &mut OrderMap<String, QuoteMonth>, month_key: String, quotes: Vec<Quote>) {
+    let merged = &mut months.entry(month_key).or_insert_with(|| QuoteMonth { quotes: Vec::new() }).quotes;
+    let offset = merged.last().map_or(0, |quote| quote.index + 1);
+    for mut quote in quotes {
+        quote.index += offset;
+        merged.push(quote);
+    }
+}
+
+fn quote_tags(value: Option<&Json>) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    if let Option::Some(&Json::Array(ref values)) = value {
+        for tag in values.iter().filter_map(Json::as_str) {
+            let tag = tag.trim().trim_left_matches('#').to_lowercase();
+            if !tag.is_empty() && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+    }
+    tags
+}
+
+fn quote_from_object(year: String, year_display: String, month: String, index: usize,
+                     quote_dto: &mut serde_json::Map<String, Json>) -> Option<Quote> {
+    if let Option::Some(Json::String(text)) = quote_dto.remove("text") {
+        Option::Some(Quote {
+            year,
+            year_display,
+            month,
+            index,
+            text,
+            image: web_url(quote_dto.get("image"), "image"),
+            url: web_url(quote_dto.get("url"), "url"),
+            source: Option::None,
+            tags: quote_tags(quote_dto.get("tags")),
+        })
+    } else {
+        Option::None
+    }
+}
+
+/// Shortens `text` to at most `limit` chars, ending with an ellipsis.
+///
+/// Cuts only between grapheme clusters, so emoji sequences and combining marks are never split.
+pub fn truncate(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let mut truncated = String::new();
+    let mut length = 0;
+    for grapheme in text.graphemes(true) {
+        let grapheme_length = grapheme.chars().count();
+        if length + grapheme_length + 1 > limit {
+            break;
+        }
+        truncated.push_str(grapheme);
+        length += grapheme_length;
+    }
+    truncated.push('…');
+    truncated
+}
+
+fn json_key(value: Option<&Json>) -> Option<String> {
+    match value {
+        Option::Some(&Json::String(ref key)) => Option::Some(key.clone()),
+        Option::Some(&Json::Number(ref key)) => Option::Some(key.to_string()),
+        _ => Option::None,
+    }
+}
+
+// Files one flat `{ year, month, text }` object under its year and month, returning whether it was a valid quote.
+// The quote is read before anything is filed, so an invalid entry never leaves an empty year or month behind.
+fn add_flat_quote(years: &mut OrderMap<String, QuoteYear>, mut quote_dto: serde_json::Map<String, Json>) -> bool {
+    let year_display = json_key(quote_dto.get("year"));
+    let year_key = year_display.as_ref().and_then(|key| normalize_key(key));
+    let month_key = json_key(quote_dto.get("month")).and_then(|key| normalize_key(&key));
+    let (year_display, year_key, month_key) = match (year_display, year_key, month_key) {
+        (Option::Some(year_display), Option::Some(year_key), Option::Some(month_key)) =>
+            (year_display, year_key, month_key),
+        _ => return false,
+    };
+    let mut quote = match quote_from_object(year_key.clone(), year_display, month_key.clone(), 0, &mut quote_dto) {
+        Option::Some(quote) => quote,
+        Option::None => return false,
+    };
+    let month = years.entry(year_key)
+        .or_insert_with(|| QuoteYear { months: OrderMap::new() })
+        .months.entry(month_key)
+        .or_insert_with(|| QuoteMonth { quotes: Vec::new() });
+    quote.index = month.quotes.len();
+    month.quotes.push(quote);
+    true
+}
+
+/// Parses a flat array of `{ year, month, text }` objects, skipping anything else.
+///
+/// Fails if the root isn't an array.
+pub fn parse_quotes_array(quotes_dto: Json) -> Result<ParsedQuotes, CacheRetrievalError> {
+    if let Json::Array(quotes_vec) = quotes_dto {
+        let mut years: OrderMap<String, QuoteYear> = OrderMap::new();
+        let mut quote_count: usize = 0;
+        let mut skipped: usize = 0;
+        for quote_dto in quotes_vec {
+            match quote_dto {
+                Json::Object(quote_dto) => if add_flat_quote(&mut years, quote_dto) {
+                    quote_count += 1;
+                } else {
+                    skipped += 1;
+                },
+                _ => skipped += 1,
+            }
+        }
+        log_skipped(skipped, "entries");
+        return Result::Ok(ParsedQuotes { quotes: years, quote_count, skipped });
+    }
+    Result::Err(CacheRetrievalError("Source root isn't an array".to_string()))
+}
+
+/// Parses one `{ year, month, text }` object per line, skipping malformed lines.
+pub fn parse_quotes_ndjson<R: BufRead>(reader: R) -> Result<ParsedQuotes, CacheRetrievalError> {
+    let mut years: OrderMap<String, QuoteYear> = OrderMap::new();
+    let mut quote_count: usize = 0;
+    let mut skipped: usize = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Json>(&line) {
+            Result::Ok(Json::Object(quote_dto)) => if add_flat_quote(&mut years, quote_dto) {
+                quote_count += 1;
+            } else {
+                skipped += 1;
+            },
+            _ => skipped += 1,
+        }
+    }
+    log_skipped(skipped, "ndjson lines");
+    Result::Ok(ParsedQuotes { quotes: years, quote_count, skipped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(quotes: &OrderMap<String, QuoteYear>) -> Vec<&str> {
+        quotes.values()
+            .flat_map(|year| year.months.values())
+            .flat_map(|month| month.quotes.iter())
+            .map(|quote| quote.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn keys_normalize_to_ascii_digits() {
+        assert_eq!(normalize_key("2019"), Option::Some("2019".to_string()));
+        assert_eq!(normalize_key(" \u{FF12}\u{FF10}\u{FF11}\u{FF19} "), Option::Some("2019".to_string()));
+        assert_eq!(normalize_key("\u{0662}\u{0660}\u{0661}\u{0669}"), Option::Some("2019".to_string()));
+        assert_eq!(normalize_key("\u{0968}\u{0966}\u{0967}\u{096F}"), Option::Some("2019".to_string()));
+        assert_eq!(normalize_key("2\u{FF10}1\u{0669}"), Option::Some("2019".to_string()));
+        assert_eq!(normalize_key("20a9"), Option::None);
+        assert_eq!(normalize_key("\u{00B2}"), Option::None);
+        assert_eq!(normalize_key("  "), Option::None);
+    }
+
+    #[test]
+    fn locale_digit_years_merge_and_keep_their_display_form() {
+        let parsed = parse_quotes_nested(serde_json::from_str(r#"{
+            "2019": { "1": ["ascii"] },
+            "\uFF12\uFF10\uFF11\uFF19": { "\uFF11": ["fullwidth"] },
+            "year": { "1": ["not a number"] }
+        }"#).unwrap()).unwrap();
+        assert_eq!(parsed.quotes.len(), 1);
+        let january = &parsed.quotes["2019"].months["1"].quotes;
+        assert_eq!(january.iter().map(|quote| quote.text.as_str()).collect::<Vec<&str>>(), vec!["ascii", "fullwidth"]);
+        assert_eq!(january[1].year_display, "\u{FF12}\u{FF10}\u{FF11}\u{FF19}");
+        assert_eq!(january[1].id(), "2019-1-2");
+    }
+
+    #[test]
+    fn dedup_keeps_the_first_copy() {
+        let mut quotes = parse_quotes_nested(serde_json::from_str(r#"{
+            "2017": { "1": ["Hello there", "General Kenobi"] },
+            "2018": { "2": ["hello   THERE", "something new"], "3": ["General Kenobi"] }
+        }"#).unwrap()).unwrap().quotes;
+        assert_eq!(dedup_quotes(&mut quotes), 2);
+        assert_eq!(texts(&quotes), vec!["Hello there", "General Kenobi", "something new"]);
+        assert_eq!(quotes["2017"].months["1"].quotes.len(), 2);
+        assert!(quotes["2018"].months.get("3").is_none());
+    }
+
+    #[test]
+    fn truncate_keeps_short_text() {
+        assert_eq!(truncate("short", 5), "short");
+        assert_eq!(truncate("a longer text", 6), "a lon…");
+    }
+
+    #[test]
+    fn truncate_never_splits_a_grapheme() {
+        // "e" plus a combining acute accent is two chars but one grapheme.
+        assert_eq!(truncate("abe\u{301}cd", 4), "ab…");
+        assert_eq!(truncate("abe\u{301}cd", 5), "abe\u{301}…");
+        // A family emoji is five chars joined by zero-width joiners.
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(truncate(&format!("hi {} there", family), 6), "hi …");
+        assert_eq!(truncate(&format!("hi {} there", family), 9), format!("hi {}…", family));
+        assert_eq!(truncate("\u{1F44D}\u{1F3FD} ok", 2), "…");
+    }
+
+    #[test]
+    fn array_skips_corrupt_entries() {
+        let parsed = parse_quotes_array(serde_json::from_str(r#"[
+            { "year": 2018, "month": 3, "text": "first" },
+            { "year": 2018, "month": 4, "text": 17 },
+            { "year": "twenty", "month": 5, "text": "bad year" },
+            "not an object",
+            { "year": 2019, "month": 1, "text": "second" }
+        ]"#).unwrap()).unwrap();
+        assert_eq!(parsed.quote_count, 2);
+        assert_eq!(parsed.skipped, 3);
+        assert_eq!(texts(&parsed.quotes), vec!["first", "second"]);
+        assert!(parsed.quotes["2018"].months.get("4").is_none());
+        assert_eq!(parsed.quotes.len(), 2);
+    }
+
+    #[test]
+    fn export_keeps_urls() {
+        let parsed = parse_quotes_nested(serde_json::from_str(include_str!("../tests/fixtures/quotes.json")).unwrap())
+            .unwrap();
+        let exported = export_quotes(&parsed.quotes);
+        let linked: Json = serde_json::from_str(r#"{ "text": "Linked quote from March",
+                                                    "url": "https://example.com/post/1" }"#).unwrap();
+        assert_eq!(exported["2018"]["3"][2], linked);
+        assert_eq!(exported["2018"]["3"][0], Json::from("Socrates drank the hemlock"));
+        let reparsed = parse_quotes_nested(exported).unwrap();
+        assert_eq!(reparsed.quote_count, parsed.quote_count);
+        assert_eq!(reparsed.quotes["2018"].months["3"].quotes[2].url,
+                   Option::Some("https://example.com/post/1".to_string()));
+    }
+
+    #[test]
+    fn detects_the_nested_shape() {
+        let parsed = parse_quotes(serde_json::from_str(r#"{ "2018": { "3": ["nested", { "text": "object" }] } }"#)
+            .unwrap()).unwrap();
+        assert_eq!(parsed.quote_count, 2);
+        assert_eq!(texts(&parsed.quotes), vec!["nested", "object"]);
+    }
+
+    #[test]
+    fn detects_the_array_shape() {
+        let parsed = parse_quotes(serde_json::from_str(r#"[{ "year": 2018, "month": 3, "text": "flat" }]"#)
+            .unwrap()).unwrap();
+        assert_eq!(parsed.quote_count, 1);
+        assert_eq!(parsed.quotes["2018"].months["3"].quotes[0].id(), "2018-3-1");
+    }
+
+    #[test]
+    fn other_roots_are_errors() {
+        for root in &["\"quotes\"", "17", "null"] {
+            match parse_quotes(serde_json::from_str(root).unwrap()) {
+                Result::Err(CacheRetrievalError(err)) => assert!(err.contains("neither"), "{}", err),
+                Result::Ok(_) => panic!("{} shouldn't parse as quotes", root),
+            }
+        }
+    }
+
+    #[test]
+    fn layouts_reject_the_other_root() {
+        match parse_quotes_nested(serde_json::from_str("[]").unwrap()) {
+            Result::Err(CacheRetrievalError(err)) => assert_eq!(err, "Source root isn't an object"),
+            Result::Ok(_) => panic!("an array shouldn't parse as nested quotes"),
+        }
+        match parse_quotes_array(serde_json::from_str("{}").unwrap()) {
+            Result::Err(CacheRetrievalError(err)) => assert_eq!(err, "Source root isn't an array"),
+            Result::Ok(_) => panic!("an object shouldn't parse as a flat list"),
+        }
+    }
+
+    #[test]
+    fn nested_skips_years_that_arent_objects() {
+        let parsed = parse_quotes_nested(serde_json::from_str(r#"{
+            "2017": ["not", "months"],
+            "2018": "nothing",
+            "2019": { "1": ["kept"] }
+        }"#).unwrap()).unwrap();
+        assert_eq!(parsed.skipped, 2);
+        assert_eq!(texts(&parsed.quotes), vec!["kept"]);
+    }
+}
@@ -0,0 +1,1322 @@
+use ordermap::OrderMap;
+use quote::{ Quote, QuoteMonth, QuoteYear };
+use rand::{ self, Rng, SeedableRng, StdRng };
+use regex::{ self, Captures, Regex, RegexBuilder };
+use std::collections::HashSet;
+use std::time::{ Duration, Instant };
+use sublime_fuzzy::best_match;
+
+const REGEX_SIZE_LIMIT: usize = 1 << 16;
+/// How many quotes a single regex query may scan before it settles for what it has found.
+pub const REGEX_SCAN_LIMIT: usize = 50_000;
+/// How long, in milliseconds, a single regex query may spend matching before it settles for what it has found.
+pub const REGEX_TIME_BUDGET_MS: u64 = 250;
+/// The largest edit distance `FuzzyDistance::Fixed` accepts; beyond this nearly every word matches.
+pub const MAX_FUZZY_DISTANCE: usize = 3;
+
+/// The RNG behind every random pick.
+///
+/// Seeding makes the sequence of picks reproducible across replicas; it is not meant to be unpredictable.
+pub enum QuoteRng {
+    Thread,
+    Seeded(StdRng),
+}
+
+impl QuoteRng {
+    /// Uses `thread_rng` when `seed` is `None`, otherwise a deterministic generator.
+    pub fn new(seed: Option<u64>) -> QuoteRng {
+        match seed {
+            Option::Some(seed) => QuoteRng::Seeded(StdRng::from_seed(&[(seed >> 32) as usize, seed as usize][..])),
+            Option::None => QuoteRng::Thread,
+        }
+    }
+}
+
+impl Rng for QuoteRng {
+    fn next_u32(&mut self) -> u32 {
+        match *self {
+            QuoteRng::Thread => rand::thread_rng().next_u32(),
+            QuoteRng::Seeded(ref mut rng) => rng.next_u32(),
+        }
+    }
+    fn next_u64(&mut self) -> u64 {
+        match *self {
+            QuoteRng::Thread => rand::thread_rng().next_u64(),
+            QuoteRng::Seeded(ref mut rng) => rng.next_u64(),
+        }
+    }
+}
+
+/// Looks a quote up by its `YYYY-MM-N` id.
+pub fn find_quote<'a>(quotes: &'a OrderMap<String, QuoteYear>, id: &str) -> Option<&'a Quote> {
+    quotes.values()
+        .flat_map(|year| year.months.values())
+        .flat_map(|month| month.quotes.iter())
+        .find(|quote| quote.id() == id)
+}
+
+/// How quotes are lined up when walking through them in order.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QuoteOrder {
+    /// Years and months sorted numerically, then the quotes within each month in source order.
+    Chronological,
+    /// Exactly as the source listed them.
+    Insertion,
+}
+
+impl QuoteOrder {
+    /// Parses `chronological` or `insertion`.
+    pub fn parse(name: &str) -> Option<QuoteOrder> {
+        match name.trim().to_lowercase().as_str() {
+            "chronological" => Option::Some(QuoteOrder::Chronological),
+            "insertion" => Option::Some(QuoteOrder::Insertion),
+            _ => Option::None,
+        }
+    }
+}
+
+// Sorts numeric keys by value, leaving any non-numeric ones after them in their original order.
+fn sorted_keys<V>(map: &OrderMap<String, V>, order: QuoteOrder) -> Vec<(&String, &V)> {
+    let mut entries: Vec<(&String, &V)> = map.iter().collect();
+    if order == QuoteOrder::Chronological {
+        entries.sort_by_key(|&(key, _)| key.parse::<u64>().unwrap_or(u64::max_value()));
+    }
+    entries
+}
+
+/// Every quote, lined up in `order`.
+pub fn ordered_quotes(quotes: &OrderMap<String, QuoteYear>, order: QuoteOrder) -> Vec<&Quote> {
+    let mut ordered: Vec<&Quote> = Vec::new();
+    for (_, year) in sorted_keys(quotes, order) {
+        for (_, month) in sorted_keys(&year.months, order) {
+            ordered.extend(month.quotes.iter());
+        }
+    }
+    ordered
+}
+
+/// Where stepping from one quote to its neighbour lands.
+pub enum Step<'a> {
+    /// The neighbour, its position in the order counting from 0, and how many quotes there are.
+    Moved(&'a Quote, usize, usize),
+    /// The quote is the first or last one and wrapping is off.
+    Edge,
+    /// No quote has the id.
+    Unknown,
+}
+
+/// Steps from the quote with `id` to the one after it (or before, unless `forward`) in `order`,
+/// running on from the last quote to the first and back if `wrap` is set.
+pub fn step_quote<'a>(quotes: &'a OrderMap<String, QuoteYear>, id: &str, order: QuoteOrder, forward: bool,
+                      wrap: bool) -> Step<'a> {
+    let flat = ordered_quotes(quotes, order);
+    let position = match flat.iter().position(|quote| quote.id() == id) {
+        Option::Some(position) => position,
+        Option::None => return Step::Unknown,
+    };
+    let total = flat.len();
+    let next = match (forward, position) {
+        (true, position) if position + 1 < total => position + 1,
+        (false, position) if position > 0 => position - 1,
+        _ if !wrap || total == 1 => return Step::Edge,
+        (true, _) => 0,
+        (false, _) => total - 1,
+    };
+    Step::Moved(flat[next], next, total)
+}
+
+/// The quote with `id` along with the month it was filed in.
+pub fn quote_month<'a>(quotes: &'a OrderMap<String, QuoteYear>, id: &str) -> Option<(&'a Quote, &'a QuoteMonth)> {
+    let quote = find_quote(quotes, id)?;
+    let month = quotes.get(&quote.year)?.months.get(&quote.month)?;
+    Option::Some((quote, month))
+}
+
+fn flatten(quotes: &OrderMap<String, QuoteYear>) -> Vec<&Quote> {
+    quotes.values()
+        .flat_map(|year| year.months.values())
+        .flat_map(|month| month.quotes.iter())
+        .collect()
+}
+
+/// Lowercased alphanumeric words in `text`.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// If `query` starts with `keyword` and has more after it, returns the rest.
+pub fn strip_keyword<'a>(query: &'a str, keyword: &str) -> Option<&'a str> {
+    let mut parts = query.splitn(2, char::is_whitespace);
+    match (parts.next(), parts.next()) {
+        (Option::Some(word), Option::Some(rest)) if word.eq_ignore_ascii_case(keyword)
+            && !rest.trim().is_empty() => Option::Some(rest.trim()),
+        _ => Option::None,
+    }
+}
+
+fn year_order(quote: &Quote) -> u64 {
+    quote.year.parse::<u64>().unwrap_or(u64::max_value())
+}
+
+/// The quote containing every term the most times, preferring older quotes on ties.
+pub fn best_quote<'a>(quotes: &'a OrderMap<String, QuoteYear>, terms: &[String]) -> Option<&'a Quote> {
+    if terms.is_empty() {
+        return Option::None;
+    }
+    let mut best: Option<(&Quote, usize)> = Option::None;
+    for (_, year) in quotes {
+        for (_, month) in &year.months {
+            for quote in &month.quotes {
+                let tokens = tokenize(&quote.text);
+                if !terms.iter().all(|term| tokens.contains(term)) {
+                    continue;
+                }
+                let score = terms.iter()
+                    .map(|term| tokens.iter().filter(|token| *token == term).count())
+                    .sum::<usize>();
+                let better = match best {
+                    Option::Some((current, current_score)) => score > current_score
+                        || (score == current_score && year_order(quote) < year_order(current)),
+                    Option::None => true,
+                };
+                if better {
+                    best = Option::Some((quote, score));
+                }
+            }
+        }
+    }
+    best.map(|(quote, _)| quote)
+}
+
+/// The quote that best fuzzy-matches `query`, if any match at all.
+pub fn fuzzy_quote<'a>(quotes: &'a OrderMap<String, QuoteYear>, query: &str) -> Option<&'a Quote> {
+    let query = query.to_lowercase();
+    let mut best: Option<&Quote> = Option::None;
+    let mut best_score: isize = 0;
+    for (_, year) in quotes {
+        for (_, month) in &year.months {
+            for quote in &month.quotes {
+                if let Option::Some(f_match) = best_match(&query, &quote.text.to_lowercase()) {
+                    if f_match.score() != 0
+                        && (!best.is_some() || f_match.score() > best_score) {
+                        best = Option::Some(quote);
+                        best_score = f_match.score();
+                    }
+                }
+            }
+        }
+    }
+    best
+}
+
+/// How many typos a query word may contain and still match a word in a quote.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FuzzyDistance {
+    /// 1 for words of up to four characters, 2 for longer ones.
+    Scaled,
+    Fixed(usize),
+}
+
+impl FuzzyDistance {
+    /// The edit distance allowed for `term`.
+    pub fn for_term(&self, term: &str) -> usize {
+        match *self {
+            FuzzyDistance::Scaled => if term.chars().count() <= 4 { 1 } else { 2 },
+            FuzzyDistance::Fixed(distance) => distance,
+        }
+    }
+}
+
+/// Levenshtein distance between two strings, counted in chars.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, &b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == b_char { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// Like `edit_distance`, but swapping two neighbouring chars counts as one edit, so `marhc` is one away from `march`.
+fn transposition_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut rows = vec![(0..b.len() + 1).collect::<Vec<usize>>()];
+    for i in 0..a.len() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for j in 0..b.len() {
+            let substitution = rows[i][j] + if a[i] == b[j] { 0 } else { 1 };
+            current[j + 1] = substitution.min(rows[i][j + 1] + 1).min(current[j] + 1);
+            if i > 0 && j > 0 && a[i] == b[j - 1] && a[i - 1] == b[j] {
+                current[j + 1] = current[j + 1].min(rows[i - 1][j - 1] + 1);
+            }
+        }
+        rows.push(current);
+    }
+    rows[a.len()][b.len()]
+}
+
+/// The keyword `word` is a near miss for: the only one of `keywords` within a single edit, ignoring case.
+///
+/// Exact matches and words under four chars are left alone, since a short word is one edit from too many others.
+pub fn correct_keyword(word: &str, keywords: &[&'static str]) -> Option<&'static str> {
+    let word = word.to_lowercase();
+    if word.chars().count() < 4 || keywords.iter().any(|&keyword| keyword == word) {
+        return Option::None;
+    }
+    let mut near = keywords.iter().filter(|&&keyword| transposition_distance(&word, keyword) == 1);
+    match (near.next(), near.next()) {
+        (Option::Some(&keyword), Option::None) => Option::Some(keyword),
+        _ => Option::None,
+    }
+}
+
+/// The quote where every query word is within its allowed edit distance of some word, with the fewest typos overall.
+pub fn typo_quote<'a>(quotes: &'a OrderMap<String, QuoteYear>, query: &str, distance: FuzzyDistance)
+    -> Option<&'a Quote> {
+    let terms: Vec<(String, usize)> = tokenize(query).into_iter()
+        .map(|term| {
+            let allowed = distance.for_term(&term);
+            (term, allowed)
+        })
+        .collect();
+    let length = |word: &str| word.chars().count() as isize;
+    if terms.is_empty() {
+        return Option::None;
+    }
+    for &(ref term, allowed) in &terms {
+        log_event!(Debug, "fuzzy_distance", format!("Matching `{}` within edit distance {}", term, allowed),
+                   term = term.as_str(), distance = allowed);
+    }
+    let mut best: Option<(&Quote, usize)> = Option::None;
+    for (_, year) in quotes {
+        for (_, month) in &year.months {
+            for quote in &month.quotes {
+                let tokens = tokenize(&quote.text);
+                let mut total = 0;
+                let matched = terms.iter().all(|&(ref term, allowed)| {
+                    let closest = tokens.iter()
+                        .filter(|token| (length(token) - length(term)).abs() as usize <= allowed)
+                        .map(|token| edit_distance(term, token))
+                        .min();
+                    match closest {
+                        Option::Some(found) if found <= allowed => {
+                            total += found;
+                            true
+                        },
+                        _ => false,
+                    }
+                });
+                if matched && best.map_or(true, |(_, best_total)| total < best_total) {
+                    best = Option::Some((quote, total));
+                }
+            }
+        }
+    }
+    best.map(|(quote, _)| quote)
+}
+
+/// Lowercases and collapses whitespace, for comparing quote text.
+pub fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase()
+}
+
+/// Every quote whose normalized text equals `text`.
+pub fn exact_quotes<'a>(quotes: &'a OrderMap<String, QuoteYear>, text: &str) -> Vec<&'a Quote> {
+    let text = normalize(text);
+    let mut matches: Vec<&Quote> = Vec::new();
+    for (_, year) in quotes {
+        for (_, month) in &year.months {
+            for quote in &month.quotes {
+                if normalize(&quote.text) == text {
+                    matches.push(quote);
+                }
+            }
+        }
+    }
+    matches
+}
+
+/// Parses a decade like `2010s` into its first year.
+pub fn parse_decade(query: &str) -> Option<u32> {
+    let query = query.to_lowercase();
+    if query.len() == 5 && query.ends_with("0s") {
+        query[..4].parse::<u32>().ok()
+    } else {
+        Option::None
+    }
+}
+
+/// Parses an inclusive length range like `50-100` into its bounds as written; it doesn't check their order.
+pub fn parse_length_range(query: &str) -> Option<(usize, usize)> {
+    let mut bounds = query.splitn(2, '-').map(|bound| bound.trim().parse::<usize>());
+    match (bounds.next(), bounds.next()) {
+        (Option::Some(Result::Ok(min)), Option::Some(Result::Ok(max))) => Option::Some((min, max)),
+        _ => Option::None,
+    }
+}
+
+/// Every quote whose text is between `min` and `max` chars long, inclusive.
+pub fn length_quotes(quotes: &OrderMap<String, QuoteYear>, min: usize, max: usize) -> Vec<&Quote> {
+    quotes.values()
+        .flat_map(|year| year.months.values())
+        .flat_map(|month| month.quotes.iter())
+        .filter(|quote| {
+            let length = quote.text.chars().count();
+            length >= min && length <= max
+        })
+        .collect()
+}
+
+/// The longest quotes by character count, or the shortest if `longest` is false. More than one when they tie,
+/// and none for an empty corpus.
+pub fn extreme_quotes(quotes: &OrderMap<String, QuoteYear>, longest: bool) -> Vec<&Quote> {
+    let mut extremes: Vec<&Quote> = Vec::new();
+    let mut extreme = 0;
+    for quote in quotes.values().flat_map(|year| year.months.values()).flat_map(|month| month.quotes.iter()) {
+        let length = quote.text.chars().count();
+        if extremes.is_empty() || (longest && length > extreme) || (!longest && length < extreme) {
+            extremes.clear();
+            extreme = length;
+        }
+        if length == extreme {
+            extremes.push(quote);
+        }
+    }
+    extremes
+}
+
+const MONTH_NAMES: [&str; 12] = ["january", "february", "march", "april", "may", "june", "july", "august",
+    "september", "october", "november", "december"];
+
+// What may sit between the parts of a date: `2018-03`, `03/2018`, `2018.03` and `March, 2018` all split apart.
+fn is_date_separator(c: char) -> bool {
+    c.is_whitespace() || c == '-' || c == '/' || c == '.' || c == ','
+}
+
+/// Parses a partial date such as `March`, `2018`, `mar 2018`, `2018-03` or `03/2018` into `(year, month)`.
+///
+/// Every word must be a date word or this returns `None`. A bare number is a year if it has four digits
+/// and a month (1 to 12) if it has one or two; month names may be abbreviated to three letters.
+pub fn parse_date_query(query: &str) -> Option<(Option<u32>, Option<u32>)> {
+    let (mut year, mut month) = (Option::None, Option::None);
+    for word in query.split(is_date_separator).filter(|word| !word.is_empty()) {
+        let word = word.to_lowercase();
+        let is_number = !word.is_empty() && word.chars().all(|c| c.is_ascii_digit());
+        if is_number && word.len() == 4 && year.is_none() {
+            year = word.parse::<u32>().ok();
+        } else if is_number && word.len() <= 2 && month.is_none() {
+            month = Option::Some(word.parse::<u32>().ok().filter(|&month| month >= 1 && month <= 12)?);
+        } else if word.len() >= 3 && month.is_none() {
+            month = Option::Some(MONTH_NAMES.iter().position(|name| name.starts_with(word.as_str()))? as u32 + 1);
+        } else {
+            return Option::None;
+        }
+    }
+    if year.is_none() && month.is_none() {
+        return Option::None;
+    }
+    Option::Some((year, month))
+}
+
+/// Whether `query` is only digits and date separators with at least one separator, like `2018-13` or
+/// `15/03/2018`: shaped like a date, so worth a specific reply when `parse_date_query` can't read it.
+pub fn looks_like_date(query: &str) -> bool {
+    let query = query.trim();
+    query.chars().any(|c| c.is_ascii_digit()) && query.chars().any(|c| c != ' ' && is_date_separator(c))
+        && query.chars().all(|c| c.is_ascii_digit() || is_date_separator(c))
+}
+
+/// Rewrites misspelt month names until `query` parses as a partial date, e.g. `Marhc 2018` to `march 2018`.
+///
+/// Returns the rewritten query with each word that was changed and the month (1 to 12) it became, or `None`
+/// if the query already parses or still would not.
+pub fn correct_date_query(query: &str) -> Option<(String, Vec<(String, u32)>)> {
+    if parse_date_query(query).is_some() {
+        return Option::None;
+    }
+    let mut corrections = Vec::new();
+    let words: Vec<String> = query.split_whitespace()
+        .map(|word| match correct_keyword(word.trim_matches(','), &MONTH_NAMES) {
+            Option::Some(name) => {
+                let month = MONTH_NAMES.iter().position(|&month| month == name).unwrap() as u32 + 1;
+                corrections.push((word.trim_matches(',').to_string(), month));
+                name.to_string()
+            },
+            Option::None => word.to_string(),
+        })
+        .collect();
+    let corrected = words.join(" ");
+    if corrections.is_empty() || parse_date_query(&corrected).is_none() {
+        return Option::None;
+    }
+    Option::Some((corrected, corrections))
+}
+
+/// Every quote matching whichever of `year` and `month` are given; `None` matches anything.
+pub fn date_quotes(quotes: &OrderMap<String, QuoteYear>, year: Option<u32>, month: Option<u32>) -> Vec<&Quote> {
+    let matches_key = |key: &str, wanted: Option<u32>|
+        wanted.map_or(true, |wanted| key.parse::<u32>() == Result::Ok(wanted));
+    quotes.iter()
+        .filter(|&(year_key, _)| matches_key(year_key, year))
+        .flat_map(|(_, quote_year)| quote_year.months.iter())
+        .filter(|&(month_key, _)| matches_key(month_key, month))
+        .flat_map(|(_, quote_month)| quote_month.quotes.iter())
+        .collect()
+}
+
+/// Every quote from `month` of `year` onward, or from the start of `year` without a month. They come back
+/// oldest first even when the source doesn't list its years and months in order; only numeric keys count.
+pub fn since_quotes(quotes: &OrderMap<String, QuoteYear>, year: u32, month: Option<u32>) -> Vec<&Quote> {
+    let mut matches: Vec<(u32, u32, &Quote)> = Vec::new();
+    for (year_key, quote_year) in quotes {
+        let year_value = match year_key.parse::<u32>() {
+            Result::Ok(year_value) if year_value >= year => year_value,
+            _ => continue,
+        };
+        for (month_key, quote_month) in &quote_year.months {
+            let month_value = match month_key.parse::<u32>() {
+                Result::Ok(month_value) => month_value,
+                Result::Err(_) => continue,
+            };
+            if year_value == year && month.map_or(false, |month| month_value < month) {
+                continue;
+            }
+            matches.extend(quote_month.quotes.iter().map(|quote| (year_value, month_value, quote)));
+        }
+    }
+    matches.sort_by_key(|&(year, month, quote)| (year, month, quote.index));
+    matches.into_iter().map(|(_, _, quote)| quote).collect()
+}
+
+/// Every quote from `year`.
+pub fn year_quotes<'a>(quotes: &'a OrderMap<String, QuoteYear>, year: &str) -> Vec<&'a Quote> {
+    match quotes.get(year) {
+        Option::Some(year) => year.months.values().flat_map(|month| month.quotes.iter()).collect(),
+        Option::None => Vec::new(),
+    }
+}
+
+/// Every quote from the ten years starting at `decade`.
+pub fn decade_quotes(quotes: &OrderMap<String, QuoteYear>, decade: u32) -> Vec<&Quote> {
+    let mut matches: Vec<&Quote> = Vec::new();
+    for (year_key, year) in quotes {
+        if let Result::Ok(year_value) = year_key.parse::<u32>() {
+            if year_value >= decade && year_value < decade + 10 {
+                for (_, month) in &year.months {
+                    matches.extend(month.quotes.iter());
+                }
+            }
+        }
+    }
+    matches
+}
+
+
+/// Splits `/pattern/ rest` into the pattern and whatever follows it.
+pub fn split_regex_query(query: &str) -> Option<(&str, &str)> {
+    if !query.starts_with('/') {
+        return Option::None;
+    }
+    let mut escaped = false;
+    for (i, c) in query.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '/' if !escaped => return Option::Some((&query[1..i], query[i + 1..].trim())),
+            _ => escaped = false,
+        }
+    }
+    Option::None
+}
+
+/// Compiles a user-supplied pattern case-insensitively, with a size limit.
+pub fn compile_query_regex(pattern: &str) -> Result<Regex, String> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+        .map_err(|err| match err {
+            regex::Error::CompiledTooBig(limit) => format!(
+                "That pattern is too complex: it compiles to more than {} KiB. Try fewer alternatives or repetitions.",
+                limit / 1024),
+            err => format!("Invalid pattern: {}", err),
+        })
+}
+
+/// The quotes a regex query matched, and whether every quote was checked.
+pub struct RegexMatches<'a> {
+    pub quotes: Vec<&'a Quote>,
+    pub scanned: usize,
+    pub complete: bool,
+}
+
+/// Collects quotes matching `regex`, stopping early after `max_scanned` quotes or once `budget` has elapsed.
+///
+/// The `regex` crate matches in linear time, so the caps only bound the total work on a large corpus.
+pub fn regex_quotes<'a>(quotes: &'a OrderMap<String, QuoteYear>, regex: &Regex, max_scanned: usize,
+                        budget: Duration) -> RegexMatches<'a> {
+    let started = Instant::now();
+    let mut matches = RegexMatches { quotes: Vec::new(), scanned: 0, complete: true };
+    for quote in quotes.values().flat_map(|year| year.months.values()).flat_map(|month| month.quotes.iter()) {
+        if matches.scanned >= max_scanned || started.elapsed() >= budget {
+            matches.complete = false;
+            break;
+        }
+        matches.scanned += 1;
+        if regex.is_match(&quote.text) {
+            matches.quotes.push(quote);
+        }
+    }
+    matches
+}
+
+fn template_groups(template: &str) -> Vec<String> {
+    let mut groups: Vec<String> = Vec::new();
+    let mut rest = template;
+    while let Option::Some(start) = rest.find('$') {
+        rest = &rest[start + 1..];
+        if rest.starts_with('$') {
+            rest = &rest[1..];
+        } else if rest.starts_with('{') {
+            if let Option::Some(end) = rest.find('}') {
+                groups.push(rest[1..end].to_string());
+                rest = &rest[end + 1..];
+            }
+        } else {
+            let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            if end > 0 {
+                groups.push(rest[..end].to_string());
+            }
+            rest = &rest[end..];
+        }
+    }
+    groups
+}
+
+/// Checks that every `$group` in `template` exists in `regex`.
+pub fn check_template(regex: &Regex, template: &str) -> Result<(), String> {
+    for group in template_groups(template) {
+        let exists = match group.parse::<usize>() {
+            Result::Ok(index) => index < regex.captures_len(),
+            Result::Err(_) => regex.capture_names().any(|name| name == Option::Some(group.as_str())),
+        };
+        if !exists {
+            return Result::Err(format!("The template refers to a group `{}` that the pattern doesn't have.", group));
+        }
+    }
+    Result::Ok(())
+}
+
+/// Expands `$group` references in `template` from `captures`.
+pub fn interpolate(captures: &Captures, template: &str) -> String {
+    let mut result = String::new();
+    captures.expand(template, &mut result);
+    result
+}
+
+
+/// One strategy in a layered search.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SearchLayer {
+    /// A random quote containing the query verbatim, ignoring case and spacing.
+    Substring,
+    Fuzzy,
+    Typo,
+}
+
+impl SearchLayer {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SearchLayer::Substring => "substring",
+            SearchLayer::Fuzzy => "fuzzy",
+            SearchLayer::Typo => "typo",
+        }
+    }
+    /// Parses a layer name as produced by `name`.
+    pub fn parse(name: &str) -> Option<SearchLayer> {
+        match name.trim().to_lowercase().as_str() {
+            "substring" => Option::Some(SearchLayer::Substring),
+            "fuzzy" => Option::Some(SearchLayer::Fuzzy),
+            "typo" => Option::Some(SearchLayer::Typo),
+            _ => Option::None,
+        }
+    }
+}
+
+/// The order `select_quote` tries the layers in.
+pub const DEFAULT_SEARCH_ORDER: [SearchLayer; 3] = [SearchLayer::Substring, SearchLayer::Fuzzy, SearchLayer::Typo];
+
+/// Every quote whose normalized text contains each word of the normalized `query`, in any order, so
+/// `team lunch` also finds "lunch with the team".
+pub fn substring_quotes<'a>(quotes: &'a OrderMap<String, QuoteYear>, query: &str) -> Vec<&'a Quote> {
+    let query = normalize(query);
+    let words: Vec<&str> = query.split(' ').filter(|word| !word.is_empty()).collect();
+    quotes.values()
+        .flat_map(|year| year.months.values())
+        .flat_map(|month| month.quotes.iter())
+        .filter(|quote| {
+            let text = normalize(&quote.text);
+            words.iter().all(|word| text.contains(word))
+        })
+        .collect()
+}
+
+/// Fewest characters a `word*` query needs before its `*`, so it can't end up matching nearly every quote.
+pub const MIN_PREFIX_CHARS: usize = 2;
+
+/// The part before the `*` when `query` is a single word ending in one, as in `meet*`. The stem may be empty or
+/// shorter than `MIN_PREFIX_CHARS`; it's up to the caller to refuse those.
+pub fn split_prefix_query(query: &str) -> Option<&str> {
+    let query = query.trim();
+    if query.ends_with('*') && !query.contains(char::is_whitespace) {
+        Option::Some(query.trim_right_matches('*'))
+    } else {
+        Option::None
+    }
+}
+
+/// Every quote with a word, as split by `tokenize`, that starts with `stem`.
+pub fn prefix_quotes<'a>(quotes: &'a OrderMap<String, QuoteYear>, stem: &str) -> Vec<&'a Quote> {
+    let stem = stem.to_lowercase();
+    quotes.values()
+        .flat_map(|year| year.months.values())
+        .flat_map(|month| month.quotes.iter())
+        .filter(|quote| tokenize(&quote.text).iter().any(|word| word.starts_with(&stem)))
+        .collect()
+}
+
+/// Whether `text` has any of `words` as one of the words `tokenize` splits it into.
+pub fn is_blocked(text: &str, words: &HashSet<String>) -> bool {
+    !words.is_empty() && tokenize(text).iter().any(|word| words.contains(word))
+}
+
+/// A copy of `quotes` without any quote containing one of `words`, dropping months and years left empty, along
+/// with how many quotes were left out.
+pub fn without_blocked(quotes: &OrderMap<String, QuoteYear>, words: &HashSet<String>)
+    -> (OrderMap<String, QuoteYear>, usize) {
+    let mut removed = 0;
+    let mut kept: OrderMap<String, QuoteYear> = OrderMap::new();
+    for (year_key, year) in quotes {
+        let mut months: OrderMap<String, QuoteMonth> = OrderMap::new();
+        for (month_key, month) in &year.months {
+            let allowed: Vec<Quote> = month.quotes.iter()
+                .filter(|quote| !is_blocked(&quote.text, words))
+                .cloned()
+                .collect();
+            removed += month.quotes.len() - allowed.len();
+            if !allowed.is_empty() {
+                months.insert(month_key.clone(), QuoteMonth { quotes: allowed });
+            }
+        }
+        if !months.is_empty() {
+            kept.insert(year_key.clone(), QuoteYear { months });
+        }
+    }
+    (kept, removed)
+}
+
+/// Most terms a boolean query may have, so a pasted wall of text can't become thousands of substring scans.
+pub const MAX_QUERY_TERMS: usize = 16;
+
+/// Whether `query` uses double quotes, `|` or a `-` exclusion, and so should be parsed as a `TermQuery` rather
+/// than matched as one substring.
+pub fn is_term_query(query: &str) -> bool {
+    query.contains('"') || query.contains('|')
+        || query.split_whitespace().any(|word| word.len() > 1 && word.starts_with('-'))
+}
+
+/// A word or double-quoted phrase in a `TermQuery`, normalized as by `normalize`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Term {
+    pub text: String,
+    /// Set by a leading `-`: the quote must not contain this term.
+    pub negated: bool,
+}
+
+/// A boolean text query: `|` separates alternatives, and within one every term must match (or, for a `-` term,
+/// must not). `meeting | standup -cancelled` matches quotes mentioning a meeting, or a standup that wasn't
+/// cancelled.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TermQuery {
+    pub groups: Vec<Vec<Term>>,
+}
+
+impl TermQuery {
+    /// Parses a query, failing on an empty alternative, a `-` with no term after it or more than
+    /// `MAX_QUERY_TERMS` terms. An unclosed quote runs to the end of the query.
+    pub fn parse(query: &str) -> Result<TermQuery, String> {
+        let mut groups: Vec<Vec<Term>> = vec![Vec::new()];
+        let mut chars = query.chars().peekable();
+        while let Option::Some(c) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+            if c == '|' {
+                groups.push(Vec::new());
+                continue;
+            }
+            let negated = c == '-';
+            if negated && chars.peek().map_or(true, |&next| next.is_whitespace() || next == '|') {
+                return Result::Err("A `-` needs a term right after it.".to_string());
+            }
+            let first = if negated { chars.next() } else { Option::Some(c) };
+            let mut text = String::new();
+            if first == Option::Some('"') {
+                text.extend(chars.by_ref().take_while(|&c| c != '"'));
+            } else {
+                text.extend(first);
+                while let Option::Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '|' || c == '"' {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+            }
+            let text = normalize(&text);
+            if !text.is_empty() {
+                groups.last_mut().unwrap().push(Term { text, negated });
+            }
+        }
+        if groups.iter().any(|group| group.is_empty()) {
+            return Result::Err(if groups.len() == 1 {
+                "Give me something to search for.".to_string()
+            } else {
+                "Each side of `|` needs at least one term.".to_string()
+            });
+        }
+        let query = TermQuery { groups };
+        if query.term_count() > MAX_QUERY_TERMS {
+            return Result::Err(format!("That query has {} terms; the most I'll match at once is {}.",
+                                       query.term_count(), MAX_QUERY_TERMS));
+        }
+        Result::Ok(query)
+    }
+    /// A query matching text that contains any one of `words`.
+    pub fn any(words: &[String]) -> TermQuery {
+        TermQuery {
+            groups: words.iter()
+                .map(|word| normalize(word))
+                .filter(|word| !word.is_empty())
+                .map(|text| vec![Term { text, negated: false }])
+                .collect(),
+        }
+    }
+    /// How many terms there are across every alternative.
+    pub fn term_count(&self) -> usize {
+        self.groups.iter().map(|group| group.len()).sum()
+    }
+    /// Whether this is just words and phrases that must all appear, with no `|` or `-`.
+    pub fn is_conjunction(&self) -> bool {
+        self.groups.len() == 1 && self.groups[0].iter().all(|term| !term.negated)
+    }
+    /// Whether normalized `text` satisfies the query.
+    pub fn matches(&self, text: &str) -> bool {
+        self.groups.iter().any(|group| group.iter().all(|term| text.contains(term.text.as_str()) != term.negated))
+    }
+    /// The terms a quote should contain, for the search layers that can't evaluate the query itself.
+    pub fn wanted(&self) -> String {
+        self.groups.iter()
+            .flat_map(|group| group.iter())
+            .filter(|term| !term.negated)
+            .map(|term| term.text.as_str())
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
+/// Every quote whose normalized text satisfies `query`.
+pub fn term_quotes<'a>(quotes: &'a OrderMap<String, QuoteYear>, query: &TermQuery) -> Vec<&'a Quote> {
+    quotes.values()
+        .flat_map(|year| year.months.values())
+        .flat_map(|month| month.quotes.iter())
+        .filter(|quote| query.matches(&normalize(&quote.text)))
+        .collect()
+}
+
+/// Tries each layer in turn, returning the first match along with the layer that found it.
+///
+/// The substring layer also reports how many quotes it picked between; the other layers keep only their
+/// single best match, so they report `None`. A query for which `is_term_query` holds has the substring layer
+/// evaluate it as a `TermQuery`, while the other layers only look for its wanted terms; one that doesn't parse
+/// finds nothing in the substring layer.
+pub fn search_quote<'a, R: Rng>(quotes: &'a OrderMap<String, QuoteYear>, query: &str, layers: &[SearchLayer],
+                                distance: FuzzyDistance, rng: &mut R)
+    -> Option<(&'a Quote, SearchLayer, Option<usize>)> {
+    let terms = if is_term_query(query) { Option::Some(TermQuery::parse(query)) } else { Option::None };
+    let bare = match terms {
+        Option::Some(Result::Ok(ref terms)) => terms.wanted(),
+        _ => query.to_string(),
+    };
+    for &layer in layers {
+        let found = match layer {
+            SearchLayer::Substring => {
+                let matches = match terms {
+                    Option::Some(Result::Ok(ref terms)) => term_quotes(quotes, terms),
+                    Option::Some(Result::Err(_)) => Vec::new(),
+                    Option::None => substring_quotes(quotes, query),
+                };
+                select_random(&matches, rng, &[]).map(|quote| (quote, Option::Some(matches.len())))
+            },
+            SearchLayer::Fuzzy => fuzzy_quote(quotes, &bare).map(|quote| (quote, Option::None)),
+            SearchLayer::Typo => typo_quote(quotes, &bare, distance).map(|quote| (quote, Option::None)),
+        };
+        if let Option::Some((quote, candidates)) = found {
+            return Option::Some((quote, layer, candidates));
+        }
+    }
+    Option::None
+}
+
+/// Picks one of `quotes` uniformly at random, never returning a quote in `avoid` while there's anything else
+/// to pick. Once every candidate is in `avoid`, repeats are allowed again.
+///
+/// Returns `None` only when `quotes` is empty. A single quote is returned without touching `rng`, so a
+/// lookup with only one match leaves a seeded sequence where it was.
+pub fn select_random<'a, R: Rng>(quotes: &[&'a Quote], rng: &mut R, avoid: &[&Quote]) -> Option<&'a Quote> {
+    if quotes.len() == 1 {
+        return Option::Some(quotes[0]);
+    }
+    let same = |a: &Quote, b: &Quote| a.year == b.year && a.month == b.month && a.index == b.index;
+    let fresh: Vec<&'a Quote> = if avoid.is_empty() {
+        Vec::new()
+    } else {
+        quotes.iter().cloned().filter(|&quote| !avoid.iter().any(|&avoid| same(quote, avoid))).collect()
+    };
+    if fresh.is_empty() {
+        rng.choose(quotes).map(|&quote| quote)
+    } else {
+        rng.choose(&fresh).map(|&quote| quote)
+    }
+}
+
+/// Up to `count` random quotes spread across the years: one from each year in chronological order, then round
+/// again while quotes are left, so a sample covers the whole history before doubling up anywhere.
+///
+/// Quotes in `avoid` are only picked once their year has nothing else left.
+pub fn spread_quotes<'a, R: Rng>(quotes: &'a OrderMap<String, QuoteYear>, count: usize, rng: &mut R,
+                                 avoid: &[&Quote]) -> Vec<&'a Quote> {
+    let same = |a: &Quote, b: &Quote| a.year == b.year && a.month == b.month && a.index == b.index;
+    // Each pool is popped from the back, so avoided quotes are sorted to the front.
+    let mut pools: Vec<Vec<&'a Quote>> = sorted_keys(quotes, QuoteOrder::Chronological).into_iter()
+        .map(|(_, year)| {
+            let mut pool: Vec<&'a Quote> = year.months.values().flat_map(|month| month.quotes.iter()).collect();
+            rng.shuffle(&mut pool);
+            pool.sort_by_key(|&quote| !avoid.iter().any(|&avoid| same(quote, avoid)));
+            pool
+        })
+        .collect();
+    let mut picked: Vec<&'a Quote> = Vec::new();
+    while picked.len() < count && pools.iter().any(|pool| !pool.is_empty()) {
+        for pool in &mut pools {
+            if picked.len() >= count {
+                break;
+            }
+            picked.extend(pool.pop());
+        }
+    }
+    picked
+}
+
+/// Up to `count` random quotes from the latest year, reaching back a year at a time while that isn't enough,
+/// along with the year keys they came from, newest first. Only numeric years count as recent.
+pub fn latest_quotes<'a, R: Rng>(quotes: &'a OrderMap<String, QuoteYear>, count: usize, rng: &mut R)
+    -> (Vec<&'a Quote>, Vec<&'a str>) {
+    let mut picked: Vec<&'a Quote> = Vec::new();
+    let mut years: Vec<&'a str> = Vec::new();
+    for (key, year) in sorted_keys(quotes, QuoteOrder::Chronological).into_iter().rev() {
+        if picked.len() >= count {
+            break;
+        }
+        if key.parse::<u64>().is_err() {
+            continue;
+        }
+        let mut pool: Vec<&'a Quote> = year.months.values().flat_map(|month| month.quotes.iter()).collect();
+        if pool.is_empty() {
+            continue;
+        }
+        rng.shuffle(&mut pool);
+        pool.truncate(count - picked.len());
+        picked.extend(pool);
+        years.push(key.as_str());
+    }
+    (picked, years)
+}
+
+/// Picks a source by weight, then a quote from that source, so a large source can't crowd out a small one.
+///
+/// `weights` pairs source labels with their weights. With no weights, or none matching `quotes`, this is
+/// the same uniform pick as `select_random`.
+pub fn select_weighted<'a, R: Rng>(quotes: &[&'a Quote], weights: &[(String, u32)], rng: &mut R,
+                                   avoid: &[&Quote]) -> Option<&'a Quote> {
+    let pools: Vec<(Vec<&'a Quote>, u32)> = weights.iter()
+        .filter(|&&(_, weight)| weight > 0)
+        .map(|&(ref label, weight)| (quotes.iter()
+            .cloned()
+            .filter(|quote| quote.source.as_ref() == Option::Some(label))
+            .collect::<Vec<&Quote>>(), weight))
+        .filter(|&(ref pool, _)| !pool.is_empty())
+        .collect();
+    let total: u64 = pools.iter().map(|&(_, weight)| weight as u64).sum();
+    if total == 0 {
+        return select_random(quotes, rng, avoid);
+    }
+    let mut pick = rng.gen_range(0, total);
+    for (pool, weight) in pools {
+        if pick < weight as u64 {
+            return select_random(&pool, rng, avoid);
+        }
+        pick -= weight as u64;
+    }
+    Option::None
+}
+
+/// Picks a quote for a plain query using the default layered search, or a random quote if there's no query.
+///
+/// This is for one-off picks, so the random one flattens `quotes` rather than taking a `QuoteIndex`: building
+/// the index would walk every quote just the same. Anything picking repeatedly should keep an index instead.
+pub fn select_quote<'a, R: Rng>(quotes: &'a OrderMap<String, QuoteYear>, query: Option<&str>,
+                                distance: FuzzyDistance, rng: &mut R) -> Option<&'a Quote> {
+    match query.map(str::trim).filter(|query| !query.is_empty()) {
+        Option::Some(query) => search_quote(quotes, query, &DEFAULT_SEARCH_ORDER, distance, rng)
+            .map(|(quote, _, _)| quote),
+        Option::None => select_random(&flatten(quotes), rng, &[]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::parse_quotes_nested;
+    use serde_json;
+
+    fn fixture() -> OrderMap<String, QuoteYear> {
+        let json = serde_json::from_str(include_str!("../tests/fixtures/quotes.json")).unwrap();
+        parse_quotes_nested(json).unwrap().quotes
+    }
+
+    fn ids(quotes: &[&Quote]) -> Vec<String> {
+        quotes.iter().map(|quote| quote.id()).collect()
+    }
+
+    #[test]
+    fn query_regex_accepts_small_patterns() {
+        let regex = compile_query_regex("socrates|hemlock").unwrap();
+        assert!(regex.is_match("SOCRATES said"));
+        assert!(compile_query_regex("(unclosed").unwrap_err().starts_with("Invalid pattern"));
+    }
+
+    #[test]
+    fn query_regex_rejects_large_alternations_quickly() {
+        let words: Vec<String> = (0..5000).map(|n| format!("word{}", n)).collect();
+        let started = Instant::now();
+        let err = compile_query_regex(&words.join("|")).unwrap_err();
+        assert!(err.starts_with("That pattern is too complex"), "{}", err);
+        let err = compile_query_regex("((a|b|c|d|e){20}){20}").unwrap_err();
+        assert!(err.starts_with("That pattern is too complex"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn select_random_from_nothing() {
+        assert!(select_random(&[], &mut QuoteRng::new(Option::Some(1)), &[]).is_none());
+    }
+
+    #[test]
+    fn select_random_single_quote_leaves_the_rng_alone() {
+        let quotes = fixture();
+        let only = find_quote(&quotes, "2019-7-1").unwrap();
+        let mut rng = QuoteRng::new(Option::Some(1));
+        assert_eq!(select_random(&[only], &mut rng, &[only]).map(Quote::id), Option::Some(only.id()));
+        assert_eq!(rng.next_u32(), QuoteRng::new(Option::Some(1)).next_u32());
+    }
+
+    #[test]
+    fn select_random_avoids_recent_quotes() {
+        let quotes = fixture();
+        let pool: Vec<&Quote> = quotes["2018"].months["3"].quotes.iter().collect();
+        for seed in 0..20 {
+            let picked = select_random(&pool, &mut QuoteRng::new(Option::Some(seed)), &pool[..2]).unwrap();
+            assert_eq!(picked.id(), "2018-3-3");
+        }
+    }
+
+    #[test]
+    fn select_random_falls_back_when_everything_is_avoided() {
+        let quotes = fixture();
+        let pool: Vec<&Quote> = quotes["2018"].months["3"].quotes.iter().collect();
+        for seed in 0..20 {
+            let picked = select_random(&pool, &mut QuoteRng::new(Option::Some(seed)), &pool).unwrap();
+            assert!(ids(&pool).contains(&picked.id()));
+        }
+    }
+
+    #[test]
+    fn select_random_repeats_for_a_seed() {
+        let quotes = fixture();
+        let pool = flatten(&quotes);
+        let picks = |seed| {
+            let mut rng = QuoteRng::new(Option::Some(seed));
+            (0..10).map(|_| select_random(&pool, &mut rng, &[]).unwrap().id()).collect::<Vec<String>>()
+        };
+        assert_eq!(picks(42), picks(42));
+        assert_ne!(picks(42), picks(43));
+    }
+
+    #[test]
+    fn strip_keyword_needs_the_exact_keyword_and_a_query() {
+        assert_eq!(strip_keyword("LINK  socrates ", "link"), Option::Some("socrates"));
+        assert_eq!(strip_keyword("link", "link"), Option::None);
+        assert_eq!(strip_keyword("link   ", "link"), Option::None);
+        assert_eq!(strip_keyword("lnik socrates", "link"), Option::None);
+        assert_eq!(strip_keyword("linked socrates", "link"), Option::None);
+    }
+
+    #[test]
+    fn near_miss_keywords_are_corrected() {
+        assert_eq!(correct_keyword("Marhc", &MONTH_NAMES), Option::Some("march"));
+        assert_eq!(correct_keyword("sourec", &["link", "source"]), Option::Some("source"));
+        assert_eq!(correct_keyword("lynk", &["link", "source"]), Option::Some("link"));
+        // exact matches, short words, ambiguous and distant misses are left alone
+        assert_eq!(correct_keyword("March", &MONTH_NAMES), Option::None);
+        assert_eq!(correct_keyword("mya", &MONTH_NAMES), Option::None);
+        assert_eq!(correct_keyword("juny", &MONTH_NAMES), Option::None);
+        assert_eq!(correct_keyword("marchhh", &MONTH_NAMES), Option::None);
+    }
+
+    #[test]
+    fn near_miss_months_make_a_date() {
+        assert_eq!(correct_date_query("Marhc 2018"),
+                   Option::Some(("march 2018".to_string(), vec![("Marhc".to_string(), 3)])));
+        assert_eq!(correct_date_query("Marhc, 2018"),
+                   Option::Some(("march 2018".to_string(), vec![("Marhc".to_string(), 3)])));
+        assert_eq!(correct_date_query("March 2018"), Option::None);
+        assert_eq!(correct_date_query("Marhc madness"), Option::None);
+    }
+
+    #[test]
+    fn names_and_words_can_be_near_misses() {
+        // Each of these is a search in its own right, which is why callers only correct a query that failed.
+        assert_eq!(correct_date_query("mary"), Option::Some(("may".to_string(), vec![("mary".to_string(), 5)])));
+        assert_eq!(correct_keyword("lost", &["list", "random"]), Option::Some("list"));
+        assert_eq!(correct_keyword("ransom", &["list", "random"]), Option::Some("random"));
+    }
+
+    // Years listed 2019, 2017, 2018, each with its months out of numeric order too.
+    fn out_of_order() -> OrderMap<String, QuoteYear> {
+        let mut quotes = OrderMap::new();
+        for year in &[r#"{ "2019": { "1": ["c"] } }"#, r#"{ "2017": { "12": ["b"], "3": ["a1", "a2"] } }"#,
+                      r#"{ "2018": { "10": ["d"], "2": ["e"] } }"#] {
+            quotes.extend(parse_quotes_nested(serde_json::from_str(year).unwrap()).unwrap().quotes);
+        }
+        quotes
+    }
+
+    fn texts<'a>(quotes: &[&'a Quote]) -> Vec<&'a str> {
+        quotes.iter().map(|quote| quote.text.as_str()).collect()
+    }
+
+    #[test]
+    fn quote_order_names() {
+        assert_eq!(QuoteOrder::parse(" Chronological "), Option::Some(QuoteOrder::Chronological));
+        assert_eq!(QuoteOrder::parse("insertion"), Option::Some(QuoteOrder::Insertion));
+        assert_eq!(QuoteOrder::parse("sorted"), Option::None);
+    }
+
+    #[test]
+    fn chronological_order_sorts_years_and_months() {
+        let quotes = out_of_order();
+        assert_eq!(texts(&ordered_quotes(&quotes, QuoteOrder::Chronological)), vec!["a1", "a2", "b", "e", "d", "c"]);
+    }
+
+    #[test]
+    fn insertion_order_follows_the_source() {
+        let quotes = out_of_order();
+        assert_eq!(texts(&ordered_quotes(&quotes, QuoteOrder::Insertion)), vec!["c", "b", "a1", "a2", "d", "e"]);
+    }
+
+    #[test]
+    fn steps_follow_the_order() {
+        let quotes = out_of_order();
+        let step = |id, order, forward, wrap| match step_quote(&quotes, id, order, forward, wrap) {
+            Step::Moved(quote, position, total) => format!("{} {}/{}", quote.text, position, total),
+            Step::Edge => "edge".to_string(),
+            Step::Unknown => "unknown".to_string(),
+        };
+        assert_eq!(step("2017-12-1", QuoteOrder::Chronological, true, false), "e 3/6");
+        assert_eq!(step("2017-12-1", QuoteOrder::Insertion, true, false), "a1 2/6");
+        assert_eq!(step("2019-1-1", QuoteOrder::Chronological, true, false), "edge");
+        assert_eq!(step("2019-1-1", QuoteOrder::Chronological, true, true), "a1 0/6");
+        assert_eq!(step("2019-1-1", QuoteOrder::Insertion, false, true), "e 5/6");
+        assert_eq!(step("2020-1-1", QuoteOrder::Insertion, true, true), "unknown");
+    }
+
+    fn term(text: &str, negated: bool) -> Term {
+        Term { text: text.to_string(), negated }
+    }
+
+    fn term_ids(query: &str) -> Vec<String> {
+        let quotes = fixture();
+        let query = TermQuery::parse(query).unwrap();
+        ids(&term_quotes(&quotes, &query))
+    }
+
+    #[test]
+    fn term_queries_parse_into_alternatives() {
+        assert_eq!(TermQuery::parse("Meeting | standup -cancelled").unwrap().groups,
+                   vec![vec![term("meeting", false)], vec![term("standup", false), term("cancelled", true)]]);
+        assert_eq!(TermQuery::parse(r#"-"drank the" hemlock|"unclosed phrase"#).unwrap().groups,
+                   vec![vec![term("drank the", true), term("hemlock", false)], vec![term("unclosed phrase", false)]]);
+    }
+
+    #[test]
+    fn term_queries_match_and_or_not() {
+        assert_eq!(term_ids("hemlock socrates"), vec!["2018-3-1"]);
+        assert_eq!(term_ids("socrates | winter"), vec!["2017-1-1", "2018-12-1", "2018-3-1"]);
+        assert_eq!(term_ids("quote -march"), vec!["2017-1-2", "2017-6-1", "2018-12-1"]);
+        assert_eq!(term_ids(r#""the hemlock" | summer -code"#), vec!["2018-3-1"]);
+    }
+
+    #[test]
+    fn malformed_term_queries_are_errors() {
+        let side = "Each side of `|` needs at least one term.";
+        assert_eq!(TermQuery::parse("socrates |"), Result::Err(side.to_string()));
+        assert_eq!(TermQuery::parse("| socrates"), Result::Err(side.to_string()));
+        assert_eq!(TermQuery::parse("socrates || hemlock"), Result::Err(side.to_string()));
+        let dangling = "A `-` needs a term right after it.";
+        assert_eq!(TermQuery::parse("socrates -"), Result::Err(dangling.to_string()));
+        assert_eq!(TermQuery::parse("socrates - hemlock"), Result::Err(dangling.to_string()));
+        assert_eq!(TermQuery::parse("socrates -| hemlock"), Result::Err(dangling.to_string()));
+        assert_eq!(TermQuery::parse(r#"  "" "#), Result::Err("Give me something to search for.".to_string()));
+        let words: Vec<String> = (0..MAX_QUERY_TERMS + 1).map(|n| format!("word{}", n)).collect();
+        assert!(TermQuery::parse(&words.join(" ")).unwrap_err().starts_with("That query has 17 terms"));
+    }
+
+    #[test]
+    fn multi_word_queries_match_in_any_order() {
+        let quotes = fixture();
+        assert_eq!(ids(&substring_quotes(&quotes, "hemlock socrates")), vec!["2018-3-1"]);
+        assert_eq!(ids(&substring_quotes(&quotes, "  Hemlock   SOCRATES ")), vec!["2018-3-1"]);
+        assert_eq!(ids(&substring_quotes(&quotes, "march quote")), vec!["2018-3-3"]);
+        assert_eq!(ids(&substring_quotes(&quotes, "socrates")), vec!["2017-1-1", "2018-3-1"]);
+        assert!(substring_quotes(&quotes, "socrates winter").is_empty());
+    }
+
+    #[test]
+    fn multi_word_queries_report_the_count() {
+        let quotes = fixture();
+        let mut rng = QuoteRng::new(Option::Some(1));
+        match search_quote(&quotes, "quote from", &DEFAULT_SEARCH_ORDER, FuzzyDistance::Scaled, &mut rng) {
+            Option::Some((quote, SearchLayer::Substring, count)) => {
+                assert!(["2017-1-2", "2018-3-3"].contains(&quote.id().as_str()));
+                assert_eq!(count, Option::Some(2));
+            },
+            _ => panic!("both words appear in two quotes"),
+        }
+    }
+
+    #[test]
+    fn prefix_queries_are_single_starred_words() {
+        assert_eq!(split_prefix_query(" meet* "), Option::Some("meet"));
+        assert_eq!(split_prefix_query("meet**"), Option::Some("meet"));
+        assert_eq!(split_prefix_query("*"), Option::Some(""));
+        assert_eq!(split_prefix_query("team meet*"), Option::None);
+        assert_eq!(split_prefix_query("meet"), Option::None);
+        assert_eq!(split_prefix_query("*meet"), Option::None);
+    }
+
+    #[test]
+    fn prefix_quotes_match_word_starts() {
+        let quotes = fixture();
+        assert_eq!(ids(&prefix_quotes(&quotes, "SOC")), vec!["2017-1-1", "2018-3-1"]);
+        assert_eq!(ids(&prefix_quotes(&quotes, "quot")), vec!["2017-1-2", "2017-6-1", "2018-12-1", "2018-3-3"]);
+        assert!(prefix_quotes(&quotes, "ocrates").is_empty());
+        assert!(prefix_quotes(&quotes, "xyz").is_empty());
+    }
+
+    fn blocked(words: &[&str]) -> HashSet<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn blocked_words_match_whole_words_only() {
+        let quotes = fixture();
+        let (kept, removed) = without_blocked(&quotes, &blocked(&["march"]));
+        assert_eq!(removed, 2);
+        assert_eq!(flatten(&kept).len(), 6);
+        assert_eq!(kept["2018"].months["3"].quotes.len(), 1);
+        assert_eq!(kept["2018"].months["3"].quotes[0].id(), "2018-3-1");
+        // `mar` is inside "March" but isn't a word of its own
+        assert_eq!(without_blocked(&quotes, &blocked(&["mar", "quot"])).1, 0);
+        assert!(is_blocked("Socrates, drank: the HEMLOCK!", &blocked(&["hemlock"])));
+        assert!(!is_blocked("Socrates drank the hemlock", &HashSet::new()));
+    }
+
+    #[test]
+    fn blocking_everything_in_a_month_drops_it() {
+        let quotes = fixture();
+        let (kept, removed) = without_blocked(&quotes, &blocked(&["winter", "summer"]));
+        assert_eq!(removed, 2);
+        assert!(kept["2018"].months.get("12").is_none());
+        assert!(kept.get("2019").is_none());
+        let (kept, removed) = without_blocked(&quotes, &HashSet::new());
+        assert_eq!((ids(&flatten(&kept)), removed), (ids(&flatten(&quotes)), 0));
+    }
+
+    #[test]
+    fn date_queries_in_every_format() {
+        let march = Option::Some((Option::Some(2018), Option::Some(3)));
+        for query in &["2018-03", "2018-3", "March 2018", "march, 2018", "mar 2018", "03/2018", "2018.03", "2018 MAR"] {
+            assert_eq!(parse_date_query(query), march, "{}", query);
+        }
+        assert_eq!(parse_date_query("2018"), Option::Some((Option::Some(2018), Option::None)));
+        assert_eq!(parse_date_query("March"), Option::Some((Option::None, Option::Some(3))));
+        assert_eq!(parse_date_query("12"), Option::Some((Option::None, Option::Some(12))));
+    }
+
+    #[test]
+    fn non_dates_are_not_parsed() {
+        for query in &["2018-13", "15/03/2018", "2018 2019", "march april", "ma 2018", "socrates", "", "201"] {
+            assert_eq!(parse_date_query(query), Option::None, "{}", query);
+        }
+    }
+
+    #[test]
+    fn date_shaped_queries_are_recognised() {
+        assert!(looks_like_date("2018-13"));
+        assert!(looks_like_date(" 15/03/2018 "));
+        assert!(!looks_like_date("2018"));
+        assert!(!looks_like_date("March 2018"));
+        assert!(!looks_like_date("-/"));
+    }
+
+    #[test]
+    fn since_a_year() {
+        let quotes = fixture();
+        assert_eq!(ids(&since_quotes(&quotes, 2018, Option::None)),
+                   vec!["2018-3-1", "2018-3-2", "2018-3-3", "2018-12-1", "2019-7-1"]);
+        assert_eq!(since_quotes(&quotes, 1990, Option::None).len(), 8);
+    }
+
+    #[test]
+    fn since_a_year_and_month() {
+        let quotes = fixture();
+        assert_eq!(ids(&since_quotes(&quotes, 2018, Option::Some(4))), vec!["2018-12-1", "2019-7-1"]);
+        assert_eq!(ids(&since_quotes(&quotes, 2018, Option::Some(3)))[0], "2018-3-1");
+        assert_eq!(texts(&since_quotes(&out_of_order(), 2017, Option::Some(4))), vec!["b", "e", "d", "c"]);
+    }
+
+    #[test]
+    fn since_the_end_of_the_quotes() {
+        let quotes = fixture();
+        assert!(since_quotes(&quotes, 2020, Option::None).is_empty());
+        assert!(since_quotes(&quotes, 2019, Option::Some(8)).is_empty());
+    }
+}